@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use barcode_key::BarcodeKey;
+use compress::*;
+
+/// Assignment of barcodes to targets, such as guides or fragments.
+pub struct Assignments {
+    targets: HashMap<BarcodeKey, String>,
+}
+
+impl Assignments {
+    /// Field holding the target in `bc-grna` and `bc-frag` assignment
+    /// tables. For `bc-pbj` tables, the target is in field 3.
+    pub const DEFAULT_TARGET_FIELD: usize = 1;
+
+    /// Reads a barcode assignment table from a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` is the name of the file that will be read
+    /// * `target_field` is the index of the tab-delimited field, counting
+    /// from 0, holding the target
+    pub fn from_file(filename: &str, target_field: usize) -> Result<Self, failure::Error> {
+        Self::read(open_input(filename)?, target_field)
+            .map_err(|e| format_err!("Reading assignments {:?}: {}", filename, e))
+    }
+
+    /// Reads a barcode assignment table, with the barcode in the
+    /// first tab-delimited field of each line.
+    ///
+    /// Blank lines, `#` comments, and a header on the first remaining
+    /// line starting with `barcode` are skipped. A barcode may appear more than once only
+    /// if it is assigned to the same target each time.
+    pub fn read<R: BufRead>(input: R, target_field: usize) -> Result<Self, failure::Error> {
+        let mut targets = HashMap::new();
+        let mut first = true;
+
+        for (line_no, line_res) in input.lines().enumerate() {
+            let line = line_res?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            let is_header = first && fields[0] == "barcode";
+            first = false;
+            if is_header {
+                continue;
+            }
+
+            let target = fields.get(target_field).ok_or_else(|| {
+                format_err!("Missing target line {} barcode {}", line_no, fields[0])
+            })?;
+
+            let barcode = BarcodeKey::new(fields[0].as_bytes());
+            if let Some(previous) = targets.insert(barcode, target.to_string()) {
+                if previous != *target {
+                    bail!(
+                        "Conflicting targets {} and {} line {} barcode {}",
+                        previous,
+                        target,
+                        line_no,
+                        fields[0]
+                    );
+                }
+            }
+        }
+
+        Ok(Assignments { targets: targets })
+    }
+
+    /// Returns the number of assigned barcodes.
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Returns the target assigned to `barcode`, if any.
+    pub fn target(&self, barcode: &[u8]) -> Option<&str> {
+        self.targets
+            .get(&BarcodeKey::new(barcode))
+            .map(String::as_str)
+    }
+
+    /// Returns the number of barcodes assigned to each target.
+    pub fn target_sizes(&self) -> HashMap<&str, usize> {
+        let mut sizes = HashMap::new();
+        for target in self.targets.values() {
+            *sizes.entry(target.as_str()).or_insert(0) += 1;
+        }
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_grna() {
+        let table = "barcode\tguide\nACGTACGT\tsgA-1\nCATGCATG\tsgA-2\n\nTTTTAAAA\tsgA-1\n";
+        let assignments = Assignments::read(table.as_bytes(), 1).unwrap();
+        assert_eq!(assignments.len(), 3);
+        assert_eq!(assignments.target(b"ACGTACGT"), Some("sgA-1"));
+        assert_eq!(assignments.target(b"CATGCATG"), Some("sgA-2"));
+        assert_eq!(assignments.target(b"GGGGCCCC"), None);
+
+        let sizes = assignments.target_sizes();
+        assert_eq!(sizes.get("sgA-1"), Some(&2));
+        assert_eq!(sizes.get("sgA-2"), Some(&1));
+    }
+
+    #[test]
+    fn read_pbj() {
+        let table = "ACGTACGT\tlib1\t12\tchrI\t100\t400\t+\n\
+                     ACGTACGT\tlib2\t3\tchrI\t100\t400\t+\n\
+                     CATGCATG\tlib1\t5\tchrII\t50\t350\t-\n";
+        let assignments = Assignments::read(table.as_bytes(), 3).unwrap();
+        assert_eq!(assignments.len(), 2);
+        assert_eq!(assignments.target(b"ACGTACGT"), Some("chrI"));
+
+        assert!(Assignments::read(table.as_bytes(), 1).is_err());
+        assert!(Assignments::read(table.as_bytes(), 7).is_err());
+    }
+
+    #[test]
+    fn read_commented_header() {
+        let table = "# bc-grna assignments\n\nbarcode\tguide\nACGTACGT\tsgA-1\n";
+        let assignments = Assignments::read(table.as_bytes(), 1).unwrap();
+        assert_eq!(assignments.len(), 1);
+        assert_eq!(assignments.target(b"barcode"), None);
+        assert_eq!(assignments.target(b"ACGTACGT"), Some("sgA-1"));
+    }
+}
@@ -0,0 +1,371 @@
+use std::io::Write;
+use std::ops::AddAssign;
+
+use bio::alphabets::dna;
+use bio::io::fastq;
+
+use flank_match::*;
+
+const MAX_FLANK_LEN: usize = 64;
+
+/// Specification for extracting barcodes that lie between constant
+/// flanking sequences in untrimmed reads.
+#[derive(Debug, Clone)]
+pub struct ExtractSpec {
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+    /// Maximum edit distance for each flanking sequence match
+    pub max_errors: u8,
+    /// Also search the reverse complement of reads that do not match
+    /// in the forward orientation
+    pub revcomp: bool,
+    pub min_insert: Option<usize>,
+    pub max_insert: Option<usize>,
+}
+
+impl ExtractSpec {
+    /// Parses an extraction specification from command-line
+    /// arguments, with no reverse complement matching and no limits
+    /// on the insert length.
+    pub fn new(before: &str, after: &str, max_errors: &str) -> Result<Self, failure::Error> {
+        Ok(ExtractSpec {
+            before: Self::flank_seq(before)?,
+            after: Self::flank_seq(after)?,
+            max_errors: max_errors
+                .parse()
+                .map_err(|e| format_err!("Bad maximum flank errors {:?}: {}", max_errors, e))?,
+            revcomp: false,
+            min_insert: None,
+            max_insert: None,
+        })
+    }
+
+    fn flank_seq(flank: &str) -> Result<Vec<u8>, failure::Error> {
+        let seq = flank.to_ascii_uppercase().into_bytes();
+        if seq.is_empty() || seq.len() > MAX_FLANK_LEN {
+            bail!(
+                "Flanking sequence {:?} must be 1 to {} bases",
+                flank,
+                MAX_FLANK_LEN
+            );
+        }
+        if !seq.iter().all(|nt| b"ACGT".contains(nt)) {
+            bail!("Flanking sequence {:?} must contain only ACGT", flank);
+        }
+        Ok(seq)
+    }
+}
+
+/// Reason a barcode could not be extracted from a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    /// Neither flanking sequence matched.
+    NoFlanks,
+    /// Only the flanking sequence after the barcode matched.
+    NoBefore,
+    /// Only the flanking sequence before the barcode matched.
+    NoAfter,
+    /// Both flanking sequences matched, but in the wrong order.
+    Misordered,
+    ShortInsert,
+    LongInsert,
+}
+
+impl Fate {
+    // Later fates reflect a more complete match.
+    fn progress(&self) -> usize {
+        match self {
+            Fate::NoFlanks => 0,
+            Fate::NoBefore | Fate::NoAfter => 1,
+            Fate::Misordered => 2,
+            Fate::ShortInsert | Fate::LongInsert => 3,
+        }
+    }
+}
+
+/// Barcode extractor for one thread, built from an `ExtractSpec`.
+pub struct Extractor {
+    matcher: FlankMatchSpec,
+    revcomp: bool,
+    min_insert: Option<usize>,
+    max_insert: Option<usize>,
+}
+
+impl Extractor {
+    pub fn new(spec: &ExtractSpec) -> Self {
+        Extractor {
+            matcher: FlankMatchSpec::new(&spec.before, &spec.after, spec.max_errors),
+            revcomp: spec.revcomp,
+            min_insert: spec.min_insert,
+            max_insert: spec.max_insert,
+        }
+    }
+
+    /// Extracts the barcode from a read, returning a record with the
+    /// same name and description as the read and the barcode sequence
+    /// and qualities.
+    ///
+    /// Barcodes from the reverse complement of the read are reverse
+    /// complemented so that they match the forward orientation.
+    pub fn extract(&mut self, rec: &fastq::Record) -> Result<fastq::Record, Fate> {
+        let fwd_fate = match self.extract_insert(rec.seq(), rec.qual()) {
+            Ok((seq, qual)) => {
+                return Ok(fastq::Record::with_attrs(rec.id(), rec.desc(), &seq, &qual))
+            }
+            Err(fate) => fate,
+        };
+
+        if !self.revcomp {
+            return Err(fwd_fate);
+        }
+
+        let rc_seq = dna::revcomp(rec.seq());
+        let rc_qual: Vec<u8> = rec.qual().iter().rev().cloned().collect();
+        match self.extract_insert(&rc_seq, &rc_qual) {
+            Ok((seq, qual)) => Ok(fastq::Record::with_attrs(rec.id(), rec.desc(), &seq, &qual)),
+            Err(rc_fate) if rc_fate.progress() > fwd_fate.progress() => Err(rc_fate),
+            Err(_) => Err(fwd_fate),
+        }
+    }
+
+    fn extract_insert(&mut self, seq: &[u8], qual: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Fate> {
+        let match_out = self.matcher.best_match(seq, qual);
+
+        let flank_match = match match_out.flank_match() {
+            Some(flank_match) => flank_match,
+            None => {
+                return Err(match (match_out.has_before(), match_out.has_after()) {
+                    (false, false) => Fate::NoFlanks,
+                    (false, true) => Fate::NoBefore,
+                    (true, false) => Fate::NoAfter,
+                    (true, true) => Fate::Misordered,
+                })
+            }
+        };
+
+        let len = flank_match.insert_seq().len();
+        if self.min_insert.map_or(false, |min_insert| len < min_insert) {
+            Err(Fate::ShortInsert)
+        } else if self.max_insert.map_or(false, |max_insert| len > max_insert) {
+            Err(Fate::LongInsert)
+        } else {
+            Ok((
+                flank_match.insert_seq().to_vec(),
+                flank_match.insert_qual().to_vec(),
+            ))
+        }
+    }
+}
+
+/// Tally of reads by barcode extraction fate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FateStats {
+    pub extracted: usize,
+    pub no_flanks: usize,
+    pub no_before: usize,
+    pub no_after: usize,
+    pub misordered: usize,
+    pub short_insert: usize,
+    pub long_insert: usize,
+}
+
+impl FateStats {
+    /// Extracts the barcode from a read with `extractor`, records the
+    /// fate of the read, and returns the barcode record if extraction
+    /// succeeded.
+    pub fn extract(
+        &mut self,
+        extractor: &mut Extractor,
+        rec: &fastq::Record,
+    ) -> Option<fastq::Record> {
+        let result = extractor.extract(rec);
+        let tally = match result {
+            Ok(_) => &mut self.extracted,
+            Err(Fate::NoFlanks) => &mut self.no_flanks,
+            Err(Fate::NoBefore) => &mut self.no_before,
+            Err(Fate::NoAfter) => &mut self.no_after,
+            Err(Fate::Misordered) => &mut self.misordered,
+            Err(Fate::ShortInsert) => &mut self.short_insert,
+            Err(Fate::LongInsert) => &mut self.long_insert,
+        };
+        *tally += 1;
+        result.ok()
+    }
+
+    pub fn total(&self) -> usize {
+        self.extracted
+            + self.no_flanks
+            + self.no_before
+            + self.no_after
+            + self.misordered
+            + self.short_insert
+            + self.long_insert
+    }
+
+    /// Writes a table of read fates, in the format
+    /// fate<tab>reads<tab>fraction-of-reads, with fractions of 0 when
+    /// there are no reads.
+    pub fn write<W: Write>(&self, out: W) -> Result<(), failure::Error> {
+        let mut out = std::io::BufWriter::new(out);
+        let total = self.total();
+
+        writeln!(out, "fate\treads\tfraction")?;
+        for (name, reads) in vec![
+            ("extracted", self.extracted),
+            ("no_flanks", self.no_flanks),
+            ("no_before", self.no_before),
+            ("no_after", self.no_after),
+            ("misordered", self.misordered),
+            ("short_insert", self.short_insert),
+            ("long_insert", self.long_insert),
+        ] {
+            writeln!(
+                out,
+                "{}\t{}\t{:0.3}",
+                name,
+                reads,
+                if total > 0 {
+                    (reads as f64) / (total as f64)
+                } else {
+                    0.0
+                }
+            )?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl AddAssign for FateStats {
+    fn add_assign(&mut self, other: Self) {
+        self.extracted += other.extracted;
+        self.no_flanks += other.no_flanks;
+        self.no_before += other.no_before;
+        self.no_after += other.no_after;
+        self.misordered += other.misordered;
+        self.short_insert += other.short_insert;
+        self.long_insert += other.long_insert;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &[u8]) -> fastq::Record {
+        let qual: Vec<u8> = (0..seq.len()).map(|i| b'A' + (i % 20) as u8).collect();
+        fastq::Record::with_attrs("read", Some("umi=ACGT"), seq, &qual)
+    }
+
+    fn spec() -> ExtractSpec {
+        ExtractSpec {
+            revcomp: true,
+            min_insert: Some(6),
+            max_insert: Some(10),
+            ..ExtractSpec::new("gcgataaaag", "CCCAACGC", "1").unwrap()
+        }
+    }
+
+    #[test]
+    fn parse_spec() {
+        let spec = ExtractSpec::new("acgtac", "TTGCA", "2").unwrap();
+        assert_eq!(spec.before, b"ACGTAC".to_vec());
+        assert_eq!(spec.after, b"TTGCA".to_vec());
+        assert_eq!(spec.max_errors, 2);
+        assert!(ExtractSpec::new("ACGNAC", "TTGCA", "1").is_err());
+        assert!(ExtractSpec::new("", "TTGCA", "1").is_err());
+        assert!(ExtractSpec::new("ACGTAC", "TTGCA", "x").is_err());
+    }
+
+    #[test]
+    fn extract_forward() {
+        let mut extractor = Extractor::new(&spec());
+
+        let rec = record(b"TTGCGATAAAAGACGTACGTCCCAACGCTT");
+        let barcode = extractor.extract(&rec).unwrap();
+        assert_eq!(barcode.seq(), b"ACGTACGT");
+        assert_eq!(barcode.qual(), &rec.qual()[12..20]);
+        assert_eq!(barcode.id(), "read");
+        assert_eq!(barcode.desc(), Some("umi=ACGT"));
+
+        // One mismatch in each flank
+        let barcode = extractor
+            .extract(&record(b"TTGCGATTAAAGACGTACGTCCCTACGCTT"))
+            .unwrap();
+        assert_eq!(barcode.seq(), b"ACGTACGT");
+    }
+
+    #[test]
+    fn extract_revcomp() {
+        let rec = record(&dna::revcomp(&b"TTGCGATAAAAGACGTTCCCCCAACGCTT"[..]));
+        let barcode = Extractor::new(&spec()).extract(&rec).unwrap();
+        assert_eq!(barcode.seq(), b"ACGTTCC");
+        let rev_qual: Vec<u8> = rec.qual().iter().rev().cloned().collect();
+        assert_eq!(barcode.qual(), &rev_qual[12..19]);
+
+        let fwd_spec = ExtractSpec {
+            revcomp: false,
+            ..spec()
+        };
+        assert_eq!(Extractor::new(&fwd_spec).extract(&rec), Err(Fate::NoFlanks));
+    }
+
+    #[test]
+    fn fates() {
+        let mut extractor = Extractor::new(&spec());
+        assert_eq!(
+            extractor.extract(&record(b"TTTTTTTTTTTTTTTTTTTTTTTT")),
+            Err(Fate::NoFlanks)
+        );
+        assert_eq!(
+            extractor.extract(&record(b"TTTTTTTTTTACGTACGTCCCAACGCTT")),
+            Err(Fate::NoBefore)
+        );
+        assert_eq!(
+            extractor.extract(&record(b"TTGCGATAAAAGACGTACGTTTTTTTTTTT")),
+            Err(Fate::NoAfter)
+        );
+        assert_eq!(
+            extractor.extract(&record(b"TTCCCAACGCACGTACGTGCGATAAAAGTT")),
+            Err(Fate::Misordered)
+        );
+        assert_eq!(
+            extractor.extract(&record(b"TTGCGATAAAAGACGTCCCAACGCTT")),
+            Err(Fate::ShortInsert)
+        );
+        assert_eq!(
+            extractor.extract(&record(b"TTGCGATAAAAGACGTACGTACGTCCCAACGCTT")),
+            Err(Fate::LongInsert)
+        );
+    }
+
+    #[test]
+    fn fate_stats() {
+        let mut extractor = Extractor::new(&spec());
+        let mut stats = FateStats::default();
+        assert!(stats
+            .extract(&mut extractor, &record(b"TTGCGATAAAAGACGTACGTCCCAACGCTT"))
+            .is_some());
+        assert!(stats
+            .extract(&mut extractor, &record(b"TTTTTTTTTTTTTTTTTTTTTTTT"))
+            .is_none());
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "fate\treads\tfraction\nextracted\t1\t0.500\nno_flanks\t1\t0.500\nno_before\t0\t0.000\nno_after\t0\t0.000\nmisordered\t0\t0.000\nshort_insert\t0\t0.000\nlong_insert\t0\t0.000\n"
+        );
+    }
+
+    #[test]
+    fn empty_fate_stats() {
+        let mut out = Vec::new();
+        FateStats::default().write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "fate\treads\tfraction\nextracted\t0\t0.000\nno_flanks\t0\t0.000\nno_before\t0\t0.000\nno_after\t0\t0.000\nmisordered\t0\t0.000\nshort_insert\t0\t0.000\nlong_insert\t0\t0.000\n"
+        );
+    }
+}
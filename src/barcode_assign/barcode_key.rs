@@ -0,0 +1,172 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+const MAX_PACKED_LEN: usize = 32;
+
+const NTS: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+/// Compact key for a barcode sequence.
+///
+/// Barcodes of up to 32 `A`, `C`, `G`, and `T` bases are packed at 2
+/// bits per base with no heap allocation. Longer barcodes, or those
+/// with `N` or any other character, are stored unpacked. Every
+/// sequence has exactly one key representation, so keys can be
+/// compared and hashed directly.
+///
+/// Keys are ordered lexicographically by their sequences.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum BarcodeKey {
+    Packed { len: u8, bits: u64 },
+    Raw(Box<[u8]>),
+}
+
+impl BarcodeKey {
+    pub fn new(barcode: &[u8]) -> Self {
+        Self::pack(barcode).unwrap_or_else(|| BarcodeKey::Raw(barcode.into()))
+    }
+
+    fn pack(barcode: &[u8]) -> Option<Self> {
+        if barcode.len() > MAX_PACKED_LEN {
+            return None;
+        }
+
+        let mut bits = 0;
+        for nt in barcode.iter() {
+            let code = match nt {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => return None,
+            };
+            bits = (bits << 2) | code;
+        }
+
+        Some(BarcodeKey::Packed {
+            len: barcode.len() as u8,
+            bits: bits,
+        })
+    }
+
+    /// Returns the length of the barcode sequence.
+    pub fn len(&self) -> usize {
+        match self {
+            BarcodeKey::Packed { len, .. } => *len as usize,
+            BarcodeKey::Raw(barcode) => barcode.len(),
+        }
+    }
+
+    pub fn is_packed(&self) -> bool {
+        match self {
+            BarcodeKey::Packed { .. } => true,
+            BarcodeKey::Raw(_) => false,
+        }
+    }
+
+    /// Returns the barcode sequence.
+    pub fn to_vec(&self) -> Vec<u8> {
+        match self {
+            BarcodeKey::Packed { len, bits } => (0..*len)
+                .rev()
+                .map(|i| NTS[((bits >> (2 * i)) & 0x03) as usize])
+                .collect(),
+            BarcodeKey::Raw(barcode) => barcode.to_vec(),
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for BarcodeKey {
+    fn from(barcode: &'a [u8]) -> Self {
+        BarcodeKey::new(barcode)
+    }
+}
+
+impl Ord for BarcodeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (
+                BarcodeKey::Packed {
+                    len: len_l,
+                    bits: bits_l,
+                },
+                BarcodeKey::Packed {
+                    len: len_r,
+                    bits: bits_r,
+                },
+            ) if len_l == len_r => bits_l.cmp(bits_r),
+            _ => self.to_vec().cmp(&other.to_vec()),
+        }
+    }
+}
+
+impl PartialOrd for BarcodeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BarcodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.to_vec()))
+    }
+}
+
+impl fmt::Debug for BarcodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BarcodeKey({:?})", self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for bc in vec![
+            &b""[..],
+            b"A",
+            b"ACGTACGTAC",
+            b"TTTTTTTTTTTTTTTTTTTTTTTTTTTTTTTT",
+            b"ACGTNCGT",
+            b"acgt",
+            b"ACGTACGTACGTACGTACGTACGTACGTACGTA",
+        ] {
+            let key = BarcodeKey::new(bc);
+            assert_eq!(key.to_vec(), bc.to_vec());
+            assert_eq!(key.len(), bc.len());
+            assert_eq!(key.to_string(), String::from_utf8_lossy(bc));
+        }
+
+        assert!(BarcodeKey::new(b"ACGTACGTACGTACGTACGTACGTACGTACGT").is_packed());
+        assert!(!BarcodeKey::new(b"ACGTACGTACGTACGTACGTACGTACGTACGTA").is_packed());
+        assert!(!BarcodeKey::new(b"ACGTNCGT").is_packed());
+    }
+
+    #[test]
+    fn distinct_lengths() {
+        assert_ne!(BarcodeKey::new(b"A"), BarcodeKey::new(b"AA"));
+        assert_ne!(BarcodeKey::new(b""), BarcodeKey::new(b"A"));
+    }
+
+    #[test]
+    fn ordering() {
+        let mut barcodes: Vec<Vec<u8>> = vec![
+            b"TACG".to_vec(),
+            b"ACGT".to_vec(),
+            b"ACG".to_vec(),
+            b"ACGTN".to_vec(),
+            b"ACGTA".to_vec(),
+            b"NACG".to_vec(),
+            b"CA".to_vec(),
+        ];
+        let mut keys: Vec<BarcodeKey> = barcodes.iter().map(|bc| BarcodeKey::new(bc)).collect();
+
+        barcodes.sort();
+        keys.sort();
+        assert_eq!(
+            keys.iter().map(BarcodeKey::to_vec).collect::<Vec<_>>(),
+            barcodes
+        );
+    }
+}
@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 use std::io::BufRead;
-use std::io::BufReader;
-use std::io::Read;
 use std::path::{Path, PathBuf};
 
+use barcode_key::BarcodeKey;
+use compress::{create_output, open_input};
+use counts::SampleCounts;
 use neighborhood::*;
 
 pub struct CLI {
     pub input: String,
+    /// Read a `barcode<TAB>count` table rather than one barcode per line
+    pub counts: bool,
+    /// Header of the count column to read from a count table
+    pub column: Option<String>,
     pub output_base: String,
+    pub nbhd_spec: NeighborhoodSpec,
 }
 
 impl CLI {
@@ -22,32 +28,34 @@ impl CLI {
     }
 
     pub fn run(&self) -> Result<(), failure::Error> {
-        let reader: Box<dyn Read> = if self.input == "-" {
-            Box::new(std::io::stdin())
+        let barcode_counts = if self.counts {
+            SampleCounts::from_file_column(&self.input, self.column.as_deref())?.key_map()
         } else {
-            Box::new(std::fs::File::open(&self.input)?)
+            CLI::count_barcodes(&mut open_input(&self.input)?)?
         };
-        let mut barcode_reader = BufReader::new(reader);
 
-        let barcode_counts = CLI::count_barcodes(&mut barcode_reader)?;
-
-        let nbhds_raw = Neighborhood::gather_neighborhoods(barcode_counts);
+        let nbhds_raw = Neighborhood::gather(barcode_counts, &self.nbhd_spec);
         let nbhds: Vec<_> = nbhds_raw.into_iter().map(|n| n.into_sorted()).collect();
 
-        SortedNeighborhood::write_tables(&self.output_base, nbhds.iter())?;
+        SortedNeighborhood::write_tables(&self.output_base, nbhds.iter(), &self.nbhd_spec)?;
+
+        let mut key_map_out =
+            create_output(&self.output_filename("-key-map.txt").to_string_lossy())?;
+        SortedNeighborhood::write_key_map(&mut key_map_out, nbhds.iter())?;
+        key_map_out.finish()?;
 
         Ok(())
     }
 
     pub fn count_barcodes<R: BufRead>(
         barcode_reader: &mut R,
-    ) -> Result<HashMap<Vec<u8>, usize>, failure::Error> {
+    ) -> Result<HashMap<BarcodeKey, usize>, failure::Error> {
         let mut barcode_counts = HashMap::new();
 
         for line_res in barcode_reader.lines() {
             let line = line_res?;
 
-            let barcode = line.into_bytes();
+            let barcode = BarcodeKey::new(line.as_bytes());
             let barcode_count = barcode_counts.entry(barcode).or_insert(0);
             *barcode_count += 1;
         }
@@ -55,3 +63,45 @@ impl CLI {
         Ok(barcode_counts)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    #[test]
+    fn collapse_count_table() {
+        let mut count_file = tempfile::NamedTempFile::new().unwrap();
+        count_file
+            .write_all(b"barcode\tcount\nACGTACGT\t100\nACGTACGA\t4\nCATGCATG\t7\n")
+            .unwrap();
+        let count_path = count_file.into_temp_path();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_base = output_dir.path().join("collapse");
+
+        let cli = CLI {
+            input: count_path.to_string_lossy().into_owned(),
+            counts: true,
+            column: None,
+            output_base: output_base.to_string_lossy().into_owned(),
+            nbhd_spec: NeighborhoodSpec::default(),
+        };
+        cli.run().unwrap();
+
+        let key_map_file = std::fs::File::open(cli.output_filename("-key-map.txt")).unwrap();
+        let keys = read_key_map(std::io::BufReader::new(key_map_file)).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(
+            keys.get(&BarcodeKey::new(b"ACGTACGA")),
+            Some(&BarcodeKey::new(b"ACGTACGT"))
+        );
+        assert_eq!(
+            keys.get(&BarcodeKey::new(b"CATGCATG")),
+            Some(&BarcodeKey::new(b"CATGCATG"))
+        );
+
+        let nbhds = std::fs::read_to_string(cli.output_filename("-nbhds.txt")).unwrap();
+        assert!(nbhds.contains("\nACGTACGT\t2\t104\t100\t"));
+    }
+}
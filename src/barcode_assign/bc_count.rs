@@ -1,10 +1,12 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
-
 use bio::io::fastq;
 
+use barcode_extract::*;
+use compress::*;
 use counts::SampleCounts;
+use fastq_chunks::*;
 use neighborhood::*;
+use read_filter::*;
+use whitelist::*;
 
 #[derive(Debug)]
 pub struct Config {
@@ -12,53 +14,123 @@ pub struct Config {
     pub out_barcodes: String,
     pub freq_filename: Option<String>,
     pub neighborhood: Option<String>,
+    pub nbhd_spec: NeighborhoodSpec,
+    pub whitelist: Option<String>,
+    pub whitelist_distance: usize,
+    pub correction_summary: Option<String>,
+    pub read_filter: ReadFilter,
+    pub filter_report: Option<String>,
+    pub threads: usize,
+    pub extract: Option<ExtractSpec>,
+    pub fates_report: Option<String>,
 }
 
 pub fn bc_count(config: Config) -> Result<(), failure::Error> {
-    let reader: Box<dyn Read> = if config.barcode_fastq == "-" {
-        Box::new(io::stdin())
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .build()?;
+    pool.install(|| count_barcodes(config))
+}
+
+fn count_barcodes(config: Config) -> Result<(), failure::Error> {
+    let (raw_counts, filter_stats, fate_stats) = fold_chunks(
+        open_input(&config.barcode_fastq)?,
+        CHUNK_RECORDS,
+        |chunk| count_chunk(chunk, &config),
+        |(mut counts, mut filters, mut fates), (chunk_counts, chunk_filters, chunk_fates)| {
+            counts += chunk_counts;
+            filters += chunk_filters;
+            fates += chunk_fates;
+            (counts, filters, fates)
+        },
+    )?;
+
+    if let Some(ref fates_filename) = config.fates_report {
+        let mut fates_out = create_output(fates_filename)?;
+        fate_stats.write(&mut fates_out)?;
+        fates_out.finish()?;
+    }
+
+    if let Some(ref report_filename) = config.filter_report {
+        let mut report_out = create_output(report_filename)?;
+        filter_stats.write(&mut report_out)?;
+        report_out.finish()?;
+    }
+
+    let barcode_counts = if let Some(ref whitelist_filename) = config.whitelist {
+        let whitelist = Whitelist::from_file(
+            whitelist_filename,
+            config.nbhd_spec.metric,
+            config.whitelist_distance,
+        )?;
+        let (corrected_counts, stats) = raw_counts.correct(&whitelist);
+        if let Some(ref summary_filename) = config.correction_summary {
+            let mut summary_out = create_output(summary_filename)?;
+            stats.write(&mut summary_out)?;
+            summary_out.finish()?;
+        }
+        corrected_counts
     } else {
-        Box::new(File::open(&config.barcode_fastq)?)
+        raw_counts
     };
-    let barcode_reader = fastq::Reader::new(reader);
-
-    let barcode_counts_res: Result<SampleCounts, bio::io::fastq::Error> =
-        barcode_reader.records().collect();
-    let barcode_counts = barcode_counts_res?;
 
     let final_counts = if let Some(nbhd_filename) = config.neighborhood {
-        neighborhood_counts(barcode_counts, &nbhd_filename)?
+        neighborhood_counts(barcode_counts, &nbhd_filename, &config.nbhd_spec)?
     } else {
         barcode_counts
     };
 
-    let writer: Box<dyn Write> = if config.out_barcodes == "-" {
-        Box::new(io::stdout())
-    } else {
-        Box::new(File::create(&config.out_barcodes)?)
-    };
-    final_counts.write(writer)?;
+    let mut counts_out = create_output(&config.out_barcodes)?;
+    final_counts.write(&mut counts_out)?;
+    counts_out.finish()?;
 
     if let Some(freq_filename) = config.freq_filename {
-        final_counts.write_freq_table(File::create(freq_filename)?)?;
+        let mut freq_out = create_output(&freq_filename)?;
+        final_counts.write_freq_table(&mut freq_out)?;
+        freq_out.finish()?;
     }
 
     Ok(())
 }
 
+fn count_chunk(
+    chunk: &[u8],
+    config: &Config,
+) -> Result<(SampleCounts, FilterStats, FateStats), failure::Error> {
+    let mut extractor = config.extract.as_ref().map(Extractor::new);
+    let mut barcode_counts = SampleCounts::default();
+    let mut filter_stats = FilterStats::default();
+    let mut fate_stats = FateStats::default();
+
+    for recres in fastq::Reader::new(chunk).records() {
+        let rec = recres?;
+        let barcode_rec = match extractor {
+            Some(ref mut extractor) => match fate_stats.extract(extractor, &rec) {
+                Some(barcode_rec) => barcode_rec,
+                None => continue,
+            },
+            None => rec,
+        };
+        if filter_stats.check(&config.read_filter, &barcode_rec) {
+            barcode_counts.count_one(barcode_rec.seq());
+        }
+    }
+
+    Ok((barcode_counts, filter_stats, fate_stats))
+}
+
 fn neighborhood_counts(
     barcode_counts: SampleCounts,
     nbhd_filename: &str,
+    nbhd_spec: &NeighborhoodSpec,
 ) -> Result<SampleCounts, failure::Error> {
-    let nbhds_raw = Neighborhood::gather_neighborhoods(barcode_counts.count_map());
+    let nbhds_raw = Neighborhood::gather_parallel(barcode_counts.key_map(), nbhd_spec);
     let nbhds: Vec<_> = nbhds_raw.into_iter().map(|n| n.into_sorted()).collect();
 
-    SortedNeighborhood::write_tables(&nbhd_filename, nbhds.iter())?;
+    SortedNeighborhood::write_tables(&nbhd_filename, nbhds.iter(), nbhd_spec)?;
 
     Ok(std::iter::FromIterator::from_iter(
-        nbhds
-            .iter()
-            .map(|n| (n.key_barcode().0.to_vec(), n.total())),
+        nbhds.iter().map(|n| (n.key_barcode().0.clone(), n.total())),
     ))
 }
 
@@ -69,6 +141,7 @@ mod tests {
 
     use self::rand::thread_rng;
     use self::rand::Rng;
+    use std::io::Write;
 
     use super::*;
 
@@ -110,6 +183,15 @@ ACGTTGCA
             out_barcodes: count_path.to_string_lossy().into_owned(),
             freq_filename: None,
             neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
         };
 
         bc_count(config).unwrap();
@@ -127,6 +209,54 @@ ACGTTGCA
         );
     }
 
+    #[test]
+    fn count_compressed() {
+        let barcode_fq = "@one\nACGTTGCA\n+\n~~~~~~~~\n@two\nCGTAATGC\n+\n~~~~~~~~\n@three\nACGTTGCA\n+\n~~~~~~~~\n";
+
+        let fastq_file = tempfile::Builder::new()
+            .suffix(".fq.gz")
+            .tempfile()
+            .unwrap();
+        {
+            let mut fastq_out = compress(&fastq_file, Format::Gzip).unwrap();
+            fastq_out.write_all(barcode_fq.as_bytes()).unwrap();
+        }
+        let fastq_path = fastq_file.into_temp_path();
+
+        let count_path = tempfile::Builder::new()
+            .suffix(".txt.zst")
+            .tempfile()
+            .unwrap()
+            .into_temp_path();
+
+        let config = Config {
+            barcode_fastq: fastq_path.to_string_lossy().into_owned(),
+            out_barcodes: count_path.to_string_lossy().into_owned(),
+            freq_filename: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+
+        bc_count(config).unwrap();
+
+        let count_in = decompress(std::fs::File::open(&count_path).unwrap()).unwrap();
+        let counts = SampleCounts::read(count_in).unwrap();
+        let mut cvec: Vec<(Vec<u8>, usize)> = counts.into_iter().collect();
+        cvec.sort();
+        assert_eq!(
+            cvec,
+            vec![(b"ACGTTGCA".to_vec(), 2), (b"CGTAATGC".to_vec(), 1)]
+        );
+    }
+
     fn barcode_records(bc: &[u8], ct: usize) -> impl Iterator<Item = fastq::Record> {
         let barcode = bc.to_vec();
         let qual = vec![b'~'; barcode.len()];
@@ -170,6 +300,69 @@ ACGTTGCA
             out_barcodes: count_path.to_string_lossy().into_owned(),
             freq_filename: None,
             neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+
+        bc_count(config).unwrap();
+
+        let out_counts = SampleCounts::from_file(count_path).unwrap();
+        let mut cvec: Vec<(Vec<u8>, usize)> = out_counts.count_map().into_iter().collect();
+        cvec.sort();
+        counts.sort();
+        assert_eq!(counts, cvec);
+    }
+
+    #[test]
+    fn count_threaded() {
+        let mut rng = thread_rng();
+        let mut counts: Vec<(Vec<u8>, usize)> = (0..50)
+            .map(|_| {
+                let bc: Vec<u8> = (0..12).map(|_| *rng.choose(b"ACGT").unwrap()).collect();
+                (bc, rng.gen_range(1, 2000))
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+            .into_iter()
+            .collect();
+
+        let mut records: Vec<fastq::Record> = counts
+            .iter()
+            .flat_map(|(bc, ct)| barcode_records(bc, *ct))
+            .collect();
+        rng.shuffle(&mut records);
+
+        let fastq_file = tempfile::NamedTempFile::new().unwrap();
+        {
+            let mut fastq_writer = fastq::Writer::new(&fastq_file);
+            for rec in records.iter() {
+                fastq_writer.write_record(rec).unwrap();
+            }
+        }
+        let fastq_path = fastq_file.into_temp_path();
+
+        let count_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: fastq_path.to_string_lossy().into_owned(),
+            out_barcodes: count_path.to_string_lossy().into_owned(),
+            freq_filename: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 4,
+            extract: None,
+            fates_report: None,
         };
 
         bc_count(config).unwrap();
@@ -218,6 +411,15 @@ ACGTTGCA
             out_barcodes: count_path.to_string_lossy().into_owned(),
             freq_filename: Some(freq_path.to_string_lossy().into_owned()),
             neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
         };
 
         bc_count(config).unwrap();
@@ -284,6 +486,15 @@ ACGTTGCA
             out_barcodes: count_path.to_string_lossy().into_owned(),
             freq_filename: None,
             neighborhood: Some(nbhd_path.to_string_lossy().into_owned()),
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
         };
 
         bc_count(config).unwrap();
@@ -297,4 +508,200 @@ ACGTTGCA
         nbhds.sort();
         assert_eq!(nbhds, cvec);
     }
+
+    #[test]
+    fn count_whitelist() {
+        let barcode_fq = r#"@one
+ACGTTGCA
++
+~~~~~~~~
+@two
+ACGTTGCT
++
+~~~~~~~~
+@three
+ACGTTGCA
++
+~~~~~~~~
+@four
+GTACCATG
++
+~~~~~~~~
+@five
+CGTAATGC
++
+~~~~~~~~
+"#;
+
+        let mut fastq_file = tempfile::NamedTempFile::new().unwrap();
+        fastq_file.write_all(barcode_fq.as_bytes()).unwrap();
+
+        let mut whitelist_file = tempfile::NamedTempFile::new().unwrap();
+        whitelist_file
+            .write_all(b"barcode\nACGTTGCA\nCGTAATGC\n")
+            .unwrap();
+
+        let count_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let summary_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: fastq_file.path().to_string_lossy().into_owned(),
+            out_barcodes: count_path.to_string_lossy().into_owned(),
+            freq_filename: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: Some(whitelist_file.path().to_string_lossy().into_owned()),
+            whitelist_distance: 1,
+            correction_summary: Some(summary_path.to_string_lossy().into_owned()),
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+
+        bc_count(config).unwrap();
+
+        let counts = SampleCounts::from_file(count_path).unwrap();
+        let mut cvec: Vec<(Vec<u8>, usize)> = counts.into_iter().collect();
+        cvec.sort();
+        assert_eq!(
+            cvec,
+            vec![(b"ACGTTGCA".to_vec(), 3), (b"CGTAATGC".to_vec(), 1)]
+        );
+
+        let summary = std::fs::read_to_string(summary_path).unwrap();
+        assert_eq!(
+            summary,
+            "outcome\tbarcodes\treads\tfraction\nexact\t2\t3\t0.600\ncorrected\t1\t1\t0.200\nambiguous\t0\t0\t0.000\nunmatched\t1\t1\t0.200\n"
+        );
+    }
+
+    #[test]
+    fn count_filtered() {
+        let barcode_fq = r#"@one
+ACGTTGCA
++
+IIIIIIII
+@two
+ACGTNGCA
++
+IIII#III
+@three
+ACGTTGCA
++
+IIIII$II
+@four
+GTACCATG
++
+IIIIIIII
+@five
+GTACCAT
++
+IIIIIII
+"#;
+
+        let mut fastq_file = tempfile::NamedTempFile::new().unwrap();
+        fastq_file.write_all(barcode_fq.as_bytes()).unwrap();
+
+        let count_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let report_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: fastq_file.path().to_string_lossy().into_owned(),
+            out_barcodes: count_path.to_string_lossy().into_owned(),
+            freq_filename: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter {
+                min_qual: Some(10),
+                max_n: Some(0),
+                min_len: Some(8),
+                ..ReadFilter::default()
+            },
+            filter_report: Some(report_path.to_string_lossy().into_owned()),
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+
+        bc_count(config).unwrap();
+
+        let counts = SampleCounts::from_file(count_path).unwrap();
+        let mut cvec: Vec<(Vec<u8>, usize)> = counts.into_iter().collect();
+        cvec.sort();
+        assert_eq!(
+            cvec,
+            vec![(b"ACGTTGCA".to_vec(), 1), (b"GTACCATG".to_vec(), 1)]
+        );
+
+        let report = std::fs::read_to_string(report_path).unwrap();
+        assert_eq!(
+            report,
+            "filter\treads\tfraction\npassed\t2\t0.400\nlength\t1\t0.200\nn_count\t1\t0.200\nmin_qual\t1\t0.200\nexpected_errors\t0\t0.000\n"
+        );
+    }
+
+    #[test]
+    fn count_extracted() {
+        let barcode_fq = r#"@one
+TTGCGATAAAAGACGTTGCACCCAACGCTT
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+@two
+AAGCGTTGGGTGCAACGTCTTTTATCGCAA
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+@three
+TTGCGATAAAAGCGTAATGCCCCAACGCTT
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+@four
+TTGCGATAAAAGCGTAATGCTTTTTTTTTT
++
+IIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+"#;
+
+        let mut fastq_file = tempfile::NamedTempFile::new().unwrap();
+        fastq_file.write_all(barcode_fq.as_bytes()).unwrap();
+
+        let count_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let fates_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: fastq_file.path().to_string_lossy().into_owned(),
+            out_barcodes: count_path.to_string_lossy().into_owned(),
+            freq_filename: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            whitelist: None,
+            whitelist_distance: 1,
+            correction_summary: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: Some(ExtractSpec {
+                revcomp: true,
+                ..ExtractSpec::new("GCGATAAAAG", "CCCAACGC", "1").unwrap()
+            }),
+            fates_report: Some(fates_path.to_string_lossy().into_owned()),
+        };
+
+        bc_count(config).unwrap();
+
+        let counts = SampleCounts::from_file(count_path).unwrap();
+        let mut cvec: Vec<(Vec<u8>, usize)> = counts.into_iter().collect();
+        cvec.sort();
+        assert_eq!(
+            cvec,
+            vec![(b"ACGTTGCA".to_vec(), 2), (b"CGTAATGC".to_vec(), 1)]
+        );
+
+        let fates = std::fs::read_to_string(fates_path).unwrap();
+        assert!(fates.starts_with("fate\treads\tfraction\nextracted\t3\t0.750\n"));
+        assert!(fates.contains("\nno_after\t1\t0.250\n"));
+    }
 }
@@ -1,43 +1,106 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bio::io::fastq;
 
-use fastq_pair;
+use barcode_extract::*;
+use barcode_key::BarcodeKey;
+use compress::*;
+use consensus::*;
+use fastq_pair::*;
+use fastq_sort::*;
 use neighborhood::*;
 
 #[derive(Debug)]
 pub struct Config {
     pub barcode_fastq: String,
-    pub sequ_fastq: String,
+    /// FastQ file of insert sequences, or `None` when `barcode_fastq`
+    /// interleaves each barcode read with its insert read
+    pub sequ_fastq: Option<String>,
+    /// Handling of barcode and insert reads whose IDs do not agree
+    pub id_check: IdCheck,
     pub out_fastq: String,
     pub out_barcodes: Option<String>,
     pub out_barcode_freqs: Option<String>,
     pub neighborhood: Option<String>,
+    pub nbhd_spec: NeighborhoodSpec,
+    pub extract: Option<ExtractSpec>,
+    pub fates_report: Option<String>,
+    /// Group sequences with an external sort, holding at most this
+    /// many records in memory, rather than holding every record
+    pub sort_records: Option<usize>,
+    /// Directory for temporary files in an external sort
+    pub temp_dir: Option<String>,
+    /// Base name for consensus sequences of each barcode and a table
+    /// of their read support
+    pub consensus: Option<String>,
+    pub consensus_spec: ConsensusSpec,
 }
 
-pub fn bc_seqs(config: Config) -> Result<()> {
-    let barcode_reader = fastq::Reader::from_file(&config.barcode_fastq)?;
-    let sequ_reader = fastq::Reader::from_file(&config.sequ_fastq)?;
+/// Groups insert sequences by barcode as described by `config`.
+/// Returns the number of read pairs whose IDs do not agree, which is
+/// non-zero only when `config.id_check` is `IdCheck::Count`.
+pub fn bc_seqs(config: Config) -> Result<usize> {
+    let barcode_reader = fastq::Reader::new(open_input(&config.barcode_fastq)?);
 
-    let mut fastq_writer = fastq::Writer::to_file(&config.out_fastq)?;
+    let mut pair_records = match config.sequ_fastq {
+        Some(ref sequ_fastq) => {
+            let sequ_reader = fastq::Reader::new(open_input(sequ_fastq)?);
+            PairRecords::new(barcode_reader.records(), sequ_reader.records())
+        }
+        None => PairRecords::interleaved(barcode_reader.records()),
+    }
+    .with_id_check(config.id_check);
 
-    let mut barcode_recs = HashMap::new();
+    let mut extractor = config.extract.as_ref().map(Extractor::new);
+    let mut fate_stats = FateStats::default();
+
+    let barcoded_records = pair_records.by_ref().filter_map(|pair_result| {
+        let (barcode_record, sequ_record) = match pair_result {
+            Ok(pair) => pair,
+            Err(e) => return Some(Err(anyhow!(e))),
+        };
+        let barcode_record = match extractor {
+            Some(ref mut extractor) => fate_stats.extract(extractor, &barcode_record)?,
+            None => barcode_record,
+        };
+        Some(Ok((BarcodeKey::new(barcode_record.seq()), sequ_record)))
+    });
+
+    if let Some(sort_records) = config.sort_records {
+        group_sorted(barcoded_records, &config, sort_records)?;
+    } else {
+        group_in_memory(barcoded_records, &config)?;
+    }
+
+    if let Some(ref fates_filename) = config.fates_report {
+        let mut fates_out = create_output(fates_filename)?;
+        fate_stats.write(&mut fates_out).map_err(|e| anyhow!(e))?;
+        fates_out.finish()?;
+    }
+
+    Ok(pair_records.mismatches())
+}
+
+// Groups sequences by barcode, holding all of them in memory.
+fn group_in_memory<I>(barcoded_records: I, config: &Config) -> Result<()>
+where
+    I: Iterator<Item = Result<(BarcodeKey, fastq::Record)>>,
+{
+    let mut fastq_out = create_output(&config.out_fastq)?;
+    let mut fastq_writer = fastq::Writer::new(&mut fastq_out);
 
-    let pair_records =
-        fastq_pair::PairRecords::new(barcode_reader.records(), sequ_reader.records());
+    let mut barcode_recs = HashMap::new();
 
-    for pair_result in pair_records {
-        let (barcode_record, sequ_record) = pair_result?;
-        let barcode = barcode_record.seq().to_vec();
+    for barcoded in barcoded_records {
+        let (barcode, sequ_record) = barcoded?;
         let recs = barcode_recs.entry(barcode).or_insert_with(|| Vec::new());
         recs.push(sequ_record);
     }
 
-    let bc_recs = if let Some(nbhd_filename) = config.neighborhood {
-        let nbhds_raw = Neighborhood::gather_neighborhoods(barcode_recs);
+    let bc_recs = if let Some(ref nbhd_filename) = config.neighborhood {
+        let nbhds_raw = Neighborhood::gather(barcode_recs, &config.nbhd_spec);
         let mut nbhds: Vec<_> = nbhds_raw.into_iter().map(|n| n.into_sorted()).collect();
         nbhds.sort_unstable_by(|nbhdl, nbhdr| nbhdl.key_barcode().0.cmp(nbhdr.key_barcode().0));
 
@@ -56,66 +119,297 @@ pub fn bc_seqs(config: Config) -> Result<()> {
             nbhd_recs.push((barcode, recs));
         }
 
-        SortedNeighborhood::write_tables(&nbhd_filename, nbhd_counts.iter())?;
+        SortedNeighborhood::write_tables(&nbhd_filename, nbhd_counts.iter(), &config.nbhd_spec)?;
 
         nbhd_recs
     } else {
-        let mut bc_recs: Vec<(Vec<u8>, Vec<fastq::Record>)> = barcode_recs.into_iter().collect();
+        let mut bc_recs: Vec<(Vec<u8>, Vec<fastq::Record>)> = barcode_recs
+            .into_iter()
+            .map(|(bc, recs)| (bc.to_vec(), recs))
+            .collect();
         bc_recs.sort_unstable_by(|(bcl, _recsl), (bcr, _recsr)| bcl.cmp(bcr));
         bc_recs
     };
 
-    if let Some(barcode_filename) = config.out_barcodes {
-        let barcode_writer = File::create(barcode_filename)?;
-        write_barcode_table(barcode_writer, bc_recs.iter())?;
-    }
+    write_group_tables(
+        config,
+        bc_recs.iter().map(|(bc, recs)| (bc.as_slice(), recs.len())),
+    )?;
 
-    if let Some(freq_filename) = config.out_barcode_freqs {
-        let freq_writer = File::create(freq_filename)?;
-        write_freq_table(freq_writer, bc_recs.iter())?;
-    }
+    let mut consensus_writer = ConsensusWriter::from_config(config)?;
 
     for (bc, recs) in bc_recs {
         let barcode = String::from_utf8(bc)?;
 
+        if let Some(ref mut consensus_writer) = consensus_writer {
+            let mut pileup = Pileup::new();
+            for rec in recs.iter() {
+                pileup.add(rec);
+            }
+            consensus_writer.write(&barcode, &pileup)?;
+        }
+
         for (recidx, rec) in recs.into_iter().enumerate() {
-            let name = format!("{}_{}", barcode, recidx + 1);
-            let named_record = fastq::Record::with_attrs(&name, None, rec.seq(), rec.qual());
-            fastq_writer.write_record(&named_record)?;
+            write_named_record(&mut fastq_writer, &barcode, recidx, &rec)?;
         }
     }
 
+    fastq_writer.flush()?;
+    drop(fastq_writer);
+    fastq_out.finish()?;
+
+    if let Some(consensus_writer) = consensus_writer {
+        consensus_writer.finish()?;
+    }
+
     Ok(())
 }
 
-fn write_barcode_table<'i, W, I, A: 'i>(barcode_out: W, barcode_iter: I) -> Result<()>
+// Groups sequences by barcode with an external sort. Only the count
+// for each distinct barcode is held in memory, along with at most
+// `sort_records` sequences. Neighborhoods need a second sort, as the
+// key barcode for each sequence is known only after all barcodes
+// have been counted.
+fn group_sorted<I>(barcoded_records: I, config: &Config, sort_records: usize) -> Result<()>
+where
+    I: Iterator<Item = Result<(BarcodeKey, fastq::Record)>>,
+{
+    let temp_dir = config.temp_dir.as_deref();
+
+    let mut barcode_sort = ExternalSort::new(sort_records, temp_dir)?;
+    let mut barcode_counts = HashMap::new();
+
+    for barcoded in barcoded_records {
+        let (barcode, sequ_record) = barcoded?;
+        *barcode_counts.entry(barcode.clone()).or_insert(0) += 1;
+        barcode_sort.push(KeyedRecord {
+            key: barcode,
+            rank: 0,
+            record: sequ_record,
+        })?;
+    }
+
+    let (sorted, group_counts) = if let Some(ref nbhd_filename) = config.neighborhood {
+        let nbhds_raw = Neighborhood::gather(barcode_counts, &config.nbhd_spec);
+        let mut nbhds: Vec<_> = nbhds_raw.into_iter().map(|n| n.into_sorted()).collect();
+        nbhds.sort_unstable_by(|nbhdl, nbhdr| nbhdl.key_barcode().0.cmp(nbhdr.key_barcode().0));
+
+        SortedNeighborhood::write_tables(&nbhd_filename, nbhds.iter(), &config.nbhd_spec)?;
+
+        // Sequences are grouped under the key barcode and then ordered
+        // by their barcode within the neighborhood, as in memory
+        let mut nbhd_ranks = HashMap::new();
+        for nbhd in nbhds.iter() {
+            let (key, _) = nbhd.key_barcode();
+            for (rank, (barcode, _)) in nbhd.barcodes().enumerate() {
+                nbhd_ranks.insert(barcode.clone(), (key.clone(), rank));
+            }
+        }
+
+        let mut nbhd_sort = ExternalSort::new(sort_records, temp_dir)?;
+        for keyed in barcode_sort.into_sorted()? {
+            let keyed = keyed?;
+            let (key, rank) = nbhd_ranks
+                .get(&keyed.key)
+                .cloned()
+                .ok_or_else(|| anyhow!("No neighborhood for barcode {}", keyed.key))?;
+            nbhd_sort.push(KeyedRecord {
+                key: key,
+                rank: rank,
+                record: keyed.record,
+            })?;
+        }
+
+        let group_counts: Vec<(Vec<u8>, usize)> = nbhds
+            .iter()
+            .map(|nbhd| (nbhd.key_barcode().0.to_vec(), nbhd.total()))
+            .collect();
+        (nbhd_sort.into_sorted()?, group_counts)
+    } else {
+        let mut group_counts: Vec<(Vec<u8>, usize)> = barcode_counts
+            .into_iter()
+            .map(|(bc, count)| (bc.to_vec(), count))
+            .collect();
+        group_counts.sort_unstable_by(|(bcl, _countl), (bcr, _countr)| bcl.cmp(bcr));
+        (barcode_sort.into_sorted()?, group_counts)
+    };
+
+    write_group_tables(
+        config,
+        group_counts
+            .iter()
+            .map(|(bc, count)| (bc.as_slice(), *count)),
+    )?;
+
+    let mut fastq_out = create_output(&config.out_fastq)?;
+    let mut fastq_writer = fastq::Writer::new(&mut fastq_out);
+
+    let mut consensus_writer = ConsensusWriter::from_config(config)?;
+    let mut pileup = Pileup::new();
+
+    let mut group: Option<(BarcodeKey, String)> = None;
+    let mut recidx = 0;
+    for keyed in sorted {
+        let keyed = keyed?;
+        match group {
+            Some((ref key, _)) if *key == keyed.key => recidx += 1,
+            _ => {
+                if let (Some((_, ref barcode)), Some(ref mut consensus_writer)) =
+                    (&group, &mut consensus_writer)
+                {
+                    consensus_writer.write(barcode, &pileup)?;
+                }
+                pileup = Pileup::new();
+
+                let barcode = String::from_utf8(keyed.key.to_vec())?;
+                group = Some((keyed.key.clone(), barcode));
+                recidx = 0;
+            }
+        }
+        if let Some((_, ref barcode)) = group {
+            write_named_record(&mut fastq_writer, barcode, recidx, &keyed.record)?;
+        }
+        if consensus_writer.is_some() {
+            pileup.add(&keyed.record);
+        }
+    }
+
+    if let (Some((_, ref barcode)), Some(ref mut consensus_writer)) =
+        (&group, &mut consensus_writer)
+    {
+        consensus_writer.write(barcode, &pileup)?;
+    }
+
+    fastq_writer.flush()?;
+    drop(fastq_writer);
+    fastq_out.finish()?;
+
+    if let Some(consensus_writer) = consensus_writer {
+        consensus_writer.finish()?;
+    }
+
+    Ok(())
+}
+
+// Writes one consensus record for each barcode with enough reads to
+// call a consensus, along with the read support for every barcode.
+struct ConsensusWriter {
+    spec: ConsensusSpec,
+    fastq_out: BufWriter<Output<Box<dyn Write>>>,
+    support_out: BufWriter<Output<Box<dyn Write>>>,
+}
+
+impl ConsensusWriter {
+    fn from_config(config: &Config) -> Result<Option<Self>> {
+        let filebase = match config.consensus {
+            Some(ref filebase) => filebase,
+            None => return Ok(None),
+        };
+
+        let fastq_out = create_output(
+            &SortedNeighborhood::output_filename(filebase, "-consensus.fq").to_string_lossy(),
+        )?;
+        let support_out = create_output(
+            &SortedNeighborhood::output_filename(filebase, "-consensus-support.txt")
+                .to_string_lossy(),
+        )?;
+        let mut support_out = BufWriter::new(support_out);
+        writeln!(support_out, "barcode\treads\tlength\tambiguous\tagreement")?;
+
+        Ok(Some(ConsensusWriter {
+            spec: config.consensus_spec,
+            fastq_out: BufWriter::new(fastq_out),
+            support_out: support_out,
+        }))
+    }
+
+    fn finish(self) -> Result<()> {
+        for out in vec![self.fastq_out, self.support_out] {
+            out.into_inner().map_err(|e| e.into_error())?.finish()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, barcode: &str, pileup: &Pileup) -> Result<()> {
+        let consensus = pileup.consensus(&self.spec);
+        writeln!(
+            self.support_out,
+            "{}\t{}\t{}\t{}\t{:0.4}",
+            barcode,
+            consensus.reads,
+            consensus.seq.len(),
+            consensus.ambiguous,
+            consensus.agreement
+        )?;
+        if !consensus.seq.is_empty() {
+            let desc = format!("reads={}", consensus.reads);
+            let record =
+                fastq::Record::with_attrs(barcode, Some(&desc), &consensus.seq, &consensus.qual);
+            write!(self.fastq_out, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_named_record<W: Write>(
+    fastq_writer: &mut fastq::Writer<W>,
+    barcode: &str,
+    recidx: usize,
+    rec: &fastq::Record,
+) -> Result<()> {
+    let name = format!("{}_{}", barcode, recidx + 1);
+    let named_record = fastq::Record::with_attrs(&name, None, rec.seq(), rec.qual());
+    fastq_writer.write_record(&named_record)?;
+    Ok(())
+}
+
+fn write_group_tables<'i, I>(config: &Config, group_iter: I) -> Result<()>
+where
+    I: Iterator<Item = (&'i [u8], usize)> + Clone,
+{
+    if let Some(ref barcode_filename) = config.out_barcodes {
+        let mut barcode_writer = create_output(barcode_filename)?;
+        write_barcode_table(&mut barcode_writer, group_iter.clone())?;
+        barcode_writer.finish()?;
+    }
+
+    if let Some(ref freq_filename) = config.out_barcode_freqs {
+        let mut freq_writer = create_output(freq_filename)?;
+        write_freq_table(&mut freq_writer, group_iter)?;
+        freq_writer.finish()?;
+    }
+
+    Ok(())
+}
+
+fn write_barcode_table<'i, W, I>(barcode_out: W, barcode_iter: I) -> Result<()>
 where
     W: std::io::Write,
-    I: Iterator<Item = &'i (Vec<u8>, Vec<A>)>,
+    I: Iterator<Item = (&'i [u8], usize)>,
 {
     let mut bcout = std::io::BufWriter::new(barcode_out);
 
-    for (barcode, entries) in barcode_iter {
-        bcout.write(barcode)?;
-        bcout.write("\t".as_bytes())?;
-        bcout.write(entries.len().to_string().as_bytes())?;
-        bcout.write("\n".as_bytes())?;
+    for (barcode, count) in barcode_iter {
+        bcout.write_all(barcode)?;
+        bcout.write_all("\t".as_bytes())?;
+        bcout.write_all(count.to_string().as_bytes())?;
+        bcout.write_all("\n".as_bytes())?;
     }
 
     Ok(())
 }
 
-fn write_freq_table<'i, W, I, A: 'i>(freq_out: W, barcode_iter: I) -> Result<()>
+fn write_freq_table<'i, W, I>(freq_out: W, barcode_iter: I) -> Result<()>
 where
     W: std::io::Write,
-    I: Iterator<Item = &'i (Vec<u8>, Vec<A>)>,
+    I: Iterator<Item = (&'i [u8], usize)>,
 {
     let mut fout = std::io::BufWriter::new(freq_out);
 
     let mut freq_counts = HashMap::new();
 
-    for freq in barcode_iter {
-        let freq_count = freq_counts.entry(freq.1.len()).or_insert(0);
+    for (_barcode, count) in barcode_iter {
+        let freq_count = freq_counts.entry(count).or_insert(0);
         *freq_count += 1;
     }
 
@@ -128,3 +422,107 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_temp(contents: &str) -> tempfile::TempPath {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file.into_temp_path()
+    }
+
+    fn run_config(
+        barcodes: &tempfile::TempPath,
+        sequences: &tempfile::TempPath,
+        output_dir: &std::path::Path,
+        name: &str,
+        sort_records: Option<usize>,
+    ) -> (String, String, String, String, String) {
+        let output = |suffix: &str| {
+            output_dir
+                .join(format!("{}{}", name, suffix))
+                .to_string_lossy()
+                .into_owned()
+        };
+        let config = Config {
+            barcode_fastq: barcodes.to_string_lossy().into_owned(),
+            sequ_fastq: Some(sequences.to_string_lossy().into_owned()),
+            id_check: IdCheck::Fail,
+            out_fastq: output("_barcoded.fq"),
+            out_barcodes: Some(output("-barcodes.txt")),
+            out_barcode_freqs: None,
+            neighborhood: Some(output("-nbhd")),
+            nbhd_spec: NeighborhoodSpec::default(),
+            extract: None,
+            fates_report: None,
+            sort_records: sort_records,
+            temp_dir: Some(output_dir.to_string_lossy().into_owned()),
+            consensus: Some(output("-cons")),
+            consensus_spec: ConsensusSpec {
+                min_depth: 2,
+                ..ConsensusSpec::default()
+            },
+        };
+        bc_seqs(config).unwrap();
+
+        let read = |suffix: &str| std::fs::read_to_string(output(suffix)).unwrap();
+        (
+            read("_barcoded.fq"),
+            read("-barcodes.txt"),
+            read("-nbhd-nbhds.txt"),
+            read("-cons-consensus.fq"),
+            read("-cons-consensus-support.txt"),
+        )
+    }
+
+    #[test]
+    fn external_sort_matches() {
+        let barcodes = [
+            "ACGTA", "CCCCC", "ACGTT", "ACGTA", "CCCCC", "GGGGG", "ACGTA",
+        ];
+        let mut barcode_fq = String::new();
+        let mut sequ_fq = String::new();
+        for (i, barcode) in barcodes.iter().enumerate() {
+            barcode_fq += &format!("@r{}\n{}\n+\n{}\n", i, barcode, "I".repeat(barcode.len()));
+            let sequ = ["AAA", "CCC", "GGG", "TTT"][i % 4].repeat(i + 1);
+            sequ_fq += &format!("@r{}\n{}\n+\n{}\n", i, sequ, "I".repeat(sequ.len()));
+        }
+        // Enough reads with a distant barcode to spill several chunks
+        for i in barcodes.len()..(barcodes.len() + 2 * MIN_CHUNK_RECORDS) {
+            barcode_fq += &format!("@r{}\nTTTTT\n+\nIIIII\n", i);
+            sequ_fq += &format!("@r{}\nACGT\n+\nIIII\n", i);
+        }
+        let barcode_path = write_temp(&barcode_fq);
+        let sequ_path = write_temp(&sequ_fq);
+        let output_dir = tempfile::tempdir().unwrap();
+
+        let in_memory = run_config(&barcode_path, &sequ_path, output_dir.path(), "mem", None);
+        let sorted = run_config(
+            &barcode_path,
+            &sequ_path,
+            output_dir.path(),
+            "sort",
+            Some(MIN_CHUNK_RECORDS),
+        );
+
+        assert_eq!(in_memory, sorted);
+        assert_eq!(sorted.1, "ACGTA\t4\nCCCCC\t2\nGGGGG\t1\nTTTTT\t2000\n");
+        assert!(sorted.0.starts_with(
+            "@ACGTA_1\nAAA\n+\nIII\n@ACGTA_2\nTTTTTTTTTTTT\n+\nIIIIIIIIIIII\n\
+             @ACGTA_3\nGGGGGGGGGGGGGGGGGGGGG\n+\nIIIIIIIIIIIIIIIIIIIII\n@ACGTA_4\nGGGGGGGGG\n"
+        ));
+
+        // Ambiguity codes for the mixture of inserts with each barcode
+        assert!(sorted.3.starts_with("@ACGTA reads=4\nDDDKKKKKKKKK\n+\n"));
+        assert_eq!(
+            sorted.4,
+            "barcode\treads\tlength\tambiguous\tagreement\n\
+             ACGTA\t4\t12\t12\t0.5833\n\
+             CCCCC\t2\t6\t6\t0.5000\n\
+             GGGGG\t1\t0\t0\t0.0000\n\
+             TTTTT\t2000\t4\t0\t1.0000\n"
+        );
+    }
+}
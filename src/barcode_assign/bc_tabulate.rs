@@ -1,63 +1,685 @@
-use std::io::Write;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
 
 use failure;
 
+use assignments::*;
+use compress::{create_output, open_input, Output};
 use counts::*;
+use hopping::*;
+use neighborhood::*;
+use normalize::*;
+use sample_sheet::*;
+
+/// Named group of samples for fold-change calculations, parsed from
+/// `NAME=SAMPLE,SAMPLE,...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleGroup {
+    pub name: String,
+    pub samples: Vec<String>,
+}
+
+impl FromStr for SampleGroup {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name_samples = s.splitn(2, '=');
+        let name = name_samples.next().unwrap_or("");
+        let samples: Vec<String> = name_samples
+            .next()
+            .ok_or_else(|| format_err!("Sample group {:?} is not NAME=SAMPLE,...", s))?
+            .split(',')
+            .map(String::from)
+            .collect();
+        if name.is_empty() || samples.iter().any(String::is_empty) {
+            bail!("Sample group {:?} is not NAME=SAMPLE,...", s);
+        }
+        Ok(SampleGroup {
+            name: name.to_string(),
+            samples: samples,
+        })
+    }
+}
+
+/// Comparison between two sample groups, parsed from `NUMER/DENOM`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contrast {
+    pub numer: String,
+    pub denom: String,
+}
+
+impl FromStr for Contrast {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let groups: Vec<&str> = s.split('/').collect();
+        if groups.len() != 2 || groups.iter().any(|g| g.is_empty()) {
+            bail!("Contrast {:?} is not NUMER/DENOM", s);
+        }
+        Ok(Contrast {
+            numer: groups[0].to_string(),
+            denom: groups[1].to_string(),
+        })
+    }
+}
+
+/// Format of the barcode × sample count table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    /// Tab-delimited table with one column per sample.
+    Dense,
+    /// Matrix Market coordinate matrix, `OUTPUT.mtx`, with barcodes
+    /// and samples listed in `OUTPUT-barcodes.txt` and
+    /// `OUTPUT-samples.txt`. An output named `BASE.mtx.gz` or
+    /// `BASE.mtx.zst` is compressed, with lists named from `BASE`.
+    Mtx,
+    /// Tab-delimited barcode<tab>sample<tab>count lines for non-zero
+    /// counts.
+    Long,
+}
+
+impl TableFormat {
+    pub const FORMATS: &'static [&'static str] = &["dense", "mtx", "long"];
+}
+
+impl FromStr for TableFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dense" => Ok(TableFormat::Dense),
+            "mtx" => Ok(TableFormat::Mtx),
+            "long" => Ok(TableFormat::Long),
+            _ => Err(format_err!("Unknown table format {:?}", s)),
+        }
+    }
+}
+
+// Writes count table rows in any `TableFormat`.
+struct TableWriter<'a> {
+    format: TableFormat,
+    out: BufWriter<Output<Box<dyn Write>>>,
+    barcodes_out: BufWriter<Output<Box<dyn Write>>>,
+    sample_names: &'a [&'a str],
+    nrows: usize,
+}
+
+impl<'a> TableWriter<'a> {
+    // Matrix Market output needs the number of rows and of non-zero
+    // entries in advance.
+    fn new(
+        format: TableFormat,
+        output: &str,
+        sample_names: &'a [&'a str],
+        nrows: usize,
+        nnz: usize,
+    ) -> Result<Self, failure::Error> {
+        let create = |filename: &str| -> Result<BufWriter<Output<Box<dyn Write>>>, failure::Error> {
+            Ok(BufWriter::new(create_output(filename)?))
+        };
+
+        let (mut out, barcodes_out) = match format {
+            TableFormat::Dense | TableFormat::Long => {
+                let sink: Box<dyn Write> = Box::new(std::io::sink());
+                (create(output)?, BufWriter::new(Output::Plain(sink)))
+            }
+            TableFormat::Mtx => {
+                let (base, mtx_name) = match SampleCounts::mtx_base(output) {
+                    Some(base) => (base, output.to_string()),
+                    None => (output, format!("{}.mtx", output)),
+                };
+                let mut samples_out = create(&format!("{}-samples.txt", base))?;
+                for sample in sample_names.iter() {
+                    writeln!(samples_out, "{}", sample)?;
+                }
+                finish_output(samples_out)?;
+                (
+                    create(&mtx_name)?,
+                    create(&format!("{}-barcodes.txt", base))?,
+                )
+            }
+        };
+
+        match format {
+            TableFormat::Dense => writeln!(out, "barcode\t{}", sample_names.join("\t"))?,
+            TableFormat::Mtx => {
+                writeln!(out, "%%MatrixMarket matrix coordinate integer general")?;
+                writeln!(out, "{} {} {}", nrows, sample_names.len(), nnz)?;
+            }
+            TableFormat::Long => writeln!(out, "barcode\tsample\tcount")?,
+        }
+
+        Ok(TableWriter {
+            format: format,
+            out: out,
+            barcodes_out: barcodes_out,
+            sample_names: sample_names,
+            nrows: 0,
+        })
+    }
+
+    fn write_row(&mut self, barcode: &[u8], count_vec: &[usize]) -> Result<(), failure::Error> {
+        let barcode = String::from_utf8_lossy(barcode);
+        self.nrows += 1;
+
+        match self.format {
+            TableFormat::Dense => {
+                write!(self.out, "{}", barcode)?;
+                for ct in count_vec.iter() {
+                    write!(self.out, "\t{}", ct)?;
+                }
+                write!(self.out, "\n")?;
+            }
+            TableFormat::Mtx => {
+                writeln!(self.barcodes_out, "{}", barcode)?;
+                for (col, ct) in count_vec.iter().enumerate().filter(|(_, &ct)| ct > 0) {
+                    writeln!(self.out, "{} {} {}", self.nrows, col + 1, ct)?;
+                }
+            }
+            TableFormat::Long => {
+                for (sample, ct) in self.sample_names.iter().zip(count_vec.iter()) {
+                    if *ct > 0 {
+                        writeln!(self.out, "{}\t{}\t{}", barcode, sample, ct)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), failure::Error> {
+        finish_output(self.out)?;
+        finish_output(self.barcodes_out)
+    }
+}
 
 pub struct CLI {
+    /// Count tables to tabulate, or all files in the sample sheet
+    /// when empty
     pub inputs: Vec<String>,
+    /// Sample sheet mapping count tables to sample names and metadata
+    pub sample_sheet: Option<String>,
+    /// Keep only samples whose metadata matches every filter
+    pub select: Vec<SampleFilter>,
+    /// Metadata column defining additional sample groups
+    pub group_by: Option<String>,
+    /// Output filename for the metadata of tabulated samples
+    pub sample_metadata: Option<String>,
+    /// Header of the count column to read from each input table.
+    /// Without a column, a Matrix Market input with several samples
+    /// is read once and contributes every one of its samples.
+    pub column: Option<String>,
     pub output: String,
+    pub format: TableFormat,
     pub mintotal: Option<usize>,
     pub minsamples: Option<usize>,
     pub mininsample: Option<usize>,
     pub omitfile: Option<String>,
+    /// Table mapping barcodes onto neighborhood keys, as written by
+    /// `bc-collapse`, applied to each sample
+    pub key_map: Option<String>,
+    /// Gather neighborhoods over barcodes pooled from all samples and
+    /// merge each sample's counts onto the shared neighborhood keys
+    pub collapse: Option<NeighborhoodSpec>,
+    /// Base name for neighborhood tables from collapsing
+    pub collapse_nbhds: Option<String>,
+    pub norm_method: NormMethod,
+    /// Output filename for normalized counts
+    pub normalized: Option<String>,
+    pub groups: Vec<SampleGroup>,
+    pub contrasts: Vec<Contrast>,
+    /// Pseudocount added to mean normalized counts for fold changes
+    pub pseudocount: f64,
+    /// Output filename for log2 fold changes of each contrast
+    pub fold_changes: Option<String>,
+    /// Barcode assignment table for rolling up counts by target
+    pub assignments: Option<String>,
+    /// Field of the assignment table holding the target
+    pub target_field: usize,
+    /// Base name for target count tables and unassigned barcodes
+    pub targets: Option<String>,
+    /// Flag counts below this fraction of the same barcode's largest
+    /// count in another sample as index hopping or contamination
+    pub hop_fraction: Option<f64>,
+    /// Output filename for estimated hopping rates between samples
+    pub hop_report: Option<String>,
+    /// Output filename for flagged barcode counts
+    pub hop_flagged: Option<String>,
+    /// Set flagged counts to zero before writing count tables
+    pub hop_zero: bool,
 }
 
 impl CLI {
     pub fn run(&self) -> Result<(), failure::Error> {
+        let sheet = match self.sample_sheet {
+            Some(ref filename) => Some(SampleSheet::from_file(filename)?),
+            None => None,
+        };
+        let samples = self.input_samples(sheet.as_ref())?;
+
+        if let (Some(sheet), Some(metadata)) = (&sheet, &self.sample_metadata) {
+            let mut metadata_out = create_output(metadata)?;
+            sheet.write_metadata(&mut metadata_out, samples.iter())?;
+            metadata_out.finish()?;
+        }
+
         let mut counts = Vec::new();
 
-        for input in self.inputs.iter() {
-            let input_counts = SampleCounts::from_file(&input)?;
-            counts.push((input.to_string(), input_counts));
+        for sample in samples.iter() {
+            if self.column.is_none() && SampleCounts::is_mtx_file(&sample.file) {
+                let mut columns = SampleCounts::from_mtx_file(&sample.file)
+                    .map_err(|e| format_err!("Reading file {:?}: {}", sample.file, e))?;
+                if columns.len() == 1 {
+                    let (_name, input_counts) = columns.remove(0);
+                    counts.push((sample.name.to_string(), input_counts));
+                } else {
+                    counts.extend(columns);
+                }
+            } else {
+                let input_counts =
+                    SampleCounts::from_file_column(&sample.file, self.column.as_deref())?;
+                counts.push((sample.name.to_string(), input_counts));
+            }
+        }
+
+        if let Some(ref key_map) = self.key_map {
+            let keys = read_key_map(open_input(key_map)?)
+                .map_err(|e| format_err!("Reading key map {:?}: {}", key_map, e))?;
+            counts = counts
+                .into_iter()
+                .map(|(name, sample_counts)| (name, sample_counts.collapse(&keys)))
+                .collect();
+        }
+
+        if let Some(ref spec) = self.collapse {
+            counts = self.collapse_samples(counts, spec)?;
         }
 
         let total_counts: SampleCounts = counts.iter().map(|(_input, counts)| counts).sum();
         let mut barcodes: Vec<(Vec<u8>, usize)> = total_counts.into_iter().collect();
         barcodes.sort_by_key(|(_barcode, counts)| -(*counts as isize));
 
-        let mut out = std::fs::File::create(&self.output)?;
-        write!(out, "barcode")?;
-        for (input, _input_counts) in counts.iter() {
-            write!(out, "\t{}", input)?;
+        let mut omit_out = Self::optional_output(&self.omitfile)?;
+
+        let sample_names: Vec<&str> = counts.iter().map(|(input, _)| input.as_str()).collect();
+
+        let mut count_vecs: Vec<Vec<usize>> = barcodes
+            .iter()
+            .map(|(barcode, _counts)| {
+                SampleCounts::barcode_count_vec(
+                    counts.iter().map(|(_input, counts)| counts),
+                    barcode,
+                )
+            })
+            .collect();
+
+        if let Some(max_fraction) = self.hop_fraction {
+            let filter = HopFilter {
+                max_fraction: max_fraction,
+            };
+            self.flag_hopping(&filter, &barcodes, &mut count_vecs, &sample_names)?;
         }
-        write!(out, "\n")?;
 
-        let mut omit_out: Box<dyn Write> = match &self.omitfile {
-            Some(f) => Box::new(std::fs::File::create(f)?),
-            None => Box::new(std::io::sink()),
+        let mut norm_out = Self::optional_output(&self.normalized)?;
+        let mut fc_out = Self::optional_output(&self.fold_changes)?;
+        let size_factors = if self.normalized.is_some() || self.fold_changes.is_some() {
+            self.norm_method
+                .size_factors(count_vecs.iter().map(Vec::as_slice), counts.len())?
+        } else {
+            vec![1.0; counts.len()]
+        };
+        let contrasts = self.contrast_samples(&self.sample_groups(&samples)?, &sample_names)?;
+
+        let (nrows, nnz) = count_vecs
+            .iter()
+            .filter(|count_vec| !self.is_omitted(count_vec))
+            .fold((0, 0), |(nrows, nnz), count_vec| {
+                (
+                    nrows + 1,
+                    nnz + count_vec.iter().filter(|&&ct| ct > 0).count(),
+                )
+            });
+        let mut out = TableWriter::new(self.format, &self.output, &sample_names, nrows, nnz)?;
+
+        let assignments = match self.assignments {
+            Some(ref filename) => Some(Assignments::from_file(filename, self.target_field)?),
+            None => None,
         };
 
-        for (barcode, _counts) in barcodes.iter() {
-            let count_vec = SampleCounts::barcode_count_vec(
-                counts.iter().map(|(_input, counts)| counts),
-                barcode,
-            );
+        write!(norm_out, "barcode")?;
+        for input in sample_names.iter() {
+            write!(norm_out, "\t{}", input)?;
+        }
+        write!(norm_out, "\n")?;
+
+        write!(fc_out, "barcode")?;
+        for contrast in self.contrasts.iter() {
+            write!(fc_out, "\t{}/{}", contrast.numer, contrast.denom)?;
+        }
+        write!(fc_out, "\n")?;
 
+        for ((barcode, _counts), count_vec) in barcodes.iter().zip(count_vecs.iter()) {
             if self.is_omitted(&count_vec) {
                 write!(omit_out, "{}\n", String::from_utf8_lossy(barcode))?;
             } else {
-                write!(out, "{}", String::from_utf8_lossy(barcode))?;
-                for ct in count_vec.iter() {
-                    write!(out, "\t{}", ct)?;
+                out.write_row(barcode, count_vec)?;
+
+                let norm_vec: Vec<f64> = count_vec
+                    .iter()
+                    .zip(size_factors.iter())
+                    .map(|(&ct, sf)| (ct as f64) / sf)
+                    .collect();
+
+                write!(norm_out, "{}", String::from_utf8_lossy(barcode))?;
+                for norm in norm_vec.iter() {
+                    write!(norm_out, "\t{:0.3}", norm)?;
+                }
+                write!(norm_out, "\n")?;
+
+                write!(fc_out, "{}", String::from_utf8_lossy(barcode))?;
+                for (numer, denom) in contrasts.iter() {
+                    let numer_norm: Vec<f64> = numer.iter().map(|&i| norm_vec[i]).collect();
+                    let denom_norm: Vec<f64> = denom.iter().map(|&i| norm_vec[i]).collect();
+                    write!(
+                        fc_out,
+                        "\t{:0.3}",
+                        log2_fold_change(&numer_norm, &denom_norm, self.pseudocount)
+                    )?;
                 }
+                write!(fc_out, "\n")?;
+            }
+        }
+
+        out.finish()?;
+        finish_output(omit_out)?;
+        finish_output(norm_out)?;
+        finish_output(fc_out)?;
+
+        if let (Some(assignments), Some(target_base)) = (assignments, &self.targets) {
+            let reported = barcodes
+                .iter()
+                .zip(count_vecs.iter())
+                .filter(|(_, count_vec)| !self.is_omitted(count_vec))
+                .map(|((barcode, _counts), count_vec)| (barcode.as_slice(), count_vec.as_slice()));
+            Self::write_targets(&assignments, target_base, &sample_names, reported)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flags barcode counts that likely arise from index hopping or
+    /// cross-sample contamination, writes the flagged counts and the
+    /// estimated hopping rate between each pair of samples, and sets
+    /// flagged counts to zero when requested.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` decides which counts are flagged
+    /// * `barcodes` are the barcodes, in the same order as `count_vecs`
+    /// * `count_vecs` are the counts for each barcode across all samples
+    /// * `sample_names` are the names of the samples
+    pub fn flag_hopping(
+        &self,
+        filter: &HopFilter,
+        barcodes: &[(Vec<u8>, usize)],
+        count_vecs: &mut [Vec<usize>],
+        sample_names: &[&str],
+    ) -> Result<(), failure::Error> {
+        let mut flagged_out = Self::optional_output(&self.hop_flagged)?;
+        writeln!(flagged_out, "barcode\tsample\tcount\tsource\tsource_count")?;
+
+        let mut stats = HopStats::new(sample_names.len());
+        for ((barcode, _counts), count_vec) in barcodes.iter().zip(count_vecs.iter_mut()) {
+            let (flagged, source) = filter.flag(count_vec);
+            stats.add(count_vec, &flagged, source);
+
+            for &dest in flagged.iter() {
+                writeln!(
+                    flagged_out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    String::from_utf8_lossy(barcode),
+                    sample_names[dest],
+                    count_vec[dest],
+                    sample_names[source],
+                    count_vec[source]
+                )?;
+                if self.hop_zero {
+                    count_vec[dest] = 0;
+                }
+            }
+        }
+
+        finish_output(flagged_out)?;
+
+        if let Some(ref report) = self.hop_report {
+            let mut report_out = create_output(report)?;
+            stats.write(&mut report_out, sample_names)?;
+            report_out.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes target × sample tables of the total count, the median
+    /// count, and the number of barcodes detected for the barcodes
+    /// assigned to each target, along with a table of reported
+    /// barcodes with no assignment.
+    ///
+    /// Assigned barcodes that are not reported count as 0 for the
+    /// median.
+    ///
+    /// # Arguments
+    ///
+    /// * `assignments` assigns barcodes to targets
+    /// * `target_base` is the base name for the output files
+    /// * `sample_names` are the names of the samples
+    /// * `reported` is an iterator over reported barcodes and their counts
+    pub fn write_targets<'a, I>(
+        assignments: &Assignments,
+        target_base: &str,
+        sample_names: &[&str],
+        reported: I,
+    ) -> Result<(), failure::Error>
+    where
+        I: Iterator<Item = (&'a [u8], &'a [usize])>,
+    {
+        let mut unassigned_out =
+            BufWriter::new(create_output(&format!("{}-unassigned.txt", target_base))?);
+        writeln!(unassigned_out, "barcode\t{}", sample_names.join("\t"))?;
+
+        let mut target_counts: HashMap<&str, Vec<&[usize]>> = HashMap::new();
+        for (barcode, count_vec) in reported {
+            match assignments.target(barcode) {
+                Some(target) => target_counts.entry(target).or_default().push(count_vec),
+                None => {
+                    write!(unassigned_out, "{}", String::from_utf8_lossy(barcode))?;
+                    for ct in count_vec.iter() {
+                        write!(unassigned_out, "\t{}", ct)?;
+                    }
+                    write!(unassigned_out, "\n")?;
+                }
+            }
+        }
+
+        let mut target_sizes: Vec<(&str, usize)> = assignments.target_sizes().into_iter().collect();
+        target_sizes.sort();
+
+        let mut outs = Vec::new();
+        for stat in ["sum", "median", "detected"].iter() {
+            let mut out = BufWriter::new(create_output(&format!(
+                "{}-target-{}.txt",
+                target_base, stat
+            ))?);
+            writeln!(out, "target\tbarcodes\t{}", sample_names.join("\t"))?;
+            outs.push(out);
+        }
+
+        let no_counts = Vec::new();
+        for (target, size) in target_sizes {
+            let count_vecs = target_counts.get(target).unwrap_or(&no_counts);
+            for out in outs.iter_mut() {
+                write!(out, "{}\t{}", target, size)?;
+            }
+
+            for sample in 0..sample_names.len() {
+                let mut counts: Vec<usize> = count_vecs.iter().map(|cts| cts[sample]).collect();
+                counts.resize(size, 0);
+                counts.sort();
+
+                write!(outs[0], "\t{}", counts.iter().sum::<usize>())?;
+                write!(outs[1], "\t{}", median_count(&counts))?;
+                write!(outs[2], "\t{}", counts.iter().filter(|&&ct| ct > 0).count())?;
+            }
+
+            for out in outs.iter_mut() {
                 write!(out, "\n")?;
             }
         }
 
+        finish_output(unassigned_out)?;
+        for out in outs {
+            finish_output(out)?;
+        }
+
         Ok(())
     }
 
+    /// Gathers neighborhoods over the total counts of barcodes pooled
+    /// from all samples, and merges the counts in each sample onto the
+    /// key barcode of each neighborhood, so that every sample uses the
+    /// same keys.
+    pub fn collapse_samples(
+        &self,
+        counts: Vec<(String, SampleCounts)>,
+        spec: &NeighborhoodSpec,
+    ) -> Result<Vec<(String, SampleCounts)>, failure::Error> {
+        let total_counts: SampleCounts = counts.iter().map(|(_input, counts)| counts).sum();
+        let nbhds: Vec<_> = Neighborhood::gather(total_counts.key_map(), spec)
+            .into_iter()
+            .map(|n| n.into_sorted())
+            .collect();
+
+        if let Some(ref nbhd_base) = self.collapse_nbhds {
+            SortedNeighborhood::write_tables(nbhd_base, nbhds.iter(), spec)?;
+        }
+
+        let keys = SortedNeighborhood::key_map(nbhds.iter());
+
+        Ok(counts
+            .into_iter()
+            .map(|(input, input_counts)| (input, input_counts.collapse(&keys)))
+            .collect())
+    }
+
+    fn optional_output(
+        filename: &Option<String>,
+    ) -> Result<BufWriter<Output<Box<dyn Write>>>, failure::Error> {
+        Ok(BufWriter::new(match filename {
+            Some(f) => create_output(f)?,
+            None => Output::Plain(Box::new(std::io::sink())),
+        }))
+    }
+
+    /// Returns the samples to tabulate, named and filtered according
+    /// to the sample sheet when one is given.
+    ///
+    /// Without a sample sheet, each input is named by its filename.
+    /// With a sample sheet, each input must be listed in the sheet, and
+    /// all samples in the sheet are used when there are no inputs.
+    pub fn input_samples(
+        &self,
+        sheet: Option<&SampleSheet>,
+    ) -> Result<Vec<SampleInfo>, failure::Error> {
+        let samples: Vec<SampleInfo> = match sheet {
+            Some(sheet) if self.inputs.is_empty() => sheet.samples().to_vec(),
+            Some(sheet) => self
+                .inputs
+                .iter()
+                .map(|input| {
+                    sheet
+                        .by_file(input)
+                        .cloned()
+                        .ok_or_else(|| format_err!("Input {:?} not in sample sheet", input))
+                })
+                .collect::<Result<_, failure::Error>>()?,
+            None => self
+                .inputs
+                .iter()
+                .map(|input| SampleInfo {
+                    name: input.to_string(),
+                    file: input.to_string(),
+                    metadata: HashMap::new(),
+                })
+                .collect(),
+        };
+
+        let samples: Vec<SampleInfo> = samples
+            .into_iter()
+            .filter(|sample| self.select.iter().all(|filter| filter.matches(sample)))
+            .collect();
+        if samples.is_empty() {
+            bail!("No samples to tabulate");
+        }
+        Ok(samples)
+    }
+
+    // Sample groups given explicitly, followed by groups from the
+    // group-by metadata column.
+    fn sample_groups(&self, samples: &[SampleInfo]) -> Result<Vec<SampleGroup>, failure::Error> {
+        let mut groups = self.groups.clone();
+        if let Some(ref column) = self.group_by {
+            for (name, group_samples) in SampleSheet::group_by(samples.iter(), column)? {
+                if groups.iter().any(|group| group.name == name) {
+                    bail!("Duplicate sample group {:?}", name);
+                }
+                groups.push(SampleGroup {
+                    name: name,
+                    samples: group_samples,
+                });
+            }
+        }
+        Ok(groups)
+    }
+
+    // Resolves the groups in each contrast into the indices of their
+    // samples.
+    fn contrast_samples(
+        &self,
+        groups: &[SampleGroup],
+        sample_names: &[&str],
+    ) -> Result<Vec<(Vec<usize>, Vec<usize>)>, failure::Error> {
+        let group_samples = |name: &str| -> Result<Vec<usize>, failure::Error> {
+            let group = groups
+                .iter()
+                .find(|group| group.name == name)
+                .ok_or_else(|| format_err!("No sample group named {:?}", name))?;
+            group
+                .samples
+                .iter()
+                .map(|sample| {
+                    sample_names
+                        .iter()
+                        .position(|input| input == sample)
+                        .ok_or_else(|| {
+                            format_err!("No input sample {:?} in group {:?}", sample, name)
+                        })
+                })
+                .collect()
+        };
+
+        self.contrasts
+            .iter()
+            .map(|contrast| {
+                Ok((
+                    group_samples(&contrast.numer)?,
+                    group_samples(&contrast.denom)?,
+                ))
+            })
+            .collect()
+    }
+
     pub fn is_omitted(&self, count_vec: &Vec<usize>) -> bool {
         if let Some(mintotal) = self.mintotal {
             let total: usize = count_vec.iter().copied().sum();
@@ -83,6 +705,24 @@ impl CLI {
     }
 }
 
+// Median of sorted counts.
+fn median_count(sorted: &[usize]) -> f64 {
+    let n = sorted.len();
+    if n == 0 {
+        0.0
+    } else if n % 2 == 1 {
+        sorted[n / 2] as f64
+    } else {
+        ((sorted[n / 2 - 1] + sorted[n / 2]) as f64) / 2.0
+    }
+}
+
+/// Flushes a buffered output and finishes any compression.
+fn finish_output(out: BufWriter<Output<Box<dyn Write>>>) -> Result<(), failure::Error> {
+    out.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     extern crate rand;
@@ -152,11 +792,33 @@ mod tests {
 
         let cli = CLI {
             inputs: vec![count_path.to_string_lossy().into_owned()],
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
             output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
             mintotal: None,
             minsamples: None,
             mininsample: None,
             omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
         };
 
         cli.run().unwrap();
@@ -211,11 +873,33 @@ mod tests {
                 count_a_path.to_string_lossy().into_owned(),
                 count_b_path.to_string_lossy().into_owned(),
             ],
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
             output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
             mintotal: None,
             minsamples: None,
             mininsample: None,
             omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
         };
 
         cli.run().unwrap();
@@ -297,11 +981,33 @@ mod tests {
                 .iter()
                 .map(|p| p.to_string_lossy().into_owned())
                 .collect(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
             output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
             mintotal: None,
             minsamples: None,
             mininsample: None,
             omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
         };
 
         cli.run().unwrap();
@@ -334,4 +1040,604 @@ mod tests {
             assert_eq!(exp_line, act_line);
         }
     }
+
+    #[test]
+    fn normalized_fold_changes() {
+        let tables = vec![
+            "AAAAAAAA\t10\nCCCCCCCC\t90\n",
+            "AAAAAAAA\t30\nCCCCCCCC\t170\n",
+            "AAAAAAAA\t40\nCCCCCCCC\t60\n",
+        ];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+        let inputs: Vec<String> = count_paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+
+        let table_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let norm_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let fc_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let cli = CLI {
+            inputs: inputs.clone(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: Some(norm_path.to_string_lossy().into_owned()),
+            groups: vec![
+                format!("ctrl={},{}", inputs[0], inputs[1]).parse().unwrap(),
+                format!("expt={}", inputs[2]).parse().unwrap(),
+            ],
+            contrasts: vec!["expt/ctrl".parse().unwrap()],
+            pseudocount: 0.0,
+            fold_changes: Some(fc_path.to_string_lossy().into_owned()),
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+
+        cli.run().unwrap();
+
+        let norm_table = String::from_utf8(std::fs::read(norm_path).unwrap()).unwrap();
+        assert_eq!(
+            norm_table,
+            format!(
+                "barcode\t{}\t{}\t{}\nCCCCCCCC\t900000.000\t850000.000\t600000.000\nAAAAAAAA\t100000.000\t150000.000\t400000.000\n",
+                inputs[0], inputs[1], inputs[2]
+            )
+        );
+
+        // AAAAAAAA is 400000 in expt and 125000 in ctrl
+        let fc_table = String::from_utf8(std::fs::read(fc_path).unwrap()).unwrap();
+        assert_eq!(
+            fc_table,
+            "barcode\texpt/ctrl\nCCCCCCCC\t-0.544\nAAAAAAAA\t1.678\n"
+        );
+    }
+
+    #[test]
+    fn parse_groups() {
+        let group: SampleGroup = "ctrl=a.txt,b.txt".parse().unwrap();
+        assert_eq!(group.name, "ctrl");
+        assert_eq!(
+            group.samples,
+            vec!["a.txt".to_string(), "b.txt".to_string()]
+        );
+        assert!("ctrl".parse::<SampleGroup>().is_err());
+        assert!("ctrl=a.txt,".parse::<SampleGroup>().is_err());
+
+        let contrast: Contrast = "expt/ctrl".parse().unwrap();
+        assert_eq!(contrast.numer, "expt");
+        assert_eq!(contrast.denom, "ctrl");
+        assert!("expt".parse::<Contrast>().is_err());
+        assert!("a/b/c".parse::<Contrast>().is_err());
+    }
+
+    #[test]
+    fn target_rollup() {
+        let tables = vec![
+            "AAAAAAAA\t10\nCCCCCCCC\t5\nGGGGGGGG\t7\nTTTTTTTT\t1\n",
+            "AAAAAAAA\t4\nGGGGGGGG\t9\nACACACAC\t2\n",
+        ];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+
+        let mut assign_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut assign_file,
+            b"barcode\tguide\nAAAAAAAA\tsgA\nCCCCCCCC\tsgA\nGGGGGGGG\tsgB\nCGCGCGCG\tsgA\nTATATATA\tsgC\n",
+        )
+        .unwrap();
+        let assign_path = assign_file.into_temp_path();
+
+        let table_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_base = target_dir.path().join("screen");
+
+        let cli = CLI {
+            inputs: count_paths
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: Some(2),
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: Some(assign_path.to_string_lossy().into_owned()),
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: Some(target_base.to_string_lossy().into_owned()),
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+
+        cli.run().unwrap();
+
+        let read_table = |name: &str| {
+            let filename = format!("{}-{}.txt", target_base.to_string_lossy(), name);
+            let table = String::from_utf8(std::fs::read(filename).unwrap()).unwrap();
+            table
+                .lines()
+                .skip(1)
+                .map(|line| line.splitn(2, '\t').nth(1).unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        // sgA has AAAAAAAA, CCCCCCCC, and CGCGCGCG, which is never seen
+        assert_eq!(
+            read_table("target-sum"),
+            vec!["3\t15\t4", "1\t7\t9", "1\t0\t0"]
+        );
+        assert_eq!(
+            read_table("target-median"),
+            vec!["3\t5\t0", "1\t7\t9", "1\t0\t0"]
+        );
+        assert_eq!(
+            read_table("target-detected"),
+            vec!["3\t2\t1", "1\t1\t1", "1\t0\t0"]
+        );
+
+        // TTTTTTTT is below the minimum total
+        assert_eq!(read_table("unassigned"), vec!["0\t2"]);
+    }
+
+    #[test]
+    fn sparse_formats() {
+        let tables = vec!["AAAAAAAA\t10\nCCCCCCCC\t5\n", "AAAAAAAA\t4\nGGGGGGGG\t9\n"];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+        let inputs: Vec<String> = count_paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let out_dir = tempfile::tempdir().unwrap();
+
+        for &format in [TableFormat::Mtx, TableFormat::Long].iter() {
+            let output = out_dir.path().join(format!("{:?}", format));
+            let cli = CLI {
+                inputs: inputs.clone(),
+                sample_sheet: None,
+                select: Vec::new(),
+                group_by: None,
+                sample_metadata: None,
+                column: None,
+                output: output.to_string_lossy().into_owned(),
+                format: format,
+                mintotal: None,
+                minsamples: None,
+                mininsample: None,
+                omitfile: None,
+                key_map: None,
+                collapse: None,
+                collapse_nbhds: None,
+                norm_method: NormMethod::Cpm,
+                normalized: None,
+                groups: Vec::new(),
+                contrasts: Vec::new(),
+                pseudocount: 1.0,
+                fold_changes: None,
+                assignments: None,
+                target_field: Assignments::DEFAULT_TARGET_FIELD,
+                targets: None,
+                hop_fraction: None,
+                hop_report: None,
+                hop_flagged: None,
+                hop_zero: false,
+            };
+            cli.run().unwrap();
+        }
+
+        let mtx_base = out_dir.path().join("Mtx").to_string_lossy().into_owned();
+        assert_eq!(
+            String::from_utf8(std::fs::read(format!("{}.mtx", mtx_base)).unwrap()).unwrap(),
+            "%%MatrixMarket matrix coordinate integer general\n3 2 4\n1 1 10\n1 2 4\n2 2 9\n3 1 5\n"
+        );
+        assert_eq!(
+            String::from_utf8(std::fs::read(format!("{}-barcodes.txt", mtx_base)).unwrap())
+                .unwrap(),
+            "AAAAAAAA\nGGGGGGGG\nCCCCCCCC\n"
+        );
+        assert_eq!(
+            String::from_utf8(std::fs::read(format!("{}-samples.txt", mtx_base)).unwrap()).unwrap(),
+            format!("{}\n{}\n", inputs[0], inputs[1])
+        );
+
+        let long_path = out_dir.path().join("Long");
+        assert_eq!(
+            String::from_utf8(std::fs::read(&long_path).unwrap()).unwrap(),
+            format!(
+                "barcode\tsample\tcount\nAAAAAAAA\t{0}\t10\nAAAAAAAA\t{1}\t4\nGGGGGGGG\t{1}\t9\nCCCCCCCC\t{0}\t5\n",
+                inputs[0], inputs[1]
+            )
+        );
+
+        let gz_path = out_dir.path().join("counts.mtx.gz");
+        let cli = CLI {
+            inputs: inputs.clone(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: gz_path.to_string_lossy().into_owned(),
+            format: TableFormat::Mtx,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+        cli.run().unwrap();
+        assert!(std::fs::metadata(out_dir.path().join("counts-barcodes.txt")).is_ok());
+
+        // Every sample in a matrix becomes a column in the dense table
+        let dense_path = out_dir.path().join("dense.txt");
+        let cli = CLI {
+            inputs: vec![gz_path.to_string_lossy().into_owned()],
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: dense_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+        cli.run().unwrap();
+        assert_eq!(
+            String::from_utf8(std::fs::read(&dense_path).unwrap()).unwrap(),
+            format!(
+                "barcode\t{}\t{}\nAAAAAAAA\t10\t4\nGGGGGGGG\t0\t9\nCCCCCCCC\t5\t0\n",
+                inputs[0], inputs[1]
+            )
+        );
+
+        let mtx_columns = SampleCounts::from_mtx_file(format!("{}.mtx", mtx_base)).unwrap();
+        assert_eq!(mtx_columns.len(), 2);
+        for (input, table) in inputs.iter().zip(tables.iter()) {
+            let expected = SampleCounts::read(table.as_bytes()).unwrap().count_map();
+            let from_mtx = SampleCounts::mtx_column(&mtx_columns, Some(input)).unwrap();
+            assert_eq!(from_mtx.count_map(), expected);
+            let from_long = SampleCounts::from_file_column(&long_path, Some(input)).unwrap();
+            assert_eq!(from_long.count_map(), expected);
+        }
+    }
+
+    #[test]
+    fn collapse_nbhds() {
+        // Each sample alone would choose a different key barcode
+        let tables = vec![
+            "ACGTACGT\t10\nACGTACGA\t4\nCATGCATG\t3\n",
+            "ACGTACGT\t2\nACGTACGA\t7\n",
+        ];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+        let inputs: Vec<String> = count_paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let table_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let cli = CLI {
+            inputs: inputs.clone(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: Some(NeighborhoodSpec::default()),
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+
+        cli.run().unwrap();
+
+        let out_table = String::from_utf8(std::fs::read(table_path).unwrap()).unwrap();
+        assert_eq!(
+            out_table,
+            format!(
+                "barcode\t{}\t{}\nACGTACGT\t14\t9\nCATGCATG\t3\t0\n",
+                inputs[0], inputs[1]
+            )
+        );
+    }
+
+    #[test]
+    fn index_hopping() {
+        let tables = vec![
+            "AAAAAAAA\t1000\nCCCCCCCC\t2\nGGGGGGGG\t50\n",
+            "AAAAAAAA\t5\nCCCCCCCC\t800\nGGGGGGGG\t40\n",
+        ];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+        let inputs: Vec<String> = count_paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        let table_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let report_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let flagged_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let cli = CLI {
+            inputs: inputs.clone(),
+            sample_sheet: None,
+            select: Vec::new(),
+            group_by: None,
+            sample_metadata: None,
+            column: None,
+            output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: Vec::new(),
+            pseudocount: 1.0,
+            fold_changes: None,
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: Some(0.01),
+            hop_report: Some(report_path.to_string_lossy().into_owned()),
+            hop_flagged: Some(flagged_path.to_string_lossy().into_owned()),
+            hop_zero: true,
+        };
+
+        cli.run().unwrap();
+
+        let out_table = String::from_utf8(std::fs::read(table_path).unwrap()).unwrap();
+        assert_eq!(
+            out_table,
+            format!(
+                "barcode\t{}\t{}\nAAAAAAAA\t1000\t0\nCCCCCCCC\t0\t800\nGGGGGGGG\t50\t40\n",
+                inputs[0], inputs[1]
+            )
+        );
+
+        let flagged = String::from_utf8(std::fs::read(flagged_path).unwrap()).unwrap();
+        assert_eq!(
+            flagged,
+            format!(
+                "barcode\tsample\tcount\tsource\tsource_count\n\
+                 AAAAAAAA\t{1}\t5\t{0}\t1000\n\
+                 CCCCCCCC\t{0}\t2\t{1}\t800\n",
+                inputs[0], inputs[1]
+            )
+        );
+
+        let report = String::from_utf8(std::fs::read(report_path).unwrap()).unwrap();
+        assert_eq!(
+            report,
+            format!(
+                "source\tdest\tsource_reads\thopped_barcodes\thopped_reads\trate\n\
+                 {0}\t{1}\t1050\t1\t5\t0.004762\n\
+                 {1}\t{0}\t800\t1\t2\t0.002500\n",
+                inputs[0], inputs[1]
+            )
+        );
+    }
+
+    #[test]
+    fn sample_sheet() {
+        let tables = vec![
+            "AAAAAAAA\t10\nCCCCCCCC\t90\n",
+            "AAAAAAAA\t30\nCCCCCCCC\t170\n",
+            "AAAAAAAA\t40\nCCCCCCCC\t60\n",
+            "GGGGGGGG\t5\n",
+        ];
+        let count_paths: Vec<_> = tables
+            .iter()
+            .map(|table| {
+                let mut count_file = tempfile::NamedTempFile::new().unwrap();
+                std::io::Write::write_all(&mut count_file, table.as_bytes()).unwrap();
+                count_file.into_temp_path()
+            })
+            .collect();
+        let inputs: Vec<String> = count_paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+
+        let mut sheet_file = tempfile::NamedTempFile::new().unwrap();
+        let sheet = format!(
+            "sample\tfile\tcondition\treplicate\n\
+             S1\t{}\tctrl\t1\n\
+             S2\t{}\tctrl\t2\n\
+             S3\t{}\tdrug\t1\n\
+             S4\t{}\tblank\t1\n",
+            inputs[0], inputs[1], inputs[2], inputs[3]
+        );
+        std::io::Write::write_all(&mut sheet_file, sheet.as_bytes()).unwrap();
+        let sheet_path = sheet_file.into_temp_path();
+
+        let table_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let fc_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let metadata_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let cli = CLI {
+            inputs: Vec::new(),
+            sample_sheet: Some(sheet_path.to_string_lossy().into_owned()),
+            select: vec!["condition=ctrl,drug".parse().unwrap()],
+            group_by: Some("condition".to_string()),
+            sample_metadata: Some(metadata_path.to_string_lossy().into_owned()),
+            column: None,
+            output: table_path.to_string_lossy().into_owned(),
+            format: TableFormat::Dense,
+            mintotal: None,
+            minsamples: None,
+            mininsample: None,
+            omitfile: None,
+            key_map: None,
+            collapse: None,
+            collapse_nbhds: None,
+            norm_method: NormMethod::Cpm,
+            normalized: None,
+            groups: Vec::new(),
+            contrasts: vec!["drug/ctrl".parse().unwrap()],
+            pseudocount: 0.0,
+            fold_changes: Some(fc_path.to_string_lossy().into_owned()),
+            assignments: None,
+            target_field: Assignments::DEFAULT_TARGET_FIELD,
+            targets: None,
+            hop_fraction: None,
+            hop_report: None,
+            hop_flagged: None,
+            hop_zero: false,
+        };
+
+        cli.run().unwrap();
+
+        let out_table = String::from_utf8(std::fs::read(table_path).unwrap()).unwrap();
+        assert_eq!(
+            out_table,
+            "barcode\tS1\tS2\tS3\nCCCCCCCC\t90\t170\t60\nAAAAAAAA\t10\t30\t40\n"
+        );
+
+        let fc_table = String::from_utf8(std::fs::read(fc_path).unwrap()).unwrap();
+        assert_eq!(
+            fc_table,
+            "barcode\tdrug/ctrl\nCCCCCCCC\t-0.544\nAAAAAAAA\t1.678\n"
+        );
+
+        let metadata = String::from_utf8(std::fs::read(metadata_path).unwrap()).unwrap();
+        assert_eq!(
+            metadata,
+            format!(
+                "sample\tfile\tcondition\treplicate\n\
+                 S1\t{}\tctrl\t1\n\
+                 S2\t{}\tctrl\t2\n\
+                 S3\t{}\tdrug\t1\n",
+                inputs[0], inputs[1], inputs[2]
+            )
+        );
+
+        let unlisted = CLI {
+            inputs: vec!["/not/in/sheet.txt".to_string()],
+            ..cli
+        };
+        assert!(unlisted.run().is_err());
+    }
 }
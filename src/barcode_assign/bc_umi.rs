@@ -1,91 +1,343 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{BufWriter, Write};
 
 use anyhow::{anyhow, Context, Result};
 use bio::io::fastq;
-//use rayon::prelude::*;
+use rayon::prelude::*;
 
+use barcode_extract::*;
+use barcode_key::BarcodeKey;
+use compress::*;
+use fastq_chunks::*;
+use fastq_pair::{IdCheck, PairRecords};
 use neighborhood::*;
+use read_filter::*;
+use saturation::*;
+use umi_source::*;
 
 #[derive(Debug)]
 pub struct Config {
     pub barcode_fastq: String,
-    pub umi_prefix: String,
+    /// Read the barcode input as BAM, as for a file named `.bam`,
+    /// which allows BAM on standard input
+    pub bam: bool,
+    pub umi_source: UmiSource,
+    /// Second FastQ file holding the UMI read, in the same order as
+    /// the barcode reads
+    pub umi_fastq: Option<String>,
+    /// Handling of barcode and UMI reads whose IDs do not agree
+    pub id_check: IdCheck,
     pub out_barcodes: String,
     pub dedup_stats: Option<String>,
+    /// Base name for saturation curve and library complexity tables
+    pub saturation: Option<String>,
+    /// Base name for UMI chimera reports, which enables reassigning
+    /// UMIs shared across barcodes to their dominant barcode
+    pub chimeras: Option<String>,
+    /// Minimum ratio of reads for the dominant barcode of a shared UMI
+    /// to the next barcode, for the UMI to be reassigned
+    pub chimera_ratio: f64,
+    /// Deduplicated FastQ output, with one representative read for
+    /// each molecule
+    pub dedup_fastq: Option<String>,
     pub neighborhood: Option<String>,
+    pub nbhd_spec: NeighborhoodSpec,
+    /// Cluster UMIs within each barcode to correct UMI sequencing errors
+    pub umi_spec: Option<NeighborhoodSpec>,
+    pub read_filter: ReadFilter,
+    pub filter_report: Option<String>,
+    pub threads: usize,
+    pub extract: Option<ExtractSpec>,
+    pub fates_report: Option<String>,
 }
 
-pub fn bc_umi(config: Config) -> Result<()> {
-    let reader: Box<dyn Read> = if config.barcode_fastq == "-" {
-        Box::new(io::stdin())
-    } else {
-        let file = File::open(&config.barcode_fastq)
-            .with_context(|| format!("Could not open barcode FastQ file {:?}", config.barcode_fastq))?;
-        Box::new(file)
-    };
-    let barcode_reader = fastq::Reader::new(reader);
+/// Counts barcodes by UMI as described by `config`. Returns the
+/// number of barcode and UMI read pairs whose IDs do not agree, which
+/// is non-zero only when `config.id_check` is `IdCheck::Count`.
+pub fn bc_umi(config: Config) -> Result<usize> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .build()?;
+    pool.install(|| count_umis(config))
+}
 
-    let mut barcode_umis = BarcodeUmis::new();
+// Barcode UMIs, filter and fate statistics, and the number of read
+// pairs with mismatched IDs.
+type UmiTally = (BarcodeUmis, FilterStats, FateStats, usize);
+
+fn count_umis(config: Config) -> Result<usize> {
+    let (barcode_umis, filter_stats, fate_stats, mismatches) = tally_umis(&config)?;
+
+    if let Some(ref fates_filename) = config.fates_report {
+        let mut writer = create_output(fates_filename)
+            .with_context(|| format!("Could not create fates file {:?}", fates_filename))?;
+        fate_stats.write(&mut writer).map_err(|e| anyhow!(e))?;
+        writer.finish()?;
+    }
 
-    for recres in barcode_reader.records() {
-        let rec = recres
-            .with_context(|| format!("Bad FastQ record"))?;
-        let desc = rec
-            .desc()
-            .ok_or_else(|| anyhow!("No header information for FastQ record {:?}", rec.id()))?;
-        let umi = BarcodeUmis::find_umi(&config.umi_prefix, desc)
-            .ok_or_else(|| anyhow!("No UMI in {:?} for FastQ record{:?}", desc, rec.id()))?;
-        barcode_umis.count_one(rec.seq(), umi.as_bytes());
+    if let Some(ref report_filename) = config.filter_report {
+        let mut writer = create_output(report_filename).with_context(|| {
+            format!("Could not create filter report file {:?}", report_filename)
+        })?;
+        filter_stats.write(&mut writer).map_err(|e| anyhow!(e))?;
+        writer.finish()?;
     }
 
     let final_counts = if let Some(ref nbhd_filename) = config.neighborhood {
-        neighborhood_counts(barcode_umis, &nbhd_filename).map_err(|e| anyhow!(e))?
+        neighborhood_counts(barcode_umis, &nbhd_filename, &config.nbhd_spec)
+            .map_err(|e| anyhow!(e))?
     } else {
         barcode_umis
     };
 
+    let mut final_counts = match config.umi_spec {
+        Some(ref umi_spec) => final_counts.cluster_umis(umi_spec),
+        None => final_counts,
+    };
+
+    if let Some(ref chimera_base) = config.chimeras {
+        let chimera_stats = final_counts.reassign_chimeras(config.chimera_ratio);
+        chimera_stats.write(&chimera_base)?;
+    }
+
+    if let Some(ref dedup_filename) = config.dedup_fastq {
+        let mut writer = create_output(dedup_filename).with_context(|| {
+            format!(
+                "Could not create deduplicated FastQ file {:?}",
+                dedup_filename
+            )
+        })?;
+        final_counts
+            .write_fastq(&mut writer)
+            .with_context(|| "Error while writing deduplicated FastQ file")?;
+        writer
+            .finish()
+            .with_context(|| "Error while writing deduplicated FastQ file")?;
+    }
+
     if let Some(ref dedup_base) = config.dedup_stats {
         final_counts.write_tables(&dedup_base)?;
     }
 
-    let writer: Box<dyn Write> = if config.out_barcodes == "-" {
-        Box::new(io::stdout())
-    } else {
-        let file = File::create(&config.out_barcodes)
-            .with_context(|| format!("Could not create counts output file {:?}", config.out_barcodes))?;
-        Box::new(file)
-    };
-    final_counts.write(writer)
+    if let Some(ref saturation_base) = config.saturation {
+        final_counts.write_saturation(&saturation_base)?;
+    }
+
+    let mut writer = create_output(&config.out_barcodes).with_context(|| {
+        format!(
+            "Could not create counts output file {:?}",
+            config.out_barcodes
+        )
+    })?;
+    final_counts
+        .write(&mut writer)
+        .with_context(|| "Error while writing counts output file")?;
+    writer
+        .finish()
         .with_context(|| "Error while writing counts output file")?;
 
-    Ok(())
+    Ok(mismatches)
+}
+
+// Reads barcodes with UMIs from a barcode FastQ file, a pair of
+// barcode and UMI FastQ files, or a BAM file, and tabulates them in
+// parallel chunks.
+fn tally_umis(config: &Config) -> Result<UmiTally> {
+    if let Some(ref umi_fastq) = config.umi_fastq {
+        let barcode_reader = open_input(&config.barcode_fastq).with_context(|| {
+            format!(
+                "Could not open barcode FastQ file {:?}",
+                config.barcode_fastq
+            )
+        })?;
+        let umi_reader = open_input(umi_fastq)
+            .with_context(|| format!("Could not open UMI FastQ file {:?}", umi_fastq))?;
+        let mut barcode_chunks = FastqChunks::new(barcode_reader, CHUNK_RECORDS);
+        let mut umi_chunks = FastqChunks::new(umi_reader, CHUNK_RECORDS);
+        let pair_chunks =
+            std::iter::from_fn(move || match (barcode_chunks.next(), umi_chunks.next()) {
+                (None, None) => None,
+                (Some(barcode_chunk), Some(umi_chunk)) => Some(
+                    barcode_chunk
+                        .and_then(|b| umi_chunk.map(|u| (b, u)))
+                        .map_err(|e| anyhow!(e)),
+                ),
+                _ => Some(Err(anyhow!(
+                    "Barcode and UMI FastQ files have different lengths"
+                ))),
+            });
+        fold_chunk_iter(
+            pair_chunks,
+            |(barcode_chunk, umi_chunk)| count_pair_chunk(barcode_chunk, umi_chunk, config),
+            merge_tallies,
+        )
+    } else if config.bam || config.barcode_fastq.ends_with(".bam") {
+        let mut records = BamRecords::from_path(&config.barcode_fastq, config.umi_source.tag())?;
+        let record_chunks = std::iter::from_fn(move || {
+            let chunk: Result<Vec<fastq::Record>> = records.by_ref().take(CHUNK_RECORDS).collect();
+            match chunk {
+                Ok(ref recs) if recs.is_empty() => None,
+                _ => Some(chunk),
+            }
+        });
+        fold_chunk_iter(
+            record_chunks,
+            |recs| {
+                count_records(
+                    recs.iter().map(|rec| split_umi(rec.clone(), config)),
+                    config,
+                )
+            },
+            merge_tallies,
+        )
+    } else {
+        let reader = open_input(&config.barcode_fastq).with_context(|| {
+            format!(
+                "Could not open barcode FastQ file {:?}",
+                config.barcode_fastq
+            )
+        })?;
+        fold_chunks(
+            reader,
+            CHUNK_RECORDS,
+            |chunk| count_chunk(chunk, config),
+            merge_tallies,
+        )
+    }
+}
+
+fn merge_tallies(
+    (mut umis, mut filters, mut fates, mismatches): UmiTally,
+    (chunk_umis, chunk_filters, chunk_fates, chunk_mismatches): UmiTally,
+) -> UmiTally {
+    umis += chunk_umis;
+    filters += chunk_filters;
+    fates += chunk_fates;
+    (umis, filters, fates, mismatches + chunk_mismatches)
+}
+
+fn count_chunk(chunk: &[u8], config: &Config) -> Result<UmiTally> {
+    let records = fastq::Reader::new(chunk).records().map(|recres| {
+        let rec = recres.with_context(|| format!("Bad FastQ record"))?;
+        split_umi(rec, config)
+    });
+    count_records(records, config)
+}
+
+fn count_pair_chunk(barcode_chunk: &[u8], umi_chunk: &[u8], config: &Config) -> Result<UmiTally> {
+    let mut pairs = PairRecords::new(
+        fastq::Reader::new(barcode_chunk).records(),
+        fastq::Reader::new(umi_chunk).records(),
+    )
+    .with_id_check(config.id_check);
+    let records = pairs.by_ref().map(|pairres| {
+        let (rec, umi_rec) = pairres.with_context(|| format!("Bad FastQ record pair"))?;
+        let umi = config
+            .umi_source
+            .find(&umi_rec)
+            .ok_or_else(|| anyhow!("No UMI in UMI read for FastQ record {:?}", umi_rec.id()))?;
+        Ok((rec, umi))
+    });
+    let (umis, filters, fates, _) = count_records(records, config)?;
+    Ok((umis, filters, fates, pairs.mismatches()))
+}
+
+// Finds the UMI for a barcode read, removing it from the read if it
+// is inline.
+fn split_umi(rec: fastq::Record, config: &Config) -> Result<(fastq::Record, Vec<u8>)> {
+    let umi = config.umi_source.find(&rec).ok_or_else(|| {
+        anyhow!(
+            "No UMI in {:?} for FastQ record {:?}",
+            rec.desc().unwrap_or(""),
+            rec.id()
+        )
+    })?;
+    Ok((config.umi_source.trim(rec), umi))
+}
+
+fn count_records<I>(records: I, config: &Config) -> Result<UmiTally>
+where
+    I: Iterator<Item = Result<(fastq::Record, Vec<u8>)>>,
+{
+    let mut extractor = config.extract.as_ref().map(Extractor::new);
+    let mut barcode_umis = BarcodeUmis::new();
+    let mut filter_stats = FilterStats::default();
+    let mut fate_stats = FateStats::default();
+
+    for recres in records {
+        let (rec, umi) = recres?;
+        let extracted = match extractor {
+            Some(ref mut extractor) => match fate_stats.extract(extractor, &rec) {
+                Some(barcode_rec) => Some(barcode_rec),
+                None => continue,
+            },
+            None => None,
+        };
+        let barcode_rec = extracted.as_ref().unwrap_or(&rec);
+        if !filter_stats.check(&config.read_filter, barcode_rec) {
+            continue;
+        }
+        if config.dedup_fastq.is_some() {
+            let barcode = barcode_rec.seq().to_vec();
+            barcode_umis.count_read(&barcode, &umi, rec);
+        } else {
+            barcode_umis.count_one(barcode_rec.seq(), &umi);
+        }
+    }
+
+    Ok((barcode_umis, filter_stats, fate_stats, 0))
 }
 
 fn neighborhood_counts(
     raw_umis: BarcodeUmis,
     nbhd_filename: &str,
+    nbhd_spec: &NeighborhoodSpec,
 ) -> Result<BarcodeUmis, failure::Error> {
-    let nbhds_raw = Neighborhood::gather_neighborhoods(raw_umis.barcode_map());
-    let nbhds: Vec<_> = nbhds_raw.into_iter().map(|n| n.into_sorted()).collect();
+    let nbhds_raw = Neighborhood::gather_parallel(raw_umis.barcode_map(), nbhd_spec);
+    let nbhds: Vec<_> = nbhds_raw.into_par_iter().map(|n| n.into_sorted()).collect();
 
     let nbhd_sizes: Vec<_> = nbhds
         .iter()
         .map(|n| n.with_mapped_values(|u| u.total_counts()))
         .collect::<Vec<SortedNeighborhood<usize>>>();
 
-    SortedNeighborhood::write_tables(&nbhd_filename, nbhd_sizes.iter())?;
+    SortedNeighborhood::write_tables(&nbhd_filename, nbhd_sizes.iter(), nbhd_spec)?;
 
     Ok(std::iter::FromIterator::from_iter(
         nbhds.into_iter().map(|n| UmiCounts::merge(n)),
     ))
 }
 
+/// Representative read for one molecule, the read with the fewest
+/// expected errors among all reads with its barcode and UMI.
+#[derive(Debug, Clone)]
+pub struct MoleculeRead {
+    record: fastq::Record,
+    expected_errors: f64,
+}
+
+impl MoleculeRead {
+    pub fn new(record: fastq::Record) -> Self {
+        let expected_errors = ReadFilter::expected_errors(record.qual());
+        MoleculeRead {
+            record: record,
+            expected_errors: expected_errors,
+        }
+    }
+
+    pub fn record(&self) -> &fastq::Record {
+        &self.record
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct UmiCounts {
     total: usize,
-    umi_counts: HashMap<Vec<u8>, usize>,
+    umi_counts: HashMap<BarcodeKey, usize>,
+    raw_umis: Option<usize>,
+    /// Representative reads for each UMI, kept only when reads are
+    /// counted with `count_read()`
+    reads: HashMap<BarcodeKey, MoleculeRead>,
 }
 
 impl UmiCounts {
@@ -93,6 +345,8 @@ impl UmiCounts {
         UmiCounts {
             total: 0,
             umi_counts: HashMap::new(),
+            raw_umis: None,
+            reads: HashMap::new(),
         }
     }
 
@@ -101,17 +355,38 @@ impl UmiCounts {
     }
 
     pub fn count_add(&mut self, umi: &[u8], count: usize) -> () {
-        let umi_count = if let Some(existing) = self.umi_counts.get_mut(umi) {
-            existing
-        } else {
-            self.umi_counts.insert(umi.to_owned(), 0);
-            self.umi_counts.get_mut(umi).unwrap()
-        };
+        self.count_add_key(BarcodeKey::new(umi), count)
+    }
 
-        *umi_count += count;
+    fn count_add_key(&mut self, umi: BarcodeKey, count: usize) -> () {
+        *self.umi_counts.entry(umi).or_insert(0) += count;
         self.total += count;
     }
 
+    /// Counts one read and keeps it as the representative read for
+    /// its UMI if it has fewer expected errors than any earlier read.
+    pub fn count_read(&mut self, umi: &[u8], record: fastq::Record) -> () {
+        let key = BarcodeKey::new(umi);
+        self.keep_read(key.clone(), MoleculeRead::new(record));
+        self.count_add_key(key, 1)
+    }
+
+    fn keep_read(&mut self, umi: BarcodeKey, read: MoleculeRead) -> () {
+        match self.reads.get(&umi) {
+            Some(best) if best.expected_errors <= read.expected_errors => (),
+            _ => {
+                self.reads.insert(umi, read);
+            }
+        }
+    }
+
+    fn remove_key(&mut self, umi: &BarcodeKey) -> Option<MoleculeRead> {
+        if let Some(count) = self.umi_counts.remove(umi) {
+            self.total -= count;
+        }
+        self.reads.remove(umi)
+    }
+
     pub fn total_counts(&self) -> usize {
         self.total
     }
@@ -119,6 +394,41 @@ impl UmiCounts {
         self.umi_counts.len()
     }
 
+    /// Number of distinct UMI sequences before clustering, which is
+    /// the same as `total_umis()` for UMIs that were never clustered.
+    pub fn raw_umis(&self) -> usize {
+        self.raw_umis.unwrap_or_else(|| self.total_umis())
+    }
+
+    /// Clusters UMIs into neighborhoods according to `umi_spec` and
+    /// merges the counts of each neighborhood onto its most abundant
+    /// UMI, so that UMIs differing by sequencing errors are counted
+    /// as one molecule.
+    ///
+    /// Clustering should follow any merging of barcodes, as the raw
+    /// UMI count is not kept when clustered counts are added together.
+    pub fn cluster(&self, umi_spec: &NeighborhoodSpec) -> UmiCounts {
+        let mut clustered = UmiCounts::new();
+        for nbhd in Neighborhood::gather(self.umi_counts.clone(), umi_spec) {
+            let nbhd = nbhd.into_sorted();
+            let (key, _) = nbhd.key_barcode();
+            clustered.count_add_key(key.clone(), nbhd.total());
+            for (umi, _) in nbhd.barcodes() {
+                if let Some(read) = self.reads.get(umi) {
+                    clustered.keep_read(key.clone(), read.clone());
+                }
+            }
+        }
+        clustered.raw_umis = Some(self.raw_umis());
+        clustered
+    }
+
+    /// Library complexity of the molecules for this barcode, where
+    /// each UMI is one molecule.
+    pub fn complexity(&self) -> Complexity {
+        Complexity::new(self.umi_counts.values().copied())
+    }
+
     pub fn counts(&self) -> Vec<usize> {
         let mut cts = self
             .umi_counts
@@ -130,7 +440,7 @@ impl UmiCounts {
         cts
     }
 
-    fn merge(sorted_nbhd: SortedNeighborhood<UmiCounts>) -> (Vec<u8>, UmiCounts) {
+    fn merge(sorted_nbhd: SortedNeighborhood<UmiCounts>) -> (BarcodeKey, UmiCounts) {
         let mut barcode_umi_iter = sorted_nbhd.into_barcodes();
 
         let (key, mut umi_counts) = barcode_umi_iter.next().unwrap();
@@ -145,8 +455,12 @@ impl UmiCounts {
 impl std::ops::AddAssign for UmiCounts {
     fn add_assign(&mut self, other: Self) {
         for (umi, count) in other.umi_counts.into_iter() {
-            self.count_add(&umi, count);
+            self.count_add_key(umi, count);
         }
+        for (umi, read) in other.reads.into_iter() {
+            self.keep_read(umi, read);
+        }
+        self.raw_umis = None;
     }
 }
 
@@ -156,28 +470,211 @@ impl OrdEntry for UmiCounts {
     }
 }
 
-/// Tabulation of barcode counts in a sample
+impl CountEntry for UmiCounts {
+    fn entry_count(&self) -> usize {
+        self.total
+    }
+}
+
+/// Molecule whose UMI was reassigned from a minor barcode onto the
+/// dominant barcode for that UMI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chimera {
+    pub umi: BarcodeKey,
+    pub barcode: BarcodeKey,
+    pub reads: usize,
+    pub dominant: BarcodeKey,
+    pub dominant_reads: usize,
+}
+
+/// Results of reassigning chimeric UMIs across barcodes.
 #[derive(Debug, Clone)]
-pub struct BarcodeUmis(HashMap<Vec<u8>, UmiCounts>);
+pub struct ChimeraStats {
+    /// Number of barcode and UMI pairs before reassignment
+    pub molecules: usize,
+    /// Number of UMIs seen with more than one barcode
+    pub shared_umis: usize,
+    /// Number of shared UMIs reassigned to their dominant barcode
+    pub reassigned_umis: usize,
+    pub chimeras: Vec<Chimera>,
+}
+
+impl ChimeraStats {
+    /// Estimated fraction of molecules that are chimeras, or 0 with
+    /// no molecules.
+    pub fn chimera_rate(&self) -> f64 {
+        if self.molecules > 0 {
+            (self.chimeras.len() as f64) / (self.molecules as f64)
+        } else {
+            0.0
+        }
+    }
+
+    /// Writes a table of reassigned molecules and a summary including
+    /// the estimated chimera rate.
+    pub fn write(&self, filebase: &str) -> Result<()> {
+        let chimeras_filename = SortedNeighborhood::output_filename(filebase, "-chimeras.txt");
+        let chimeras_file = create_output(&chimeras_filename.to_string_lossy())
+            .with_context(|| format!("Error creating chimera file {:?}", chimeras_filename))?;
+        let mut chimeras_out = BufWriter::new(chimeras_file);
+
+        writeln!(
+            chimeras_out,
+            "umi\tbarcode\treads\tdominant\tdominant_reads"
+        )?;
+        for chimera in self.chimeras.iter() {
+            writeln!(
+                chimeras_out,
+                "{}\t{}\t{}\t{}\t{}",
+                chimera.umi,
+                chimera.barcode,
+                chimera.reads,
+                chimera.dominant,
+                chimera.dominant_reads
+            )?;
+        }
+        finish_output(chimeras_out)?;
+
+        let summary_filename =
+            SortedNeighborhood::output_filename(filebase, "-chimera-summary.txt");
+        let summary_file =
+            create_output(&summary_filename.to_string_lossy()).with_context(|| {
+                format!("Error creating chimera summary file {:?}", summary_filename)
+            })?;
+        let mut summary_out = BufWriter::new(summary_file);
+
+        writeln!(summary_out, "molecules\t{}", self.molecules)?;
+        writeln!(summary_out, "shared_umis\t{}", self.shared_umis)?;
+        writeln!(summary_out, "reassigned_umis\t{}", self.reassigned_umis)?;
+        writeln!(summary_out, "chimeric_molecules\t{}", self.chimeras.len())?;
+        writeln!(
+            summary_out,
+            "chimeric_reads\t{}",
+            self.chimeras.iter().map(|c| c.reads).sum::<usize>()
+        )?;
+        writeln!(summary_out, "chimera_rate\t{:0.6}", self.chimera_rate())?;
+        finish_output(summary_out)?;
+
+        Ok(())
+    }
+}
+
+// Flushes a buffered output and finishes any compression.
+fn finish_output(out: BufWriter<Output<Box<dyn Write>>>) -> Result<()> {
+    out.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
+
+/// Tabulation of barcode counts in a sample
+#[derive(Debug, Clone, Default)]
+pub struct BarcodeUmis(HashMap<BarcodeKey, UmiCounts>);
 
 impl BarcodeUmis {
     pub fn new() -> Self {
         BarcodeUmis(HashMap::new())
     }
 
-    pub fn barcode_map(self) -> HashMap<Vec<u8>, UmiCounts> {
+    pub fn barcode_map(self) -> HashMap<BarcodeKey, UmiCounts> {
         self.0
     }
 
+    /// Finds UMIs shared among barcodes and, when the read support for
+    /// a shared UMI is lopsided, treats the minor barcodes as chimeras
+    /// from PCR template switching and moves their reads onto the
+    /// dominant barcode. Barcodes left with no UMIs are removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_ratio` is the minimum ratio of reads for the dominant
+    /// barcode to the next barcode, for a shared UMI to be reassigned
+    pub fn reassign_chimeras(&mut self, min_ratio: f64) -> ChimeraStats {
+        let mut umi_barcodes: HashMap<&BarcodeKey, Vec<(&BarcodeKey, usize)>> = HashMap::new();
+        let mut molecules = 0;
+        for (barcode, umis) in self.0.iter() {
+            for (umi, &reads) in umis.umi_counts.iter() {
+                umi_barcodes.entry(umi).or_default().push((barcode, reads));
+                molecules += 1;
+            }
+        }
+
+        let mut shared_umis = 0;
+        let mut reassigned_umis = 0;
+        let mut chimeras = Vec::new();
+        for (umi, mut barcodes) in umi_barcodes.into_iter() {
+            if barcodes.len() < 2 {
+                continue;
+            }
+            shared_umis += 1;
+
+            barcodes.sort_by(|(bcl, rl), (bcr, rr)| rr.cmp(rl).then(bcl.cmp(bcr)));
+            let (dominant, dominant_reads) = barcodes[0];
+            if (dominant_reads as f64) < min_ratio * (barcodes[1].1 as f64) {
+                continue;
+            }
+            reassigned_umis += 1;
+
+            for &(barcode, reads) in barcodes[1..].iter() {
+                chimeras.push(Chimera {
+                    umi: umi.clone(),
+                    barcode: barcode.clone(),
+                    reads: reads,
+                    dominant: dominant.clone(),
+                    dominant_reads: dominant_reads,
+                });
+            }
+        }
+        chimeras.sort_by(|l, r| {
+            (&l.dominant, &l.umi, &l.barcode).cmp(&(&r.dominant, &r.umi, &r.barcode))
+        });
+
+        for chimera in chimeras.iter() {
+            let mut read = None;
+            if let Some(umis) = self.0.get_mut(&chimera.barcode) {
+                read = umis.remove_key(&chimera.umi);
+                if umis.total_counts() == 0 {
+                    self.0.remove(&chimera.barcode);
+                }
+            }
+            if let Some(umis) = self.0.get_mut(&chimera.dominant) {
+                umis.count_add_key(chimera.umi.clone(), chimera.reads);
+                if let Some(read) = read {
+                    umis.keep_read(chimera.umi.clone(), read);
+                }
+            }
+        }
+
+        ChimeraStats {
+            molecules: molecules,
+            shared_umis: shared_umis,
+            reassigned_umis: reassigned_umis,
+            chimeras: chimeras,
+        }
+    }
+
     pub fn count_one(&mut self, barcode: &[u8], umi: &[u8]) -> () {
-        let barcode_umis = if let Some(existing) = self.0.get_mut(barcode) {
-            existing
-        } else {
-            self.0.insert(barcode.to_owned(), UmiCounts::new());
-            self.0.get_mut(barcode).unwrap()
-        };
+        self.0
+            .entry(BarcodeKey::new(barcode))
+            .or_insert_with(UmiCounts::new)
+            .count_one(umi);
+    }
 
-        barcode_umis.count_one(umi);
+    /// Counts one read as in `count_one()`, keeping the read as a
+    /// representative of its molecule for `write_fastq()`.
+    pub fn count_read(&mut self, barcode: &[u8], umi: &[u8], record: fastq::Record) -> () {
+        self.0
+            .entry(BarcodeKey::new(barcode))
+            .or_insert_with(UmiCounts::new)
+            .count_read(umi, record);
+    }
+
+    /// Clusters the UMIs within each barcode, as in `UmiCounts::cluster()`.
+    pub fn cluster_umis(self, umi_spec: &NeighborhoodSpec) -> Self {
+        BarcodeUmis(
+            self.0
+                .into_par_iter()
+                .map(|(barcode, umis)| (barcode, umis.cluster(umi_spec)))
+                .collect(),
+        )
     }
 
     pub fn find_umi<'a, 'b>(prefix: &'b str, desc: &'a str) -> Option<&'a str> {
@@ -185,18 +682,107 @@ impl BarcodeUmis {
         rest.split_whitespace().next()
     }
 
+    /// Writes one representative read for each molecule, sorted by
+    /// barcode and then UMI, with the barcode, the corrected UMI, and
+    /// the number of supporting reads in the read description.
+    pub fn write_fastq<W: Write>(&self, fastq_out: W) -> Result<()> {
+        let mut out = fastq::Writer::new(fastq_out);
+
+        let mut barcodes: Vec<_> = self.0.iter().collect();
+        barcodes.sort_by(|(bcl, _), (bcr, _)| bcl.cmp(bcr));
+        for (barcode, umis) in barcodes {
+            let mut molecules: Vec<_> = umis.reads.iter().collect();
+            molecules.sort_by(|(umil, _), (umir, _)| umil.cmp(umir));
+            for (umi, read) in molecules {
+                let desc = format!(
+                    "barcode={} umi={} reads={}",
+                    barcode,
+                    umi,
+                    umis.umi_counts.get(umi).copied().unwrap_or(0)
+                );
+                let rec = read.record();
+                out.write(rec.id(), Some(&desc), rec.seq(), rec.qual())?;
+            }
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+
     pub fn write<W: Write>(&self, umi_out: W) -> Result<()> {
         let mut out = std::io::BufWriter::new(umi_out);
 
         for (barcode, umis) in self.0.iter() {
-            write!(
-                out,
-                "{}\t{}\n",
-                String::from_utf8_lossy(barcode),
-                umis.total_umis()
+            write!(out, "{}\t{}\n", barcode, umis.total_umis())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a saturation curve of the expected number of molecules
+    /// seen when subsampling reads, and a table of library complexity
+    /// estimates over all barcodes and for each barcode.
+    pub fn write_saturation(&self, filebase: &str) -> Result<()> {
+        let mut total = Complexity::default();
+        let mut barcode_complexity = Vec::new();
+        for (barcode, umis) in self.0.iter() {
+            let complexity = umis.complexity();
+            total += &complexity;
+            barcode_complexity.push((barcode, complexity));
+        }
+        barcode_complexity
+            .sort_by(|(bcl, cl), (bcr, cr)| cr.reads().cmp(&cl.reads()).then(bcl.cmp(bcr)));
+
+        let curve_filename = SortedNeighborhood::output_filename(filebase, "-saturation.txt");
+        let curve_file = create_output(&curve_filename.to_string_lossy()).with_context(|| {
+            format!("Error creating saturation curve file {:?}", curve_filename)
+        })?;
+        let mut curve_out = BufWriter::new(curve_file);
+
+        writeln!(curve_out, "fraction\treads\tmolecules\tsaturation")?;
+        for &fraction in SATURATION_FRACTIONS.iter() {
+            writeln!(
+                curve_out,
+                "{:0.2}\t{:0.1}\t{:0.1}\t{:0.4}",
+                fraction,
+                fraction * (total.reads() as f64),
+                total.subsample(fraction),
+                total.subsample_saturation(fraction)
             )?;
         }
+        finish_output(curve_out)?;
+
+        let complexity_filename = SortedNeighborhood::output_filename(filebase, "-complexity.txt");
+        let complexity_file = create_output(&complexity_filename.to_string_lossy())
+            .with_context(|| format!("Error creating complexity file {:?}", complexity_filename))?;
+        let mut complexity_out = BufWriter::new(complexity_file);
+
+        writeln!(
+            complexity_out,
+            "barcode\treads\tmolecules\tsingletons\tdoubletons\tsaturation\tchao1\tgood_toulmin_2x"
+        )?;
+        Self::write_complexity(&mut complexity_out, "total", &total)?;
+        for (barcode, complexity) in barcode_complexity.iter() {
+            Self::write_complexity(&mut complexity_out, &barcode.to_string(), complexity)?;
+        }
+        finish_output(complexity_out)?;
+
+        Ok(())
+    }
 
+    fn write_complexity<W: Write>(out: &mut W, name: &str, complexity: &Complexity) -> Result<()> {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{:0.4}\t{:0.1}\t{:0.1}",
+            name,
+            complexity.reads(),
+            complexity.molecules(),
+            complexity.freq(1),
+            complexity.freq(2),
+            complexity.saturation(),
+            complexity.chao1(),
+            complexity.good_toulmin(1.0)
+        )?;
         Ok(())
     }
 
@@ -210,7 +796,7 @@ impl BarcodeUmis {
         let mut umis_out = std::io::BufWriter::new(umis_out_file);
 
         for (barcode, umis) in self.0.iter() {
-            write!(umis_out, "{}", String::from_utf8_lossy(barcode))?;
+            write!(umis_out, "{}", barcode)?;
             let umi_counts = umis.counts();
 
             write!(
@@ -225,18 +811,311 @@ impl BarcodeUmis {
             for count in umi_counts.iter() {
                 write!(umis_out, "{},", count)?;
             }
-            write!(umis_out, "\n")?;
+
+            // UMIs before clustering, last to keep the other columns
+            // in place
+            write!(umis_out, "\t{}\n", umis.raw_umis())?;
         }
 
         Ok(())
     }
 }
 
-impl std::iter::FromIterator<(Vec<u8>, UmiCounts)> for BarcodeUmis {
+impl std::ops::AddAssign for BarcodeUmis {
+    fn add_assign(&mut self, other: Self) {
+        for (barcode, umis) in other.0.into_iter() {
+            *self.0.entry(barcode).or_insert_with(UmiCounts::new) += umis;
+        }
+    }
+}
+
+impl std::iter::FromIterator<(BarcodeKey, UmiCounts)> for BarcodeUmis {
     fn from_iter<I>(iter: I) -> Self
     where
-        I: IntoIterator<Item = (Vec<u8>, UmiCounts)>,
+        I: IntoIterator<Item = (BarcodeKey, UmiCounts)>,
     {
         BarcodeUmis(HashMap::from_iter(iter))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_umis() {
+        let mut barcode_umis = BarcodeUmis::new();
+        for (umi, count) in vec![
+            (&b"AAAAAA"[..], 20),
+            (b"AAAAAT", 2),
+            (b"AAAATT", 1),
+            (b"CCCCCC", 8),
+            (b"CCCCCG", 6),
+        ] {
+            for _ in 0..count {
+                barcode_umis.count_one(b"ACGTACGT", umi);
+            }
+        }
+
+        let directional = NeighborhoodSpec {
+            method: NeighborhoodMethod::Directional,
+            ..NeighborhoodSpec::default()
+        };
+        let clustered = barcode_umis
+            .clone()
+            .cluster_umis(&directional)
+            .barcode_map();
+        let umis = clustered.get(&BarcodeKey::new(b"ACGTACGT")).unwrap();
+        assert_eq!(umis.total_counts(), 37);
+        assert_eq!(umis.raw_umis(), 5);
+        assert_eq!(umis.total_umis(), 3);
+        assert_eq!(umis.counts(), vec![23, 8, 6]);
+
+        let connected = barcode_umis
+            .cluster_umis(&NeighborhoodSpec::default())
+            .barcode_map();
+        let umis = connected.get(&BarcodeKey::new(b"ACGTACGT")).unwrap();
+        assert_eq!(umis.raw_umis(), 5);
+        assert_eq!(umis.counts(), vec![23, 14]);
+    }
+
+    #[test]
+    fn umi_fastq() {
+        let mut barcode_file = tempfile::NamedTempFile::new().unwrap();
+        barcode_file
+            .write_all(b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACGTACGT\n+\nIIIIIIII\n@r3\nACGTACGT\n+\nIIIIIIII\n")
+            .unwrap();
+        let barcode_path = barcode_file.into_temp_path();
+        let mut umi_file = tempfile::NamedTempFile::new().unwrap();
+        umi_file
+            .write_all(
+                b"@r1\nAAAATTT\n+\nIIIIIII\n@r2\nCCCCTTT\n+\nIIIIIII\n@r3\nAAAAGGG\n+\nIIIIIII\n",
+            )
+            .unwrap();
+        let umi_path = umi_file.into_temp_path();
+        let output_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: barcode_path.to_string_lossy().into_owned(),
+            bam: false,
+            umi_source: UmiSource::Positions(UmiSource::parse_positions("1:4").unwrap()),
+            umi_fastq: Some(umi_path.to_string_lossy().into_owned()),
+            id_check: IdCheck::Fail,
+            out_barcodes: output_path.to_string_lossy().into_owned(),
+            dedup_stats: None,
+            saturation: None,
+            chimeras: None,
+            chimera_ratio: 10.0,
+            dedup_fastq: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            umi_spec: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+        bc_umi(config).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(output_path).unwrap(),
+            "ACGTACGT\t2\n"
+        );
+    }
+
+    #[test]
+    fn umi_fastq_mismatched_ids() {
+        let mut barcode_file = tempfile::NamedTempFile::new().unwrap();
+        barcode_file
+            .write_all(b"@r1\nACGTACGT\n+\nIIIIIIII\n@r2\nACGTACGT\n+\nIIIIIIII\n@r3\nACGTACGT\n+\nIIIIIIII\n")
+            .unwrap();
+        let barcode_path = barcode_file.into_temp_path();
+        let mut umi_file = tempfile::NamedTempFile::new().unwrap();
+        umi_file
+            .write_all(b"@r2\nCCCC\n+\nIIII\n@r1\nAAAA\n+\nIIII\n@r3\nAAAA\n+\nIIII\n")
+            .unwrap();
+        let umi_path = umi_file.into_temp_path();
+        let output_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = |id_check| Config {
+            barcode_fastq: barcode_path.to_string_lossy().into_owned(),
+            bam: false,
+            umi_source: UmiSource::Positions(UmiSource::parse_positions("1:4").unwrap()),
+            umi_fastq: Some(umi_path.to_string_lossy().into_owned()),
+            id_check: id_check,
+            out_barcodes: output_path.to_string_lossy().into_owned(),
+            dedup_stats: None,
+            saturation: None,
+            chimeras: None,
+            chimera_ratio: 10.0,
+            dedup_fastq: None,
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            umi_spec: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+        assert!(bc_umi(config(IdCheck::Fail)).is_err());
+        assert_eq!(bc_umi(config(IdCheck::Count)).unwrap(), 2);
+    }
+
+    #[test]
+    fn dedup_fastq() {
+        let mut barcode_file = tempfile::NamedTempFile::new().unwrap();
+        barcode_file
+            .write_all(
+                b"@r1\nAAAAACGTACGT\n+\nIIIII#IIIIII\n@r2\nAAAAACGTACGT\n+\nIIIIIIIIIIII\n\
+                  @r3\nCCCCACGTACGT\n+\nIIIIIIIIIIII\n@r4\nAAAAACGTACGT\n+\nIIIIIII#IIII\n",
+            )
+            .unwrap();
+        let barcode_path = barcode_file.into_temp_path();
+        let output_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let dedup_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+
+        let config = Config {
+            barcode_fastq: barcode_path.to_string_lossy().into_owned(),
+            bam: false,
+            umi_source: UmiSource::Positions(UmiSource::parse_positions("1:4").unwrap()),
+            umi_fastq: None,
+            id_check: IdCheck::Fail,
+            out_barcodes: output_path.to_string_lossy().into_owned(),
+            dedup_stats: None,
+            saturation: None,
+            chimeras: None,
+            chimera_ratio: 10.0,
+            dedup_fastq: Some(dedup_path.to_string_lossy().into_owned()),
+            neighborhood: None,
+            nbhd_spec: NeighborhoodSpec::default(),
+            umi_spec: None,
+            read_filter: ReadFilter::default(),
+            filter_report: None,
+            threads: 1,
+            extract: None,
+            fates_report: None,
+        };
+        bc_umi(config).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dedup_path).unwrap(),
+            "@r2 barcode=ACGTACGT umi=AAAA reads=3\nACGTACGT\n+\nIIIIIIII\n\
+             @r3 barcode=ACGTACGT umi=CCCC reads=1\nACGTACGT\n+\nIIIIIIII\n"
+        );
+    }
+
+    #[test]
+    fn cluster_reads() {
+        let mut umis = UmiCounts::new();
+        for (umi, qual) in vec![
+            (&b"AAAA"[..], &b"IIII"[..]),
+            (b"AAAA", b"II#I"),
+            (b"AAAA", b"IIII"),
+            (b"AAAT", b"JJJJ"),
+        ] {
+            umis.count_read(umi, fastq::Record::with_attrs("r", None, b"ACGT", qual));
+        }
+
+        let clustered = umis.cluster(&NeighborhoodSpec::default());
+        assert_eq!(clustered.counts(), vec![4]);
+        let read = clustered.reads.get(&BarcodeKey::new(b"AAAA")).unwrap();
+        assert_eq!(read.record().qual(), b"JJJJ");
+    }
+
+    #[test]
+    fn saturation_tables() {
+        let mut barcode_umis = BarcodeUmis::new();
+        for (barcode, umi, count) in vec![
+            (&b"ACGTACGT"[..], &b"AAAA"[..], 3),
+            (b"ACGTACGT", b"CCCC", 1),
+            (b"ACGTACGT", b"GGGG", 1),
+            (b"CATGCATG", b"AAAA", 2),
+        ] {
+            for _ in 0..count {
+                barcode_umis.count_one(barcode, umi);
+            }
+        }
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let base = output_dir.path().join("sat").to_string_lossy().into_owned();
+        barcode_umis.write_saturation(&base).unwrap();
+
+        let complexity = std::fs::read_to_string(format!("{}-complexity.txt", base)).unwrap();
+        assert_eq!(
+            complexity,
+            "barcode\treads\tmolecules\tsingletons\tdoubletons\tsaturation\tchao1\tgood_toulmin_2x\n\
+             total\t7\t4\t2\t1\t0.4286\t4.5\t2.0\n\
+             ACGTACGT\t5\t3\t2\t0\t0.4000\t4.0\t3.0\n\
+             CATGCATG\t2\t1\t0\t1\t0.5000\t1.0\t0.0\n"
+        );
+
+        let curve = std::fs::read_to_string(format!("{}-saturation.txt", base)).unwrap();
+        let mut lines = curve.lines();
+        assert_eq!(lines.next(), Some("fraction\treads\tmolecules\tsaturation"));
+        assert_eq!(lines.last(), Some("1.00\t7.0\t4.0\t0.4286"));
+    }
+
+    #[test]
+    fn reassign_chimeras() {
+        let mut barcode_umis = BarcodeUmis::new();
+        for (barcode, umi, count) in vec![
+            // Lopsided: AAAA reassigned from CATGCATG and GGGGCCCC
+            (&b"ACGTACGT"[..], &b"AAAA"[..], 50),
+            (b"CATGCATG", b"AAAA", 2),
+            (b"GGGGCCCC", b"AAAA", 1),
+            // Balanced: CCCC is an independent collision
+            (b"ACGTACGT", b"CCCC", 8),
+            (b"CATGCATG", b"CCCC", 6),
+            (b"CATGCATG", b"GGGG", 4),
+        ] {
+            for _ in 0..count {
+                barcode_umis.count_one(barcode, umi);
+            }
+        }
+
+        let stats = barcode_umis.reassign_chimeras(10.0);
+        assert_eq!(stats.molecules, 6);
+        assert_eq!(stats.shared_umis, 2);
+        assert_eq!(stats.reassigned_umis, 1);
+        assert_eq!(
+            stats.chimeras,
+            vec![
+                Chimera {
+                    umi: BarcodeKey::new(b"AAAA"),
+                    barcode: BarcodeKey::new(b"CATGCATG"),
+                    reads: 2,
+                    dominant: BarcodeKey::new(b"ACGTACGT"),
+                    dominant_reads: 50,
+                },
+                Chimera {
+                    umi: BarcodeKey::new(b"AAAA"),
+                    barcode: BarcodeKey::new(b"GGGGCCCC"),
+                    reads: 1,
+                    dominant: BarcodeKey::new(b"ACGTACGT"),
+                    dominant_reads: 50,
+                },
+            ]
+        );
+        assert!((stats.chimera_rate() - 2.0 / 6.0).abs() < 1e-9);
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let base = output_dir.path().join("chim");
+        stats.write(&base.to_string_lossy()).unwrap();
+        let summary =
+            std::fs::read_to_string(base.with_file_name("chim-chimera-summary.txt")).unwrap();
+        assert_eq!(summary.lines().last(), Some("chimera_rate\t0.333333"));
+
+        let barcode_map = barcode_umis.barcode_map();
+        assert_eq!(barcode_map.len(), 2);
+        let dominant = barcode_map.get(&BarcodeKey::new(b"ACGTACGT")).unwrap();
+        assert_eq!(dominant.counts(), vec![53, 8]);
+        let minor = barcode_map.get(&BarcodeKey::new(b"CATGCATG")).unwrap();
+        assert_eq!(minor.counts(), vec![6, 4]);
+        assert_eq!(minor.total_counts(), 10);
+
+        let empty = BarcodeUmis::new().reassign_chimeras(10.0);
+        assert_eq!(empty.chimera_rate(), 0.0);
+    }
+}
@@ -0,0 +1,14 @@
+use std::str::FromStr;
+
+use clap::ArgMatches;
+
+/// Parses the value of an optional command-line argument, exiting
+/// with a usage error when the value is malformed.
+pub fn optional_value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            clap::Error::value_validation_auto(format!("Invalid value {:?} for {}", value, name))
+                .exit()
+        })
+    })
+}
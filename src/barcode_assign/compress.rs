@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const MAGIC_LEN: usize = 4;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression format of an input or output stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Plain,
+    Gzip,
+    Zstd,
+}
+
+impl Format {
+    /// Determines the compression format from the magic bytes at the
+    /// start of a stream.
+    ///
+    /// Block gzip (bgzip) files are ordinary multi-member gzip files
+    /// and are reported as `Gzip`.
+    pub fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(&GZIP_MAGIC) {
+            Format::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Format::Zstd
+        } else {
+            Format::Plain
+        }
+    }
+
+    /// Determines the compression format for an output file from its
+    /// extension, `.gz` for gzip and `.zst` for zstd.
+    pub fn from_filename<P: AsRef<Path>>(filename: P) -> Self {
+        match filename.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") | Some("bgz") => Format::Gzip,
+            Some("zst") => Format::Zstd,
+            _ => Format::Plain,
+        }
+    }
+}
+
+/// Opens an input file, transparently decompressing gzip, bgzip, or
+/// zstd data.
+///
+/// The compression format is detected from the contents of the input
+/// and not from the filename.
+///
+/// # Arguments
+///
+/// * `filename` is the name of the file to read, or `-` for standard input
+pub fn open_input(filename: &str) -> io::Result<Box<dyn BufRead>> {
+    let raw: Box<dyn Read> = if filename == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(filename)?)
+    };
+    decompress(raw)
+}
+
+/// Wraps an input source, transparently decompressing gzip, bgzip, or
+/// zstd data.
+///
+/// # Arguments
+///
+/// * `input` is the raw input source
+pub fn decompress<'a, R: Read + 'a>(mut input: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&mut input)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut magic)?;
+    let format = Format::from_magic(&magic);
+    let restored = io::Cursor::new(magic).chain(input);
+
+    Ok(match format {
+        Format::Plain => Box::new(BufReader::new(restored)),
+        Format::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(restored))),
+        Format::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::new(restored)?)),
+    })
+}
+
+/// Output destination that may compress data written to it.
+///
+/// A compressed stream must be completed by calling `finish()`, which
+/// reports any error writing the final block. Dropping the writer
+/// without finishing it leaves a truncated stream.
+pub enum Output<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Output<W> {
+    /// Completes the compressed stream, flushes all output, and
+    /// returns the underlying destination.
+    pub fn finish(self) -> io::Result<W> {
+        let mut output = match self {
+            Output::Plain(output) => output,
+            Output::Gzip(encoder) => encoder.finish()?,
+            Output::Zstd(encoder) => encoder.finish()?,
+        };
+        output.flush()?;
+        Ok(output)
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(output) => output.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(output) => output.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Creates an output file, compressing the output when the filename
+/// ends in `.gz` (gzip) or `.zst` (zstd).
+///
+/// Call `finish()` on the output after the last write.
+///
+/// # Arguments
+///
+/// * `filename` is the name of the file to create, or `-` for standard output
+pub fn create_output(filename: &str) -> io::Result<Output<Box<dyn Write>>> {
+    if filename == "-" {
+        Ok(Output::Plain(Box::new(io::stdout())))
+    } else {
+        let file: Box<dyn Write> = Box::new(File::create(filename)?);
+        compress(file, Format::from_filename(filename))
+    }
+}
+
+/// Wraps an output destination, compressing data written to it.
+///
+/// # Arguments
+///
+/// * `output` is the raw output destination
+/// * `format` is the compression format to write
+pub fn compress<W: Write>(output: W, format: Format) -> io::Result<Output<W>> {
+    Ok(match format {
+        Format::Plain => Output::Plain(output),
+        Format::Gzip => Output::Gzip(GzEncoder::new(output, Compression::default())),
+        Format::Zstd => Output::Zstd(zstd::stream::write::Encoder::new(output, ZSTD_LEVEL)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "@one\nACGTA\n+\n~~~~~\n@two\nCGTAC\n+\n~~~~~\n";
+
+    fn round_trip(format: Format) -> (Vec<u8>, String) {
+        let mut out = compress(Vec::new(), format).unwrap();
+        out.write_all(TEXT.as_bytes()).unwrap();
+        let compressed = out.finish().unwrap();
+
+        let mut text = String::new();
+        decompress(compressed.as_slice())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        (compressed, text)
+    }
+
+    #[test]
+    fn plain() {
+        let (compressed, text) = round_trip(Format::Plain);
+        assert_eq!(compressed, TEXT.as_bytes());
+        assert_eq!(text, TEXT);
+    }
+
+    #[test]
+    fn gzip() {
+        let (compressed, text) = round_trip(Format::Gzip);
+        assert_eq!(Format::from_magic(&compressed), Format::Gzip);
+        assert_eq!(text, TEXT);
+    }
+
+    #[test]
+    fn zstd() {
+        let (compressed, text) = round_trip(Format::Zstd);
+        assert_eq!(Format::from_magic(&compressed), Format::Zstd);
+        assert_eq!(text, TEXT);
+    }
+
+    #[test]
+    fn multi_member_gzip() {
+        let mut compressed = Vec::new();
+        for half in [&TEXT[..12], &TEXT[12..]].iter() {
+            let mut out = GzEncoder::new(&mut compressed, Compression::default());
+            out.write_all(half.as_bytes()).unwrap();
+            out.finish().unwrap();
+        }
+
+        let mut text = String::new();
+        decompress(compressed.as_slice())
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, TEXT);
+    }
+
+    #[test]
+    fn short_input() {
+        let mut text = String::new();
+        decompress(&b"AC"[..])
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "AC");
+    }
+
+    #[test]
+    fn unfinished() {
+        let mut out = compress(Vec::new(), Format::Gzip).unwrap();
+        out.write_all(TEXT.as_bytes()).unwrap();
+        out.flush().unwrap();
+        let partial = match out {
+            Output::Gzip(ref encoder) => encoder.get_ref().clone(),
+            _ => unreachable!(),
+        };
+
+        let mut text = String::new();
+        assert!(decompress(partial.as_slice())
+            .unwrap()
+            .read_to_string(&mut text)
+            .is_err());
+        assert!(out.finish().unwrap().len() > partial.len());
+    }
+
+    #[test]
+    fn output_format() {
+        assert_eq!(Format::from_filename("counts.txt"), Format::Plain);
+        assert_eq!(Format::from_filename("counts.txt.gz"), Format::Gzip);
+        assert_eq!(Format::from_filename("out/x_barcoded.fq.zst"), Format::Zstd);
+    }
+}
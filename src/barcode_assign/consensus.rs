@@ -0,0 +1,217 @@
+use bio::io::fastq;
+
+const QUAL_OFFSET: u8 = 33;
+
+/// Highest consensus quality score.
+pub const MAX_CONSENSUS_QUAL: usize = 60;
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+// IUPAC codes indexed by a bit set of bases, with A = 1, C = 2, G = 4,
+// and T = 8.
+const IUPAC: &[u8; 16] = b"NACMGRSVTWYHKDBN";
+
+/// Parameters for calling a consensus sequence from reads with a
+/// common barcode.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsensusSpec {
+    /// Minimum number of reads covering a position to include it in
+    /// the consensus
+    pub min_depth: usize,
+    /// Minimum fraction of quality-weighted support for the called
+    /// base. When no single base has this support, the position is
+    /// called as the ambiguity code for the fewest bases that do.
+    pub min_fraction: f64,
+}
+
+impl Default for ConsensusSpec {
+    fn default() -> Self {
+        ConsensusSpec {
+            min_depth: 3,
+            min_fraction: 0.8,
+        }
+    }
+}
+
+/// Consensus sequence of a set of reads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+    /// Number of reads in the consensus
+    pub reads: usize,
+    /// Number of positions called as an ambiguity code or `N`
+    pub ambiguous: usize,
+    /// Fraction of read bases within the consensus that agree with
+    /// the best-supported base at their position
+    pub agreement: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Column {
+    coverage: usize,
+    /// Sum of quality scores for each base
+    weights: [usize; 4],
+    counts: [usize; 4],
+}
+
+/// Tally of bases and qualities at each position of a set of reads
+/// that all start at the first position of an amplicon.
+#[derive(Debug, Clone, Default)]
+pub struct Pileup {
+    reads: usize,
+    columns: Vec<Column>,
+}
+
+impl Pileup {
+    pub fn new() -> Self {
+        Pileup::default()
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn add(&mut self, rec: &fastq::Record) {
+        if self.columns.len() < rec.seq().len() {
+            self.columns.resize(rec.seq().len(), Column::default());
+        }
+        for (column, (&nt, &q)) in self
+            .columns
+            .iter_mut()
+            .zip(rec.seq().iter().zip(rec.qual().iter()))
+        {
+            column.coverage += 1;
+            if let Some(base) = BASES.iter().position(|&b| b == nt.to_ascii_uppercase()) {
+                column.weights[base] += q.saturating_sub(QUAL_OFFSET) as usize;
+                column.counts[base] += 1;
+            }
+        }
+        self.reads += 1;
+    }
+
+    /// Calls a consensus over the positions covered by at least
+    /// `min_depth` reads. Each position is called as the base with
+    /// the largest total quality, or an ambiguity code when no base
+    /// has `min_fraction` of the total quality. The consensus quality
+    /// is the total quality of the best-supported base, less that of
+    /// all other bases, so an ambiguity code between evenly supported
+    /// bases has a quality near 0.
+    pub fn consensus(&self, spec: &ConsensusSpec) -> Consensus {
+        let mut seq = Vec::new();
+        let mut qual = Vec::new();
+        let mut ambiguous = 0;
+        let mut agree = 0;
+        let mut observed = 0;
+
+        for column in self
+            .columns
+            .iter()
+            .take_while(|column| column.coverage >= spec.min_depth)
+        {
+            let total: usize = column.weights.iter().sum();
+
+            let mut order = [0, 1, 2, 3];
+            order.sort_by(|&l, &r| column.weights[r].cmp(&column.weights[l]));
+
+            let mut called = 0;
+            let mut support = 0;
+            for &base in order.iter() {
+                if total == 0 || (support as f64) >= spec.min_fraction * (total as f64) {
+                    break;
+                }
+                called |= 1 << base;
+                support += column.weights[base];
+            }
+
+            let nt = IUPAC[called];
+            if !BASES.contains(&nt) {
+                ambiguous += 1;
+            }
+            let top = order[0];
+            let q = (2 * column.weights[top])
+                .saturating_sub(total)
+                .min(MAX_CONSENSUS_QUAL);
+            seq.push(nt);
+            qual.push((q as u8) + QUAL_OFFSET);
+
+            observed += column.counts.iter().sum::<usize>();
+            agree += column.counts[top];
+        }
+
+        Consensus {
+            seq: seq,
+            qual: qual,
+            reads: self.reads,
+            ambiguous: ambiguous,
+            agreement: if observed > 0 {
+                (agree as f64) / (observed as f64)
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pileup(reads: &[(&[u8], &[u8])]) -> Pileup {
+        let mut pileup = Pileup::new();
+        for (seq, qual) in reads.iter() {
+            pileup.add(&fastq::Record::with_attrs("r", None, seq, qual));
+        }
+        pileup
+    }
+
+    #[test]
+    fn majority() {
+        let reads = pileup(&[
+            (b"ACGTAC", b"IIIIII"),
+            (b"ACGTA", b"IIIII"),
+            (b"ACTTA", b"II#II"),
+            (b"ACGT", b"IIII"),
+        ]);
+        let consensus = reads.consensus(&ConsensusSpec::default());
+        assert_eq!(consensus.seq, b"ACGTA".to_vec());
+        assert_eq!(consensus.qual, vec![b'!' + 60; 5]);
+        assert_eq!(consensus.reads, 4);
+        assert_eq!(consensus.ambiguous, 0);
+        assert!((consensus.agreement - 18.0 / 19.0).abs() < 1e-9);
+
+        let deep = reads.consensus(&ConsensusSpec {
+            min_depth: 4,
+            ..ConsensusSpec::default()
+        });
+        assert_eq!(deep.seq, b"ACGT".to_vec());
+
+        // Two agreeing Q10 bases and one disagreeing Q10 base
+        let low = pileup(&[(b"A", b"+"), (b"A", b"+"), (b"C", b"+")]).consensus(&ConsensusSpec {
+            min_fraction: 0.6,
+            ..ConsensusSpec::default()
+        });
+        assert_eq!(low.seq, b"A".to_vec());
+        assert_eq!(low.qual, vec![b'!' + 10]);
+        assert!((low.agreement - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ambiguous() {
+        let reads = pileup(&[
+            (b"ACGT", b"IIII"),
+            (b"ACGA", b"IIII"),
+            (b"ATGN", b"IIII"),
+            (b"ATGN", b"IIII"),
+        ]);
+        let consensus = reads.consensus(&ConsensusSpec::default());
+        assert_eq!(consensus.seq, b"AYGW".to_vec());
+        assert_eq!(consensus.ambiguous, 2);
+        assert_eq!(consensus.qual, b"]!]!".to_vec());
+        assert!((consensus.agreement - 11.0 / 14.0).abs() < 1e-9);
+
+        let shallow = pileup(&[(b"ACGT", b"IIII")]).consensus(&ConsensusSpec::default());
+        assert!(shallow.seq.is_empty());
+        assert_eq!(shallow.agreement, 0.0);
+    }
+}
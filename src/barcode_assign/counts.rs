@@ -1,26 +1,42 @@
 use std::collections::HashMap;
 use std::io::BufRead;
-use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
 use std::iter::FromIterator;
 use std::iter::Sum;
+use std::ops::AddAssign;
 use std::path::Path;
 
 use bio::io::fasta;
 use bio::io::fastq;
 use failure;
 
+use barcode_key::BarcodeKey;
+use compress::{decompress, open_input};
+use whitelist::*;
+
+const LONG_HEADER: [&str; 3] = ["barcode", "sample", "count"];
+
 /// Tabulation of barcode counts in a sample
-#[derive(Debug, Clone)]
-pub struct SampleCounts(HashMap<Vec<u8>, usize>);
+#[derive(Debug, Clone, Default)]
+pub struct SampleCounts(HashMap<BarcodeKey, usize>);
 
 impl SampleCounts {
     /// Returns a `HashMap` of barcodes and their counts
     pub fn count_map(self) -> HashMap<Vec<u8>, usize> {
+        self.into_iter().collect()
+    }
+
+    /// Returns a `HashMap` of packed barcode keys and their counts
+    pub fn key_map(self) -> HashMap<BarcodeKey, usize> {
         self.0
     }
 
+    /// Adds one occurrence of a barcode
+    pub fn count_one(&mut self, barcode: &[u8]) -> () {
+        *self.0.entry(BarcodeKey::new(barcode)).or_insert(0) += 1;
+    }
+
     /// Reads a barcode count table from a file.
     ///
     /// # Arguments
@@ -29,8 +45,188 @@ impl SampleCounts {
     pub fn from_file<P: AsRef<Path> + std::fmt::Debug>(
         filename: P,
     ) -> Result<Self, failure::Error> {
-        Self::read(std::fs::File::open(filename.as_ref())?)
-            .map_err(|e| format_err!("Reading file {:?}: {}", filename, e))
+        Self::from_file_column(filename, None)
+    }
+
+    /// Reads one column of counts from a barcode count table in a file.
+    ///
+    /// Files named `BASE.mtx`, optionally compressed as `.mtx.gz` or
+    /// `.mtx.zst`, are read as Matrix Market tables with `read_mtx()`,
+    /// with barcodes and samples listed in `BASE-barcodes.txt` and
+    /// `BASE-samples.txt`.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` is the name of the file that will be read.
+    /// * `column` is the header of the count column, as in `read_column()`
+    pub fn from_file_column<P: AsRef<Path> + std::fmt::Debug>(
+        filename: P,
+        column: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        if Self::is_mtx_file(&filename) {
+            Self::from_mtx_file(&filename)
+                .and_then(|columns| Self::mtx_column(&columns, column))
+                .map_err(|e| format_err!("Reading file {:?}: {}", filename, e))
+        } else {
+            open_input(&filename.as_ref().to_string_lossy())
+                .map_err(failure::Error::from)
+                .and_then(|input| Self::read_column(input, column))
+                .map_err(|e| format_err!("Reading file {:?}: {}", filename, e))
+        }
+    }
+
+    /// Returns the base name of a Matrix Market table file named
+    /// `BASE.mtx`, `BASE.mtx.gz`, or `BASE.mtx.zst`.
+    pub fn mtx_base(filename: &str) -> Option<&str> {
+        [".mtx", ".mtx.gz", ".mtx.zst"]
+            .iter()
+            .find(|ext| filename.ends_with(*ext))
+            .map(|ext| &filename[..(filename.len() - ext.len())])
+    }
+
+    /// Returns true when `from_file_column()` would read `filename`
+    /// as a Matrix Market table.
+    pub fn is_mtx_file<P: AsRef<Path>>(filename: P) -> bool {
+        filename
+            .as_ref()
+            .to_str()
+            .map_or(false, |name| Self::mtx_base(name).is_some())
+    }
+
+    /// Reads every sample from a Matrix Market table in a file,
+    /// named as in `from_file_column()`, in a single pass. Returns
+    /// the name of each sample along with its counts.
+    pub fn from_mtx_file<P: AsRef<Path> + std::fmt::Debug>(
+        filename: P,
+    ) -> Result<Vec<(String, Self)>, failure::Error> {
+        let base = filename
+            .as_ref()
+            .to_str()
+            .and_then(Self::mtx_base)
+            .ok_or_else(|| format_err!("Matrix file {:?} is not named BASE.mtx", filename))?;
+        let read_names = |suffix: &str| -> Result<Vec<String>, failure::Error> {
+            let names: Result<Vec<String>, std::io::Error> =
+                open_input(&format!("{}{}", base, suffix))?
+                    .lines()
+                    .collect();
+            Ok(names?)
+        };
+        let barcodes = read_names("-barcodes.txt")?;
+        let samples = read_names("-samples.txt")?;
+        let columns =
+            Self::read_mtx_columns(std::fs::File::open(filename.as_ref())?, &barcodes, &samples)?;
+        Ok(samples.into_iter().zip(columns.into_iter()).collect())
+    }
+
+    /// Selects one sample from the samples of a Matrix Market table,
+    /// as read by `from_mtx_file()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `columns` are the names and counts of each sample
+    /// * `sample` is the name of the sample to select, which may be omitted when there is just one
+    pub fn mtx_column(
+        columns: &[(String, Self)],
+        sample: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        let names: Vec<String> = columns.iter().map(|(name, _)| name.to_string()).collect();
+        let sample_col = Self::mtx_sample_col(&names, sample)?;
+        Ok(columns[sample_col].1.clone())
+    }
+
+    fn mtx_sample_col(samples: &[String], sample: Option<&str>) -> Result<usize, failure::Error> {
+        match sample {
+            Some(name) => samples
+                .iter()
+                .position(|s| s == name)
+                .ok_or_else(|| format_err!("No sample {:?} in matrix", name)),
+            None if samples.len() == 1 => Ok(0),
+            None => bail!("Matrix has {} samples and none was selected", samples.len()),
+        }
+    }
+
+    /// Reads and parses one sample from a Matrix Market coordinate
+    /// table of counts, with barcodes as rows and samples as columns.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is an input source that will be read.
+    /// * `barcodes` are the barcodes for each row
+    /// * `samples` are the names of the samples for each column
+    /// * `sample` is the name of the sample to read, which may be omitted when there is just one
+    pub fn read_mtx<R: Read>(
+        input: R,
+        barcodes: &[String],
+        samples: &[String],
+        sample: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        let sample_col = Self::mtx_sample_col(samples, sample)?;
+        Ok(Self::read_mtx_columns(input, barcodes, samples)?.swap_remove(sample_col))
+    }
+
+    /// Reads and parses every sample from a Matrix Market coordinate
+    /// table of counts, returning the counts for each column in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is an input source that will be read.
+    /// * `barcodes` are the barcodes for each row
+    /// * `samples` are the names of the samples for each column
+    pub fn read_mtx_columns<R: Read>(
+        input: R,
+        barcodes: &[String],
+        samples: &[String],
+    ) -> Result<Vec<Self>, failure::Error> {
+        let mut columns = vec![HashMap::new(); samples.len()];
+        let mut has_size = false;
+
+        for (line_no, line_res) in decompress(input)?.lines().enumerate() {
+            let line = line_res?;
+            if line.starts_with('%') {
+                continue;
+            }
+
+            let values = line
+                .split_whitespace()
+                .map(|value| value.parse::<usize>())
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|e| format_err!("Malformed matrix line {}: {}", line_no, e))?;
+            if values.len() != 3 {
+                bail!("Malformed matrix line {}: {:?}", line_no, line);
+            }
+
+            if !has_size {
+                if values[0] != barcodes.len() || values[1] != samples.len() {
+                    bail!(
+                        "Matrix is {} x {} with {} barcodes and {} samples",
+                        values[0],
+                        values[1],
+                        barcodes.len(),
+                        samples.len()
+                    );
+                }
+                has_size = true;
+                continue;
+            }
+
+            let (row, col, count) = (values[0], values[1], values[2]);
+            if row < 1 || row > barcodes.len() || col < 1 || col > samples.len() {
+                bail!("Matrix entry out of bounds line {}: {:?}", line_no, line);
+            }
+            if count == 0 {
+                continue;
+            }
+
+            let barcode = &barcodes[row - 1];
+            if columns[col - 1]
+                .insert(BarcodeKey::new(barcode.as_bytes()), count)
+                .is_some()
+            {
+                bail!("Duplicate entry line {} barcode {}", line_no, barcode);
+            }
+        }
+
+        Ok(columns.into_iter().map(SampleCounts).collect())
     }
 
     /// Reads and parses a barcode count table.
@@ -41,17 +237,66 @@ impl SampleCounts {
     ///
     /// * `input` is an input source that will be read.
     pub fn read<R: Read>(input: R) -> Result<Self, failure::Error> {
+        Self::read_column(input, None)
+    }
+
+    /// Reads and parses one column of counts from a barcode count
+    /// table or matrix.
+    ///
+    /// The first column holds barcodes. A header line is recognized
+    /// when the first field of the first line is `barcode`, as in
+    /// the output of `bc-tabulate` or the neighborhood tables. Counts
+    /// are then taken from the column named `count` if there is one,
+    /// and otherwise from the second column. In long-format tables,
+    /// with a barcode<tab>sample<tab>count header, `column` instead
+    /// selects the sample to read. Lines starting with `#`
+    /// are skipped and compressed input is decompressed.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` is an input source that will be read.
+    /// * `column` is the header of the count column, and requires a header line.
+    pub fn read_column<R: Read>(input: R, column: Option<&str>) -> Result<Self, failure::Error> {
         let mut counts = HashMap::new();
+        let mut count_field = None;
+        let mut has_header = false;
+        let mut long_sample = None;
 
-        for (line_no, line_res) in BufReader::new(input).lines().enumerate() {
+        for (line_no, line_res) in decompress(input)?.lines().enumerate() {
             let line = line_res?;
-            let mut field_iter = line.split("\t");
+            if line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split("\t").collect();
+
+            let count_idx = match count_field {
+                Some(count_idx) => count_idx,
+                None if fields == LONG_HEADER => {
+                    long_sample = Some(column.ok_or_else(|| {
+                        format_err!("Long-format table needs a sample to be selected")
+                    })?);
+                    count_field = Some(2);
+                    has_header = true;
+                    continue;
+                }
+                None => {
+                    let (count_idx, is_header) = Self::find_count_field(&fields, column)?;
+                    count_field = Some(count_idx);
+                    has_header = is_header;
+                    if is_header {
+                        continue;
+                    }
+                    count_idx
+                }
+            };
+
+            if long_sample.map_or(false, |sample| fields.get(1) != Some(&sample)) {
+                continue;
+            }
 
-            let barcode = field_iter
-                .next()
-                .ok_or_else(|| format_err!("Missing barcode line {}", line_no))?;
-            let count = field_iter
-                .next()
+            let barcode = fields[0];
+            let count = fields
+                .get(count_idx)
                 .ok_or_else(|| format_err!("Missing count line {} barcode {}", line_no, barcode))?
                 .parse::<usize>()
                 .map_err(|e| {
@@ -62,7 +307,7 @@ impl SampleCounts {
                         e
                     )
                 })?;
-            if field_iter.next().is_some() {
+            if !has_header && fields.len() > 2 {
                 bail!(
                     "Extra fields after count line {} barcode {}\n{:?}",
                     line_no,
@@ -71,7 +316,10 @@ impl SampleCounts {
                 );
             }
 
-            if counts.insert(barcode.as_bytes().to_vec(), count).is_some() {
+            if counts
+                .insert(BarcodeKey::new(barcode.as_bytes()), count)
+                .is_some()
+            {
                 return Err(format_err!(
                     "Duplicate entry line {} barcode {}",
                     line_no,
@@ -79,9 +327,42 @@ impl SampleCounts {
                 ));
             }
         }
+
         Ok(SampleCounts(counts))
     }
 
+    // Returns the index of the count field and whether the first line
+    // is a header.
+    fn find_count_field(
+        first: &[&str],
+        column: Option<&str>,
+    ) -> Result<(usize, bool), failure::Error> {
+        let is_header = first.first() == Some(&"barcode");
+
+        if let Some(name) = column {
+            if !is_header {
+                bail!("No header for count column {:?} in {:?}", name, first);
+            }
+            return first
+                .iter()
+                .skip(1)
+                .position(|&field| field == name)
+                .map(|idx| (idx + 1, true))
+                .ok_or_else(|| format_err!("No count column {:?} in header {:?}", name, first));
+        }
+
+        if !is_header {
+            Ok((1, false))
+        } else {
+            let count_idx = first
+                .iter()
+                .skip(1)
+                .position(|&field| field == "count")
+                .map_or(1, |idx| idx + 1);
+            Ok((count_idx, true))
+        }
+    }
+
     /// Writes a barcode count table.
     ///
     /// # Arguments
@@ -91,7 +372,7 @@ impl SampleCounts {
         let mut bcout = std::io::BufWriter::new(barcode_out);
 
         for (barcode, count) in self.0.iter() {
-            write!(bcout, "{}\t{}\n", String::from_utf8_lossy(barcode), count)?;
+            write!(bcout, "{}\t{}\n", barcode, count)?;
         }
 
         Ok(())
@@ -134,7 +415,7 @@ impl SampleCounts {
     ///
     /// `barcode` specifies the barcode to look up.
     pub fn barcode_count(&self, barcode: &[u8]) -> usize {
-        self.0.get(barcode).copied().unwrap_or(0)
+        self.0.get(&BarcodeKey::new(barcode)).copied().unwrap_or(0)
     }
 
     /// Returns a vector of counts for a barcode across a set of samples.
@@ -154,6 +435,51 @@ impl SampleCounts {
             .map(|counts| counts.barcode_count(barcode))
             .collect()
     }
+
+    /// Corrects barcodes against a whitelist of trusted barcodes.
+    ///
+    /// Counts for barcodes near a unique whitelisted barcode are added
+    /// to that barcode. Ambiguous and unmatched barcodes are dropped
+    /// from the corrected counts and tallied in the correction
+    /// statistics.
+    ///
+    /// # Arguments
+    ///
+    /// * `whitelist` is the set of trusted barcodes
+    pub fn correct(self, whitelist: &Whitelist) -> (SampleCounts, CorrectionStats) {
+        let mut corrected_counts = HashMap::new();
+        let mut stats = CorrectionStats::default();
+
+        for (barcode, count) in self.0.into_iter() {
+            let correction = whitelist.correct(&barcode.to_vec());
+            stats.add(&correction, count);
+
+            let corrected = match correction {
+                Correction::Exact => barcode,
+                Correction::Corrected(wl_barcode) => BarcodeKey::new(wl_barcode),
+                Correction::Ambiguous | Correction::Unmatched => continue,
+            };
+            *corrected_counts.entry(corrected).or_insert(0) += count;
+        }
+
+        (SampleCounts(corrected_counts), stats)
+    }
+
+    /// Merges the counts for each barcode onto the key barcode of its
+    /// neighborhood.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` maps barcodes to their neighborhood keys, and barcodes with no key are unchanged
+    pub fn collapse(self, keys: &HashMap<BarcodeKey, BarcodeKey>) -> SampleCounts {
+        self.0
+            .into_iter()
+            .map(|(barcode, count)| match keys.get(&barcode) {
+                Some(key) => (key.clone(), count),
+                None => (barcode, count),
+            })
+            .collect()
+    }
 }
 
 impl<'a> Sum<&'a SampleCounts> for SampleCounts {
@@ -164,7 +490,7 @@ impl<'a> Sum<&'a SampleCounts> for SampleCounts {
         let mut total_counts = HashMap::new();
         for SampleCounts(count_map) in iter {
             for (barcode, count) in count_map.iter() {
-                let barcode_count = total_counts.entry(barcode.to_vec()).or_insert(0);
+                let barcode_count = total_counts.entry(barcode.clone()).or_insert(0);
                 *barcode_count += *count;
             }
         }
@@ -172,19 +498,33 @@ impl<'a> Sum<&'a SampleCounts> for SampleCounts {
     }
 }
 
+impl AddAssign for SampleCounts {
+    fn add_assign(&mut self, other: Self) {
+        for (barcode, count) in other.0.into_iter() {
+            *self.0.entry(barcode).or_insert(0) += count;
+        }
+    }
+}
+
 impl IntoIterator for SampleCounts {
     type Item = (Vec<u8>, usize);
-    type IntoIter = ::std::collections::hash_map::IntoIter<Vec<u8>, usize>;
+    type IntoIter = ::std::iter::Map<
+        ::std::collections::hash_map::IntoIter<BarcodeKey, usize>,
+        fn((BarcodeKey, usize)) -> (Vec<u8>, usize),
+    >;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        fn unpack((bc, ct): (BarcodeKey, usize)) -> (Vec<u8>, usize) {
+            (bc.to_vec(), ct)
+        }
+        self.0.into_iter().map(unpack)
     }
 }
 
-impl FromIterator<(Vec<u8>, usize)> for SampleCounts {
+impl FromIterator<(BarcodeKey, usize)> for SampleCounts {
     fn from_iter<I>(iter: I) -> SampleCounts
     where
-        I: IntoIterator<Item = (Vec<u8>, usize)>,
+        I: IntoIterator<Item = (BarcodeKey, usize)>,
     {
         let mut barcode_counts = HashMap::new();
 
@@ -197,6 +537,17 @@ impl FromIterator<(Vec<u8>, usize)> for SampleCounts {
     }
 }
 
+impl FromIterator<(Vec<u8>, usize)> for SampleCounts {
+    fn from_iter<I>(iter: I) -> SampleCounts
+    where
+        I: IntoIterator<Item = (Vec<u8>, usize)>,
+    {
+        iter.into_iter()
+            .map(|(bc, ct)| (BarcodeKey::new(&bc), ct))
+            .collect()
+    }
+}
+
 impl<'a> FromIterator<&'a (Vec<u8>, usize)> for SampleCounts {
     fn from_iter<I>(iter: I) -> SampleCounts
     where
@@ -214,11 +565,9 @@ impl<'a> FromIterator<&'a str> for SampleCounts {
         let mut barcode_counts = HashMap::new();
 
         for bc in iter {
-            if barcode_counts.contains_key(bc.as_bytes()) {
-                *(barcode_counts.get_mut(bc.as_bytes()).unwrap()) += 1;
-            } else {
-                barcode_counts.insert(bc.as_bytes().to_vec(), 1);
-            }
+            *barcode_counts
+                .entry(BarcodeKey::new(bc.as_bytes()))
+                .or_insert(0) += 1;
         }
 
         SampleCounts(barcode_counts)
@@ -233,11 +582,7 @@ impl<'a> FromIterator<&'a [u8]> for SampleCounts {
         let mut barcode_counts = HashMap::new();
 
         for bc in iter {
-            if barcode_counts.contains_key(bc) {
-                *(barcode_counts.get_mut(bc).unwrap()) += 1;
-            } else {
-                barcode_counts.insert(bc.to_vec(), 1);
-            }
+            *barcode_counts.entry(BarcodeKey::new(bc)).or_insert(0) += 1;
         }
 
         SampleCounts(barcode_counts)
@@ -261,11 +606,9 @@ impl FromIterator<fastq::Record> for SampleCounts {
         let mut barcode_counts = HashMap::new();
 
         for rec in iter {
-            if barcode_counts.contains_key(rec.seq()) {
-                *(barcode_counts.get_mut(rec.seq()).unwrap()) += 1;
-            } else {
-                barcode_counts.insert(rec.seq().to_vec(), 1);
-            }
+            *barcode_counts
+                .entry(BarcodeKey::new(rec.seq()))
+                .or_insert(0) += 1;
         }
 
         SampleCounts(barcode_counts)
@@ -289,11 +632,9 @@ impl FromIterator<fasta::Record> for SampleCounts {
         let mut barcode_counts = HashMap::new();
 
         for rec in iter {
-            if barcode_counts.contains_key(rec.seq()) {
-                *(barcode_counts.get_mut(rec.seq()).unwrap()) += 1;
-            } else {
-                barcode_counts.insert(rec.seq().to_vec(), 1);
-            }
+            *barcode_counts
+                .entry(BarcodeKey::new(rec.seq()))
+                .or_insert(0) += 1;
         }
 
         SampleCounts(barcode_counts)
@@ -305,6 +646,7 @@ mod tests {
     extern crate tempfile;
 
     use super::*;
+    use std::io::BufReader;
 
     #[test]
     fn from_iterator() {
@@ -419,6 +761,80 @@ mod tests {
         // Duplicate entries for CAGTA
         let table6 = "TACGGA\t3\nCAGTA\t2\nAATTA\t6\nCAGTA\t7\n";
         assert!(SampleCounts::read(table6.as_bytes()).is_err());
+
+        // Missing file is named in the error
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+        let err = SampleCounts::from_file(&missing).unwrap_err();
+        assert!(err.to_string().contains("missing.txt"));
+    }
+
+    #[test]
+    fn read_header() {
+        let table = "# bc-count output\nbarcode\tcount\nTACGGA\t3\n# comment\nCAGTA\t2\n";
+        let cts = SampleCounts::read(table.as_bytes()).unwrap();
+        assert_eq!(cts.barcode_count(b"TACGGA"), 3);
+        assert_eq!(cts.barcode_count(b"CAGTA"), 2);
+        assert_eq!(cts.into_iter().count(), 2);
+
+        // Neighborhood barcode table, using the count column
+        let nbhd_table = "barcode\tneighborhood\tcount\ttotal\tfraction\n\
+                          TACGGA\tTACGGA\t3\t5\t0.600\n\
+                          TACGGT\tTACGGA\t2\t5\t0.400\n";
+        let cts = SampleCounts::read(nbhd_table.as_bytes()).unwrap();
+        assert_eq!(cts.barcode_count(b"TACGGA"), 3);
+        assert_eq!(cts.barcode_count(b"TACGGT"), 2);
+
+        // Malformed first line rather than a header
+        assert!(SampleCounts::read("TACGGA\t12x\nCAGTA\t2\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn read_matrix_column() {
+        let matrix = "barcode\tsample_a\tsample_b\nTACGGA\t3\t0\nCAGTA\t2\t5\nAATTA\t0\t1\n";
+
+        let cts_a = SampleCounts::read(matrix.as_bytes()).unwrap();
+        let mut cvec_a: Vec<(Vec<u8>, usize)> = cts_a.into_iter().collect();
+        cvec_a.sort();
+        assert_eq!(
+            cvec_a,
+            vec![
+                (b"AATTA".to_vec(), 0),
+                (b"CAGTA".to_vec(), 2),
+                (b"TACGGA".to_vec(), 3)
+            ]
+        );
+
+        let cts_b = SampleCounts::read_column(matrix.as_bytes(), Some("sample_b")).unwrap();
+        let mut cvec_b: Vec<(Vec<u8>, usize)> = cts_b.into_iter().collect();
+        cvec_b.sort();
+        assert_eq!(
+            cvec_b,
+            vec![
+                (b"AATTA".to_vec(), 1),
+                (b"CAGTA".to_vec(), 5),
+                (b"TACGGA".to_vec(), 0)
+            ]
+        );
+
+        assert!(SampleCounts::read_column(matrix.as_bytes(), Some("sample_c")).is_err());
+        assert!(SampleCounts::read_column(matrix.as_bytes(), Some("barcode")).is_err());
+        assert!(SampleCounts::read_column("TACGGA\t3\n".as_bytes(), Some("count")).is_err());
+    }
+
+    #[test]
+    fn read_compressed() {
+        let table = "TACGGA\t3\nCAGTA\t2\nAATTA\t6\n";
+        let mut compressed = Vec::new();
+        let mut out = ::compress::compress(&mut compressed, ::compress::Format::Gzip).unwrap();
+        out.write_all(table.as_bytes()).unwrap();
+        out.finish().unwrap();
+
+        let cts = SampleCounts::read(compressed.as_slice()).unwrap();
+        assert_eq!(
+            cts.count_map(),
+            SampleCounts::read(table.as_bytes()).unwrap().count_map()
+        );
     }
 
     #[test]
@@ -543,4 +959,59 @@ mod tests {
         expmap.insert(b"GTACG".to_vec(), 4);
         assert_eq!(ctmap, expmap);
     }
+
+    #[test]
+    fn correct() {
+        let table = "ACGTACGTAC\t6\nACGTTCGTAC\t2\nACGTACGTA\t1\nTTGCATGCCA\t3\nGGGGGGGGGG\t1\n";
+        let cts = SampleCounts::read(table.as_bytes()).unwrap();
+
+        let wl_table = "ACGTACGTAC\nTTGCATGCAA\nTTGCATGCTA\n";
+        let wl = Whitelist::new(
+            Whitelist::read_barcodes(wl_table.as_bytes()).unwrap(),
+            ::neighbor_index::DistanceMetric::Levenshtein,
+            1,
+        );
+
+        let (corrected, stats) = cts.correct(&wl);
+        let mut cvec: Vec<(Vec<u8>, usize)> = corrected.into_iter().collect();
+        cvec.sort();
+        assert_eq!(cvec, vec![(b"ACGTACGTAC".to_vec(), 9)]);
+        assert_eq!(stats.exact, (1, 6));
+        assert_eq!(stats.corrected, (2, 3));
+        assert_eq!(stats.ambiguous, (1, 3));
+        assert_eq!(stats.unmatched, (1, 1));
+    }
+
+    #[test]
+    fn collapse() {
+        let cts: SampleCounts = vec![
+            (b"ACGTACGT".to_vec(), 5),
+            (b"ACGTACGA".to_vec(), 2),
+            (b"CATGCATG".to_vec(), 3),
+        ]
+        .into_iter()
+        .collect();
+        let keys: HashMap<BarcodeKey, BarcodeKey> = vec![
+            (BarcodeKey::new(b"ACGTACGA"), BarcodeKey::new(b"ACGTACGT")),
+            (BarcodeKey::new(b"ACGTACGT"), BarcodeKey::new(b"ACGTACGT")),
+        ]
+        .into_iter()
+        .collect();
+
+        let collapsed = cts.collapse(&keys);
+        assert_eq!(collapsed.barcode_count(b"ACGTACGT"), 7);
+        assert_eq!(collapsed.barcode_count(b"ACGTACGA"), 0);
+        assert_eq!(collapsed.barcode_count(b"CATGCATG"), 3);
+    }
+
+    #[test]
+    fn add_assign() {
+        let mut cts1 = SampleCounts::read("ACGTA\t3\nCGTAC\t7\n".as_bytes()).unwrap();
+        let cts2 = SampleCounts::read("ACGTA\t4\nTACGT\t5\n".as_bytes()).unwrap();
+        cts1 += cts2;
+        assert_eq!(cts1.barcode_count(b"ACGTA"), 7);
+        assert_eq!(cts1.barcode_count(b"CGTAC"), 7);
+        assert_eq!(cts1.barcode_count(b"TACGT"), 5);
+        assert_eq!(cts1.into_iter().count(), 3);
+    }
 }
@@ -0,0 +1,151 @@
+use std::io::{self, BufRead};
+
+use rayon::prelude::*;
+
+const FASTQ_LINES: usize = 4;
+
+/// Default number of FastQ records in each chunk.
+pub const CHUNK_RECORDS: usize = 16384;
+
+/// Iterator over chunks of raw FastQ text, each holding a whole
+/// number of records, so that parsing can be split across threads.
+///
+/// Records are assumed to have single-line sequences, as in the
+/// `bio::io::fastq` parser.
+pub struct FastqChunks<R> {
+    input: R,
+    chunk_records: usize,
+}
+
+impl<R: BufRead> FastqChunks<R> {
+    pub fn new(input: R, chunk_records: usize) -> Self {
+        FastqChunks {
+            input: input,
+            chunk_records: chunk_records,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FastqChunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = Vec::new();
+
+        for _ in 0..(self.chunk_records * FASTQ_LINES) {
+            match self.input.read_until(b'\n', &mut chunk) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+}
+
+/// Processes a FastQ input in chunks on the current rayon thread
+/// pool and merges the results.
+///
+/// # Arguments
+///
+/// * `input` is the FastQ input source
+/// * `chunk_records` is the number of records in each chunk
+/// * `process` parses and tabulates one chunk of raw FastQ text
+/// * `merge` combines the results from two chunks
+pub fn fold_chunks<R, T, E, F, G>(
+    input: R,
+    chunk_records: usize,
+    process: F,
+    merge: G,
+) -> Result<T, E>
+where
+    R: BufRead,
+    T: Default + Send,
+    E: From<io::Error> + Send,
+    F: Fn(&[u8]) -> Result<T, E> + Sync,
+    G: Fn(T, T) -> T + Sync,
+{
+    fold_chunk_iter(
+        FastqChunks::new(input, chunk_records).map(|chunk| chunk.map_err(E::from)),
+        |chunk: &Vec<u8>| process(chunk),
+        merge,
+    )
+}
+
+/// Processes chunks of input on the current rayon thread pool and
+/// merges the results, as in `fold_chunks()`, for chunks of any kind,
+/// such as paired chunks from two FastQ files or batches of parsed
+/// records.
+///
+/// # Arguments
+///
+/// * `chunks` is an iterator over chunks of input
+/// * `process` tabulates one chunk
+/// * `merge` combines the results from two chunks
+pub fn fold_chunk_iter<I, C, T, E, F, G>(mut chunks: I, process: F, merge: G) -> Result<T, E>
+where
+    I: Iterator<Item = Result<C, E>>,
+    C: Sync,
+    T: Default + Send,
+    E: Send,
+    F: Fn(&C) -> Result<T, E> + Sync,
+    G: Fn(T, T) -> T + Sync,
+{
+    let batch_size = 2 * rayon::current_num_threads();
+    let mut total = T::default();
+
+    loop {
+        let batch = chunks
+            .by_ref()
+            .take(batch_size)
+            .collect::<Result<Vec<C>, E>>()?;
+        if batch.is_empty() {
+            break;
+        }
+
+        let batch_total = batch
+            .par_iter()
+            .map(|chunk| process(chunk))
+            .try_reduce(T::default, |a, b| Ok(merge(a, b)))?;
+        total = merge(total, batch_total);
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FASTQ: &str = "@one\nACGT\n+\n~~~~\n@two\nCGTA\n+\n~~~~\n@three\nGTAC\n+\n~~~~\n";
+
+    #[test]
+    fn chunks() {
+        let chunks: Vec<Vec<u8>> = FastqChunks::new(FASTQ.as_bytes(), 2)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                b"@one\nACGT\n+\n~~~~\n@two\nCGTA\n+\n~~~~\n".to_vec(),
+                b"@three\nGTAC\n+\n~~~~\n".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn fold() {
+        let nrec: Result<usize, io::Error> = fold_chunks(
+            FASTQ.as_bytes(),
+            1,
+            |chunk| Ok(chunk.iter().filter(|&&c| c == b'@').count()),
+            |a, b| a + b,
+        );
+        assert_eq!(nrec.unwrap(), 3);
+    }
+}
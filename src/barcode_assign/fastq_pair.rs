@@ -4,19 +4,127 @@ use bio::io::fastq::Record;
 use std::io;
 use std::io::{Error, ErrorKind};
 
+/// Handling of read pairs whose read IDs do not agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdCheck {
+    /// Fail with an error on the first mismatched pair
+    Fail,
+    /// Count mismatched pairs, reported by `PairRecords::mismatches()`
+    Count,
+    /// Pair reads without comparing IDs
+    Ignore,
+}
+
+impl IdCheck {
+    pub const NAMES: &'static [&'static str] = &["fail", "count", "ignore"];
+}
+
+impl std::str::FromStr for IdCheck {
+    type Err = Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "fail" => Ok(IdCheck::Fail),
+            "count" => Ok(IdCheck::Count),
+            "ignore" => Ok(IdCheck::Ignore),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown read ID check {:?}", s),
+            )),
+        }
+    }
+}
+
+enum PairSource<R: io::Read, S: io::Read> {
+    Separate(bio::io::fastq::Records<R>, bio::io::fastq::Records<S>),
+    Interleaved(bio::io::fastq::Records<R>),
+}
+
 pub struct PairRecords<R: io::Read, S: io::Read> {
-    r1_records: bio::io::fastq::Records<R>,
-    r2_records: bio::io::fastq::Records<S>,
+    source: PairSource<R, S>,
+    id_check: IdCheck,
+    mismatches: usize,
 }
 
 impl<R: io::Read, S: io::Read> PairRecords<R, S> {
+    /// Pairs reads from two FastQ files in the same order without
+    /// comparing read IDs. Use `with_id_check` to check that the IDs
+    /// of each pair agree.
     pub fn new(
         r1_records: bio::io::fastq::Records<R>,
         r2_records: bio::io::fastq::Records<S>,
     ) -> Self {
         PairRecords {
-            r1_records: r1_records,
-            r2_records: r2_records,
+            source: PairSource::Separate(r1_records, r2_records),
+            id_check: IdCheck::Ignore,
+            mismatches: 0,
+        }
+    }
+
+    pub fn with_id_check(self, id_check: IdCheck) -> Self {
+        PairRecords {
+            id_check: id_check,
+            ..self
+        }
+    }
+
+    /// Number of pairs read so far whose read IDs do not agree.
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+}
+
+impl<R: io::Read> PairRecords<R, R> {
+    /// Pairs reads from a single FastQ file with each R1 read
+    /// followed by its R2 read, failing when the read IDs of a pair
+    /// do not agree.
+    pub fn interleaved(records: bio::io::fastq::Records<R>) -> Self {
+        PairRecords {
+            source: PairSource::Interleaved(records),
+            id_check: IdCheck::Fail,
+            mismatches: 0,
+        }
+    }
+}
+
+/// Returns the ID of a read without a trailing `/1` or `/2` mate
+/// number. Illumina comment fields such as `1:N:0:ACGT` follow the ID
+/// in the read description and are not part of the ID.
+pub fn pair_id(id: &str) -> &str {
+    if id.ends_with("/1") || id.ends_with("/2") {
+        &id[..(id.len() - 2)]
+    } else {
+        id
+    }
+}
+
+impl<R: io::BufRead, S: io::BufRead> PairRecords<R, S> {
+    fn next_pair(&mut self) -> Option<io::Result<(Record, Record)>> {
+        match self.source {
+            PairSource::Separate(ref mut r1_records, ref mut r2_records) => {
+                match (r1_records.next(), r2_records.next()) {
+                    (None, None) => None,
+                    (Some(Err(e)), _) => Some(Err(Error::new(ErrorKind::Other, e))),
+                    (_, Some(Err(e))) => Some(Err(Error::new(ErrorKind::Other, e))),
+                    (None, Some(_)) => {
+                        Some(Err(Error::new(ErrorKind::Other, "R1 ended before R2")))
+                    }
+                    (Some(_), None) => {
+                        Some(Err(Error::new(ErrorKind::Other, "R2 ended before R1")))
+                    }
+                    (Some(Ok(r1)), Some(Ok(r2))) => Some(Ok((r1, r2))),
+                }
+            }
+            PairSource::Interleaved(ref mut records) => match (records.next(), records.next()) {
+                (None, _) => None,
+                (Some(Err(e)), _) => Some(Err(Error::new(ErrorKind::Other, e))),
+                (_, Some(Err(e))) => Some(Err(Error::new(ErrorKind::Other, e))),
+                (Some(Ok(r1)), None) => Some(Err(Error::new(
+                    ErrorKind::Other,
+                    format!("No R2 for final interleaved R1 {:?}", r1.id()),
+                ))),
+                (Some(Ok(r1)), Some(Ok(r2))) => Some(Ok((r1, r2))),
+            },
         }
     }
 }
@@ -25,13 +133,96 @@ impl<R: io::BufRead, S: io::BufRead> Iterator for PairRecords<R, S> {
     type Item = io::Result<(Record, Record)>;
 
     fn next(&mut self) -> Option<io::Result<(Record, Record)>> {
-        match (self.r1_records.next(), self.r2_records.next()) {
-            (None, None) => None,
-            (Some(Err(e)), _) => Some(Err(Error::new(ErrorKind::Other, e))),
-            (_, Some(Err(e))) => Some(Err(Error::new(ErrorKind::Other, e))),
-            (None, Some(_)) => Some(Err(Error::new(ErrorKind::Other, "R1 ended before R2"))),
-            (Some(_), None) => Some(Err(Error::new(ErrorKind::Other, "R2 ended before R1"))),
-            (Some(Ok(r1)), Some(Ok(r2))) => Some(Ok((r1, r2))),
+        let (r1, r2) = match self.next_pair()? {
+            Ok(pair) => pair,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if self.id_check != IdCheck::Ignore && pair_id(r1.id()) != pair_id(r2.id()) {
+            self.mismatches += 1;
+            if self.id_check == IdCheck::Fail {
+                return Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Mismatched read IDs {:?} and {:?}", r1.id(), r2.id()),
+                )));
+            }
         }
+
+        Some(Ok((r1, r2)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::io::fastq;
+
+    #[test]
+    fn pair_ids() {
+        assert_eq!(pair_id("read1/1"), "read1");
+        assert_eq!(pair_id("read1/2"), "read1");
+        assert_eq!(pair_id("read1/3"), "read1/3");
+        assert_eq!(
+            pair_id("M00123:1:000:1:1101:15589:1331"),
+            "M00123:1:000:1:1101:15589:1331"
+        );
+    }
+
+    #[test]
+    fn separate_ids() {
+        let r1 = b"@a/1\nAC\n+\nII\n@b/1\nAC\n+\nII\n@c/1\nAC\n+\nII\n";
+        let r2 = b"@a/2 2:N:0:ACGT\nGT\n+\nII\n@c/2\nGT\n+\nII\n@d/2\nGT\n+\nII\n";
+        let pairs = |id_check| {
+            PairRecords::new(
+                fastq::Reader::new(&r1[..]).records(),
+                fastq::Reader::new(&r2[..]).records(),
+            )
+            .with_id_check(id_check)
+        };
+
+        let mut fail = pairs(IdCheck::Fail);
+        let (a1, a2) = fail.next().unwrap().unwrap();
+        assert_eq!((a1.id(), a2.id()), ("a/1", "a/2"));
+        assert!(fail.next().unwrap().is_err());
+
+        let mut count = pairs(IdCheck::Count);
+        assert_eq!(count.by_ref().filter(|res| res.is_ok()).count(), 3);
+        assert_eq!(count.mismatches(), 2);
+
+        let mut ignore = pairs(IdCheck::Ignore);
+        assert_eq!(ignore.by_ref().filter(|res| res.is_ok()).count(), 3);
+        assert_eq!(ignore.mismatches(), 0);
+
+        let unchecked = PairRecords::new(
+            fastq::Reader::new(&r1[..]).records(),
+            fastq::Reader::new(&r2[..]).records(),
+        );
+        assert_eq!(unchecked.filter(|res| res.is_ok()).count(), 3);
+    }
+
+    #[test]
+    fn interleaved() {
+        let reads = b"@a 1:N:0:ACGT\nAC\n+\nII\n@a 2:N:0:ACGT\nGT\n+\nII\n\
+                      @b/1\nCC\n+\nII\n@b/2\nGG\n+\nII\n";
+        let pairs: Vec<_> = PairRecords::interleaved(fastq::Reader::new(&reads[..]).records())
+            .map(|res| {
+                let (r1, r2) = res.unwrap();
+                (r1.seq().to_vec(), r2.seq().to_vec())
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (b"AC".to_vec(), b"GT".to_vec()),
+                (b"CC".to_vec(), b"GG".to_vec())
+            ]
+        );
+
+        let odd = b"@a/1\nAC\n+\nII\n@a/2\nGT\n+\nII\n@b/1\nCC\n+\nII\n";
+        let mut odd_pairs = PairRecords::interleaved(fastq::Reader::new(&odd[..]).records());
+        assert!(odd_pairs.next().unwrap().is_ok());
+        assert!(odd_pairs.next().unwrap().is_err());
+        assert!(odd_pairs.next().is_none());
     }
 }
@@ -0,0 +1,335 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bio::io::fastq;
+
+use barcode_key::BarcodeKey;
+
+/// Sequence record with the key and rank used to sort it. Records
+/// are ordered by key, then by rank, and then by the order in which
+/// they were added to the sort.
+#[derive(Debug, Clone)]
+pub struct KeyedRecord {
+    pub key: BarcodeKey,
+    pub rank: usize,
+    pub record: fastq::Record,
+}
+
+impl KeyedRecord {
+    // Spilled records carry the key as the read name and the rank as
+    // the description, so keys must not contain whitespace.
+    fn to_spill(&self) -> fastq::Record {
+        fastq::Record::with_attrs(
+            &String::from_utf8_lossy(&self.key.to_vec()),
+            Some(&self.rank.to_string()),
+            self.record.seq(),
+            self.record.qual(),
+        )
+    }
+
+    fn from_spill(spill: fastq::Record) -> Result<Self> {
+        let rank = spill
+            .desc()
+            .and_then(|desc| desc.parse().ok())
+            .ok_or_else(|| anyhow!("Bad rank in sorted record {:?}", spill.desc()))?;
+        Ok(KeyedRecord {
+            key: BarcodeKey::new(spill.id().as_bytes()),
+            rank: rank,
+            record: fastq::Record::with_attrs("", None, spill.seq(), spill.qual()),
+        })
+    }
+}
+
+/// Smallest number of records held in memory by an `ExternalSort`.
+/// Smaller chunks would create a temporary file for every handful of
+/// records.
+pub const MIN_CHUNK_RECORDS: usize = 1000;
+
+/// Default number of chunks merged at once by an `ExternalSort`,
+/// which bounds the number of temporary files held open.
+pub const DEFAULT_FAN_IN: usize = 64;
+
+// Sorted chunk in a temporary file. Chunks spilled from memory are at
+// level 0, and a chunk merged from chunks at level `n` is at level
+// `n + 1`.
+struct Chunk {
+    level: usize,
+    file: File,
+}
+
+/// External sort of sequence records, holding a bounded number of
+/// records in memory and spilling sorted chunks to temporary files.
+pub struct ExternalSort {
+    chunk_records: usize,
+    fan_in: usize,
+    temp_dir: PathBuf,
+    buffer: Vec<KeyedRecord>,
+    chunks: Vec<Chunk>,
+}
+
+impl ExternalSort {
+    /// Creates a new, empty sort.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk_records` is the number of records held in memory
+    /// before spilling a sorted chunk, at least `MIN_CHUNK_RECORDS`
+    ///
+    /// * `temp_dir` is the directory for temporary chunk files, or
+    /// the system temporary directory when `None`
+    pub fn new(chunk_records: usize, temp_dir: Option<&str>) -> Result<Self> {
+        if chunk_records < MIN_CHUNK_RECORDS {
+            bail!(
+                "Sort chunk of {} records is below the minimum of {}",
+                chunk_records,
+                MIN_CHUNK_RECORDS
+            );
+        }
+
+        Ok(ExternalSort {
+            chunk_records: chunk_records,
+            fan_in: DEFAULT_FAN_IN,
+            temp_dir: temp_dir.map_or_else(std::env::temp_dir, PathBuf::from),
+            buffer: Vec::new(),
+            chunks: Vec::new(),
+        })
+    }
+
+    /// Sets the number of chunks merged at once, at least 2. Sorts
+    /// with more chunks merge them in several passes.
+    pub fn with_fan_in(self, fan_in: usize) -> Self {
+        ExternalSort {
+            fan_in: fan_in.max(2),
+            ..self
+        }
+    }
+
+    pub fn push(&mut self, keyed: KeyedRecord) -> Result<()> {
+        self.buffer.push(keyed);
+        if self.buffer.len() >= self.chunk_records {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Number of sorted chunks in temporary files so far.
+    pub fn chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.buffer
+            .sort_by(|l, r| (&l.key, l.rank).cmp(&(&r.key, r.rank)));
+
+        let buffer = std::mem::replace(&mut self.buffer, Vec::new());
+        let file = self.write_chunk(buffer.into_iter().map(Ok))?;
+        self.chunks.push(Chunk {
+            level: 0,
+            file: file,
+        });
+
+        // Chunks are ordered by decreasing level, so merging the last
+        // `fan_in` chunks of one level keeps them in the order added.
+        while self.chunks.len() >= self.fan_in {
+            let tail = self.chunks.len() - self.fan_in;
+            let level = self.chunks[tail].level;
+            if self.chunks[tail..].iter().any(|chunk| chunk.level != level) {
+                break;
+            }
+            self.merge_tail(tail, level + 1)?;
+        }
+
+        Ok(())
+    }
+
+    // Merges all chunks from index `tail` onward into one chunk.
+    fn merge_tail(&mut self, tail: usize, level: usize) -> Result<()> {
+        let merging = self.chunks.split_off(tail);
+        let merged = SortedRecords::merge(merging.into_iter().map(|chunk| chunk.file))?;
+        let file = self.write_chunk(merged)?;
+        self.chunks.push(Chunk {
+            level: level,
+            file: file,
+        });
+        Ok(())
+    }
+
+    fn write_chunk<I>(&self, records: I) -> Result<File>
+    where
+        I: Iterator<Item = Result<KeyedRecord>>,
+    {
+        let mut file = tempfile::tempfile_in(&self.temp_dir)
+            .with_context(|| format!("Could not create temporary file in {:?}", self.temp_dir))?;
+        {
+            let mut writer = fastq::Writer::new(&mut file);
+            for keyed in records {
+                writer.write_record(&keyed?.to_spill())?;
+            }
+            writer.flush()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    /// Returns all records in sorted order, merging the chunks in
+    /// temporary files.
+    pub fn into_sorted(mut self) -> Result<SortedRecords> {
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+
+        while self.chunks.len() > self.fan_in {
+            let tail = self.chunks.len() - self.fan_in;
+            let level = self.chunks[tail].level + 1;
+            self.merge_tail(tail, level)?;
+        }
+
+        SortedRecords::merge(self.chunks.into_iter().map(|chunk| chunk.file))
+    }
+}
+
+/// Iterator over records merged from the sorted chunks of an
+/// `ExternalSort`.
+pub struct SortedRecords {
+    chunks: Vec<Box<dyn Iterator<Item = Result<KeyedRecord>>>>,
+    heads: Vec<Option<KeyedRecord>>,
+    heap: BinaryHeap<Reverse<(BarcodeKey, usize, usize)>>,
+}
+
+impl SortedRecords {
+    fn merge<I>(files: I) -> Result<Self>
+    where
+        I: Iterator<Item = File>,
+    {
+        let mut sorted = SortedRecords {
+            chunks: Vec::new(),
+            heads: Vec::new(),
+            heap: BinaryHeap::new(),
+        };
+        for file in files {
+            let records = fastq::Reader::new(file).records().map(|recres| {
+                recres
+                    .map_err(|e| anyhow!(e))
+                    .and_then(KeyedRecord::from_spill)
+            });
+            sorted.chunks.push(Box::new(records));
+            sorted.heads.push(None);
+            sorted.advance(sorted.chunks.len() - 1)?;
+        }
+        Ok(sorted)
+    }
+
+    // Reads the next record from chunk `idx` into its head, breaking
+    // ties between chunks in favor of the earlier chunk.
+    fn advance(&mut self, idx: usize) -> Result<()> {
+        self.heads[idx] = self.chunks[idx].next().transpose()?;
+        if let Some(ref keyed) = self.heads[idx] {
+            self.heap
+                .push(Reverse((keyed.key.clone(), keyed.rank, idx)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SortedRecords {
+    type Item = Result<KeyedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, _, idx)) = self.heap.pop()?;
+        let keyed = self.heads[idx].take()?;
+        match self.advance(idx) {
+            Ok(()) => Some(Ok(keyed)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(key: &[u8], rank: usize, seq: &[u8]) -> KeyedRecord {
+        KeyedRecord {
+            key: BarcodeKey::new(key),
+            rank: rank,
+            record: fastq::Record::with_attrs("", None, seq, &vec![b'I'; seq.len()]),
+        }
+    }
+
+    #[test]
+    fn external_sort() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_path = temp_dir.path().to_string_lossy();
+        assert!(ExternalSort::new(3, Some(&temp_path)).is_err());
+
+        let mut sort = ExternalSort::new(MIN_CHUNK_RECORDS, Some(&temp_path)).unwrap();
+        for (key, rank, seq) in vec![
+            (&b"GGTT"[..], 0, &b"A"[..]),
+            (b"ACGT", 1, b"C"),
+            (b"ACGT", 0, b"G"),
+            (b"GGTT", 0, b"T"),
+            (b"ACGTN", 0, b"AA"),
+            (b"ACGT", 1, b"CC"),
+            (b"CCCC", 0, b"GG"),
+        ] {
+            sort.push(keyed(key, rank, seq)).unwrap();
+        }
+        assert_eq!(sort.chunks(), 0);
+
+        let sorted: Vec<(Vec<u8>, usize, Vec<u8>)> = sort
+            .into_sorted()
+            .unwrap()
+            .map(|res| {
+                let keyed = res.unwrap();
+                (keyed.key.to_vec(), keyed.rank, keyed.record.seq().to_vec())
+            })
+            .collect();
+        assert_eq!(
+            sorted,
+            vec![
+                (b"ACGT".to_vec(), 0, b"G".to_vec()),
+                (b"ACGT".to_vec(), 1, b"C".to_vec()),
+                (b"ACGT".to_vec(), 1, b"CC".to_vec()),
+                (b"ACGTN".to_vec(), 0, b"AA".to_vec()),
+                (b"CCCC".to_vec(), 0, b"GG".to_vec()),
+                (b"GGTT".to_vec(), 0, b"A".to_vec()),
+                (b"GGTT".to_vec(), 0, b"T".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_pass_merge() {
+        let keys: [&[u8]; 5] = [b"GGTT", b"ACGT", b"ACGTN", b"CCCC", b"AAAA"];
+        let records = 10 * MIN_CHUNK_RECORDS + 7;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut sort =
+            ExternalSort::new(MIN_CHUNK_RECORDS, Some(&temp_dir.path().to_string_lossy()))
+                .unwrap()
+                .with_fan_in(2);
+        let mut expected = Vec::new();
+        for i in 0..records {
+            let (key, rank, seq) = (keys[(i * 7) % 5], (i * 3) % 4, i.to_string());
+            sort.push(keyed(key, rank, seq.as_bytes())).unwrap();
+            expected.push((key.to_vec(), rank, seq.into_bytes()));
+        }
+        // Ten spilled chunks merge pairwise into chunks of eight and two
+        assert_eq!(sort.chunks(), 2);
+
+        expected.sort_by(|(keyl, rankl, _), (keyr, rankr, _)| (keyl, rankl).cmp(&(keyr, rankr)));
+        let sorted: Vec<(Vec<u8>, usize, Vec<u8>)> = sort
+            .into_sorted()
+            .unwrap()
+            .map(|res| {
+                let keyed = res.unwrap();
+                (keyed.key.to_vec(), keyed.rank, keyed.record.seq().to_vec())
+            })
+            .collect();
+        assert_eq!(sorted, expected);
+    }
+}
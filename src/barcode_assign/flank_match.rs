@@ -207,6 +207,18 @@ pub struct FlankMatchOut<'a> {
 const DESC_LEN: usize = 10;
 
 impl<'a> FlankMatchOut<'a> {
+    /// Returns `true` if the flanking sequence before the insert
+    /// matched anywhere in the query.
+    pub fn has_before(&self) -> bool {
+        !self.before.is_empty()
+    }
+
+    /// Returns `true` if the flanking sequence after the insert
+    /// matched anywhere in the query.
+    pub fn has_after(&self) -> bool {
+        !self.after.is_empty()
+    }
+
     /// Returns the successful match, or `None` if either the before
     /// or after match failed.
     pub fn flank_match(&self) -> Option<FlankMatch<'a>> {
@@ -355,13 +367,14 @@ mod tests {
 
         let query_perfect = build_query(upstream, before, insert, after, downstream);
         assert_eq!(query_perfect, b"CTAACGTACGTTTAATTAACAGTCAGTAAG");
+        let query_perfect_qual = qual(&query_perfect);
 
         let insert_start = upstream.len() + before.len();
         let insert_end = insert_start + insert.len();
 
         let mut match_spec_a = FlankMatchSpec::new(before, after, 0);
         let match_a = match_spec_a
-            .best_match(&query_perfect)
+            .best_match(&query_perfect, &query_perfect_qual)
             .flank_match()
             .unwrap();
         assert_eq!(match_a.score(), 0);
@@ -374,18 +387,28 @@ mod tests {
 
         let before_mut = b"ACGTTCGT";
         let query_before_mut = build_query(upstream, before_mut, insert, after, downstream);
-        let match_before_mut = match_spec_a.best_match(&query_before_mut).flank_match();
+        let query_before_mut_qual = qual(&query_before_mut);
+        let match_before_mut = match_spec_a
+            .best_match(&query_before_mut, &query_before_mut_qual)
+            .flank_match();
         assert_eq!(match_before_mut, None);
         let mut match_spec_b = FlankMatchSpec::new(before_mut, after, 0);
-        let match_b = match_spec_b.best_match(&query_perfect).flank_match();
+        let match_b = match_spec_b
+            .best_match(&query_perfect, &query_perfect_qual)
+            .flank_match();
         assert_eq!(match_b, None);
 
         let after_mut = b"CACTCAGT";
         let query_after_mut = build_query(upstream, before, insert, after_mut, downstream);
-        let match_after_mut = match_spec_a.best_match(&query_after_mut).flank_match();
+        let query_after_mut_qual = qual(&query_after_mut);
+        let match_after_mut = match_spec_a
+            .best_match(&query_after_mut, &query_after_mut_qual)
+            .flank_match();
         assert_eq!(match_after_mut, None);
         let mut match_spec_c = FlankMatchSpec::new(before, after_mut, 0);
-        let match_c = match_spec_c.best_match(&query_perfect).flank_match();
+        let match_c = match_spec_c
+            .best_match(&query_perfect, &query_perfect_qual)
+            .flank_match();
         assert_eq!(match_c, None);
     }
 
@@ -448,18 +471,27 @@ mod tests {
         insert_start: usize,
         insert_end: usize,
     ) {
+        let query_qual = qual(query);
         let mut match_spec = FlankMatchSpec::new(before, after, max_errors);
-        let match_out = match_spec.best_match(query).flank_match().unwrap();
+        let match_out = match_spec
+            .best_match(query, &query_qual)
+            .flank_match()
+            .unwrap();
         assert_eq!(match_out.insert_start(), insert_start);
         assert_eq!(match_out.insert_end(), insert_end);
     }
 
     fn assert_no_match(query: &[u8], before: &[u8], after: &[u8], max_errors: u8) {
+        let query_qual = qual(query);
         let mut match_spec = FlankMatchSpec::new(before, after, max_errors);
-        let match_out = match_spec.best_match(query).flank_match();
+        let match_out = match_spec.best_match(query, &query_qual).flank_match();
         assert_eq!(match_out, None);
     }
 
+    fn qual(query: &[u8]) -> Vec<u8> {
+        vec![b'I'; query.len()]
+    }
+
     fn build_query(
         upstream: &[u8],
         before: &[u8],
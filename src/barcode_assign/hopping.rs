@@ -0,0 +1,153 @@
+use std::io::Write;
+
+/// Filter for barcode counts that likely arise from index hopping or
+/// cross-contamination from another sample.
+#[derive(Debug, Clone, Copy)]
+pub struct HopFilter {
+    /// A non-zero count is flagged when it is less than this fraction
+    /// of the largest count for the same barcode in another sample
+    pub max_fraction: f64,
+}
+
+impl HopFilter {
+    /// Returns the samples whose counts are flagged for one barcode,
+    /// along with the source sample, which has the largest count.
+    ///
+    /// # Arguments
+    ///
+    /// * `count_vec` is the count of the barcode in each sample
+    pub fn flag(&self, count_vec: &[usize]) -> (Vec<usize>, usize) {
+        let source =
+            count_vec.iter().enumerate().fold(
+                0,
+                |best, (i, &ct)| if ct > count_vec[best] { i } else { best },
+            );
+        let threshold = self.max_fraction * (count_vec[source] as f64);
+
+        let flagged = count_vec
+            .iter()
+            .enumerate()
+            .filter(|&(i, &ct)| i != source && ct > 0 && (ct as f64) < threshold)
+            .map(|(i, _)| i)
+            .collect();
+        (flagged, source)
+    }
+}
+
+/// Tally of flagged counts between each pair of samples, used to
+/// estimate hopping rates.
+#[derive(Debug, Clone)]
+pub struct HopStats {
+    source_reads: Vec<usize>,
+    hopped_reads: Vec<Vec<usize>>,
+    hopped_barcodes: Vec<Vec<usize>>,
+}
+
+impl HopStats {
+    pub fn new(nsamples: usize) -> Self {
+        HopStats {
+            source_reads: vec![0; nsamples],
+            hopped_reads: vec![vec![0; nsamples]; nsamples],
+            hopped_barcodes: vec![vec![0; nsamples]; nsamples],
+        }
+    }
+
+    /// Records one barcode, with the flagged samples and source sample
+    /// returned by `HopFilter::flag`.
+    pub fn add(&mut self, count_vec: &[usize], flagged: &[usize], source: usize) {
+        self.source_reads[source] += count_vec[source];
+        for &dest in flagged.iter() {
+            self.hopped_reads[source][dest] += count_vec[dest];
+            self.hopped_barcodes[source][dest] += 1;
+        }
+    }
+
+    /// Returns the estimated hopping rate from `source` into `dest`,
+    /// the flagged reads in `dest` as a fraction of all reads in
+    /// `source` for barcodes whose largest count is in `source`. The
+    /// rate is 0 when no barcode has its largest count in `source`.
+    pub fn rate(&self, source: usize, dest: usize) -> f64 {
+        if self.source_reads[source] == 0 {
+            0.0
+        } else {
+            (self.hopped_reads[source][dest] as f64) / (self.source_reads[source] as f64)
+        }
+    }
+
+    /// Writes a table of hopping between each ordered pair of
+    /// distinct samples, in the format
+    /// source<tab>dest<tab>source_reads<tab>hopped_barcodes<tab>hopped_reads<tab>rate.
+    pub fn write<W: Write>(&self, out: W, sample_names: &[&str]) -> Result<(), failure::Error> {
+        let mut out = std::io::BufWriter::new(out);
+
+        writeln!(
+            out,
+            "source\tdest\tsource_reads\thopped_barcodes\thopped_reads\trate"
+        )?;
+        for (source, source_name) in sample_names.iter().enumerate() {
+            for (dest, dest_name) in sample_names.iter().enumerate() {
+                if source == dest {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{:0.6}",
+                    source_name,
+                    dest_name,
+                    self.source_reads[source],
+                    self.hopped_barcodes[source][dest],
+                    self.hopped_reads[source][dest],
+                    self.rate(source, dest)
+                )?;
+            }
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag() {
+        let filter = HopFilter { max_fraction: 0.01 };
+        assert_eq!(filter.flag(&[1000, 5, 0, 10, 50]), (vec![1], 0));
+        assert_eq!(filter.flag(&[5, 0, 1000, 10]), (vec![0], 2));
+        assert_eq!(filter.flag(&[20, 30]), (vec![], 1));
+        assert_eq!(filter.flag(&[0, 0]), (vec![], 0));
+    }
+
+    #[test]
+    fn stats() {
+        let filter = HopFilter { max_fraction: 0.01 };
+        let mut stats = HopStats::new(2);
+        for count_vec in vec![[1000, 2], [1000, 0], [3, 500], [40, 60]].iter() {
+            let (flagged, source) = filter.flag(count_vec);
+            stats.add(count_vec, &flagged, source);
+        }
+
+        let mut out = Vec::new();
+        stats.write(&mut out, &["a", "b"]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "source\tdest\tsource_reads\thopped_barcodes\thopped_reads\trate\n\
+             a\tb\t2000\t1\t2\t0.001000\n\
+             b\ta\t560\t1\t3\t0.005357\n"
+        );
+    }
+
+    #[test]
+    fn empty_source() {
+        let filter = HopFilter { max_fraction: 0.01 };
+        let mut stats = HopStats::new(2);
+        let count_vec = [1000, 2];
+        let (flagged, source) = filter.flag(&count_vec);
+        stats.add(&count_vec, &flagged, source);
+
+        assert_eq!(stats.rate(0, 1), 0.002);
+        assert_eq!(stats.rate(1, 0), 0.0);
+    }
+}
@@ -1,14 +1,23 @@
 extern crate anyhow;
 extern crate bio;
 extern crate bio_types;
+extern crate clap;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
+extern crate rayon;
+extern crate regex;
 extern crate rust_htslib;
 extern crate serde;
+extern crate tempfile;
 extern crate toml;
+extern crate zstd;
 
 pub mod assign;
+pub mod assignments;
+pub mod barcode_extract;
 pub mod barcode_group;
+pub mod barcode_key;
 pub mod bc_collapse;
 pub mod bc_count;
 pub mod bc_frag;
@@ -16,13 +25,26 @@ pub mod bc_grna;
 pub mod bc_seqs;
 pub mod bc_tabulate;
 pub mod bc_umi;
+pub mod cli;
+pub mod compress;
+pub mod consensus;
 pub mod counts;
 pub mod depth;
+pub mod fastq_chunks;
 pub mod fastq_pair;
+pub mod fastq_sort;
 pub mod flank_match;
 pub mod frag_purity;
+pub mod hopping;
+pub mod neighbor_index;
 pub mod neighborhood;
+pub mod normalize;
 pub mod pacbio_extract;
 pub mod pacbio_join;
 pub mod pacbio_reads;
 pub mod purity;
+pub mod read_filter;
+pub mod sample_sheet;
+pub mod saturation;
+pub mod umi_source;
+pub mod whitelist;
@@ -0,0 +1,414 @@
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Distance metric between barcodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// Substitutions only, between barcodes of equal length.
+    Hamming,
+    /// Substitutions, insertions, and deletions.
+    Levenshtein,
+}
+
+impl DistanceMetric {
+    /// Returns the distance between `a` and `b`, or `None` if it
+    /// exceeds `max_dist`.
+    pub fn bounded_distance(&self, a: &[u8], b: &[u8], max_dist: usize) -> Option<usize> {
+        match self {
+            DistanceMetric::Hamming => hamming(a, b).filter(|&d| d <= max_dist),
+            DistanceMetric::Levenshtein => bounded_levenshtein(a, b, max_dist),
+        }
+    }
+
+    /// Returns the edits in an optimal alignment of `a` to `b`, or
+    /// `None` if `a` and `b` differ in length under Hamming distance.
+    pub fn edits(&self, a: &[u8], b: &[u8]) -> Option<EditCounts> {
+        match self {
+            DistanceMetric::Hamming => hamming(a, b).map(|d| EditCounts {
+                substitutions: d,
+                ..EditCounts::default()
+            }),
+            DistanceMetric::Levenshtein => Some(levenshtein_edits(a, b)),
+        }
+    }
+}
+
+impl FromStr for DistanceMetric {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hamming" => Ok(DistanceMetric::Hamming),
+            "levenshtein" => Ok(DistanceMetric::Levenshtein),
+            _ => Err(format_err!("Unknown distance metric {:?}", s)),
+        }
+    }
+}
+
+/// Returns the Hamming distance between two sequences, or `None` if
+/// they differ in length.
+pub fn hamming(a: &[u8], b: &[u8]) -> Option<usize> {
+    if a.len() != b.len() {
+        return None;
+    }
+    Some(a.iter().zip(b.iter()).filter(|(x, y)| x != y).count())
+}
+
+/// Returns the Levenshtein distance between two sequences, or `None`
+/// if it exceeds `max_dist`.
+///
+/// Only the band of width `2 * max_dist + 1` around the diagonal is
+/// computed, so the cost is linear in the sequence length.
+pub fn bounded_levenshtein(a: &[u8], b: &[u8], max_dist: usize) -> Option<usize> {
+    if max(a.len(), b.len()) - min(a.len(), b.len()) > max_dist {
+        return None;
+    }
+
+    let over = max_dist + 1;
+    let mut prev: Vec<usize> = (0..=b.len()).map(|j| min(j, over)).collect();
+    let mut curr = vec![over; b.len() + 1];
+
+    for i in 1..=a.len() {
+        let lo = i.saturating_sub(max_dist);
+        let hi = min(b.len(), i + max_dist);
+
+        // Only the band is updated, and the cell just left of it is
+        // read below, so it must not keep a value from an earlier row.
+        let mut row_min = over;
+        if lo == 0 {
+            curr[0] = min(i, over);
+            row_min = curr[0];
+        } else {
+            curr[lo - 1] = over;
+        }
+
+        for j in max(lo, 1)..=hi {
+            let subst = prev[j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let dist = min(subst, min(prev[j], curr[j - 1]) + 1);
+            curr[j] = min(dist, over);
+            row_min = min(row_min, curr[j]);
+        }
+
+        if row_min > max_dist {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+
+    Some(prev[b.len()]).filter(|&d| d <= max_dist)
+}
+
+/// Numbers of each kind of edit in an alignment of one barcode to
+/// another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EditCounts {
+    pub substitutions: usize,
+    /// Bases in the second barcode that are missing from the first
+    pub insertions: usize,
+    /// Bases in the first barcode that are missing from the second
+    pub deletions: usize,
+}
+
+impl EditCounts {
+    pub fn distance(&self) -> usize {
+        self.substitutions + self.insertions + self.deletions
+    }
+}
+
+impl fmt::Display for EditCounts {
+    /// Formats the kinds of edits present, e.g. `sub,del`, or `none`
+    /// for identical barcodes.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kinds: Vec<&str> = vec![
+            ("sub", self.substitutions),
+            ("ins", self.insertions),
+            ("del", self.deletions),
+        ]
+        .into_iter()
+        .filter(|&(_, n)| n > 0)
+        .map(|(kind, _)| kind)
+        .collect();
+
+        if kinds.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", kinds.join(","))
+        }
+    }
+}
+
+/// Returns the edits in a minimum-distance alignment of `a` to `b`,
+/// preferring substitutions over an insertion and deletion pair.
+///
+/// The full dynamic programming matrix is kept for the traceback, so
+/// this is meant for occasional use on short barcodes.
+pub fn levenshtein_edits(a: &[u8], b: &[u8]) -> EditCounts {
+    let width = b.len() + 1;
+    let mut dist = vec![0; (a.len() + 1) * width];
+    for i in 0..=a.len() {
+        dist[i * width] = i;
+    }
+    for j in 0..=b.len() {
+        dist[j] = j;
+    }
+
+    let mismatch = |i: usize, j: usize| if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let subst = dist[(i - 1) * width + j - 1] + mismatch(i, j);
+            let indel = min(dist[(i - 1) * width + j], dist[i * width + j - 1]) + 1;
+            dist[i * width + j] = min(subst, indel);
+        }
+    }
+
+    let mut edits = EditCounts::default();
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 || j > 0 {
+        let d = dist[i * width + j];
+        if i > 0 && j > 0 && d == dist[(i - 1) * width + j - 1] + mismatch(i, j) {
+            edits.substitutions += mismatch(i, j);
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && d == dist[(i - 1) * width + j] + 1 {
+            edits.deletions += 1;
+            i -= 1;
+        } else {
+            edits.insertions += 1;
+            j -= 1;
+        }
+    }
+
+    edits
+}
+
+/// Index for finding all barcodes within a maximum distance of a
+/// query without enumerating the full mutational neighborhood.
+///
+/// Each barcode is split into `max_dist + 1` segments. Any barcode
+/// within `max_dist` edits of a query has at least one segment that
+/// is unchanged, and which therefore occurs exactly in the query
+/// within `max_dist` positions of its own offset. Candidates sharing
+/// a segment are then checked against the full distance.
+pub struct NeighborIndex {
+    barcodes: Vec<Vec<u8>>,
+    segments: HashMap<(usize, usize, Vec<u8>), Vec<usize>>,
+    metric: DistanceMetric,
+    max_dist: usize,
+}
+
+impl NeighborIndex {
+    pub fn new<I>(barcodes: I, metric: DistanceMetric, max_dist: usize) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let barcodes: Vec<Vec<u8>> = barcodes
+            .into_iter()
+            .map(|bc| bc.as_ref().to_vec())
+            .collect();
+        let mut segments = HashMap::new();
+
+        for (bc_idx, bc) in barcodes.iter().enumerate() {
+            for (seg_idx, (start, end)) in Self::segment_bounds(bc.len(), max_dist).enumerate() {
+                segments
+                    .entry((bc.len(), seg_idx, bc[start..end].to_vec()))
+                    .or_insert_with(Vec::new)
+                    .push(bc_idx);
+            }
+        }
+
+        NeighborIndex {
+            barcodes: barcodes,
+            segments: segments,
+            metric: metric,
+            max_dist: max_dist,
+        }
+    }
+
+    /// Returns all indexed barcodes, other than `query` itself, within
+    /// the maximum distance of `query`.
+    pub fn neighbors(&self, query: &[u8]) -> Vec<Vec<u8>> {
+        let mut candidates = Vec::new();
+
+        let (min_len, max_len) = match self.metric {
+            DistanceMetric::Hamming => (query.len(), query.len()),
+            DistanceMetric::Levenshtein => (
+                query.len().saturating_sub(self.max_dist),
+                query.len() + self.max_dist,
+            ),
+        };
+        let max_shift = match self.metric {
+            DistanceMetric::Hamming => 0,
+            DistanceMetric::Levenshtein => self.max_dist as isize,
+        };
+
+        for target_len in min_len..=max_len {
+            for (seg_idx, (start, end)) in
+                Self::segment_bounds(target_len, self.max_dist).enumerate()
+            {
+                for shift in -max_shift..=max_shift {
+                    let query_start = start as isize + shift;
+                    let query_end = end as isize + shift;
+                    if query_start < 0 || query_end > query.len() as isize {
+                        continue;
+                    }
+                    let key = (
+                        target_len,
+                        seg_idx,
+                        query[(query_start as usize)..(query_end as usize)].to_vec(),
+                    );
+                    if let Some(bc_idxs) = self.segments.get(&key) {
+                        candidates.extend_from_slice(bc_idxs);
+                    }
+                }
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        candidates
+            .into_iter()
+            .map(|bc_idx| &self.barcodes[bc_idx])
+            .filter(|bc| bc.as_slice() != query)
+            .filter(|bc| {
+                self.metric
+                    .bounded_distance(query, bc, self.max_dist)
+                    .is_some()
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn segment_bounds(len: usize, max_dist: usize) -> impl Iterator<Item = (usize, usize)> {
+        let nseg = max_dist + 1;
+        (0..nseg).map(move |i| (i * len / nseg, (i + 1) * len / nseg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hamming_distance() {
+        assert_eq!(hamming(b"ACGTACGT", b"ACGTACGT"), Some(0));
+        assert_eq!(hamming(b"ACGTACGT", b"ACCTACGA"), Some(2));
+        assert_eq!(hamming(b"ACGTACGT", b"ACGTACG"), None);
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"ACGTACGT", 2), Some(0));
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"ACGACGT", 2), Some(1));
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"CGTACGTA", 2), Some(2));
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"CGTACGTA", 1), None);
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"TTGTACGA", 3), Some(3));
+        assert_eq!(bounded_levenshtein(b"ACGTACGT", b"TTGTACGA", 2), None);
+        assert_eq!(bounded_levenshtein(b"ACGT", b"ACGTAAA", 2), None);
+        assert_eq!(bounded_levenshtein(b"", b"AC", 2), Some(2));
+
+        // Cells left of the band must not keep values from earlier rows
+        assert_eq!(bounded_levenshtein(b"AA", b"C", 1), None);
+        assert_eq!(bounded_levenshtein(b"CCCC", b"CCA", 1), None);
+        assert_eq!(bounded_levenshtein(b"CACCCC", b"CAA", 3), None);
+        assert_eq!(bounded_levenshtein(b"CACCCC", b"CAA", 4), Some(4));
+    }
+
+    #[test]
+    fn edit_counts() {
+        let lev = DistanceMetric::Levenshtein;
+        assert_eq!(
+            lev.edits(b"ACGTACGT", b"ACGTACGT").unwrap().to_string(),
+            "none"
+        );
+
+        let sub_del = lev.edits(b"ACGTACGT", b"ACCTCGT").unwrap();
+        assert_eq!(sub_del.substitutions, 1);
+        assert_eq!(sub_del.deletions, 1);
+        assert_eq!(sub_del.distance(), 2);
+        assert_eq!(sub_del.to_string(), "sub,del");
+
+        let ins = lev.edits(b"ACGTACGT", b"ACGTTACGT").unwrap();
+        assert_eq!(ins.to_string(), "ins");
+        assert_eq!(lev.edits(b"", b"AC").unwrap().insertions, 2);
+
+        let ham = DistanceMetric::Hamming;
+        assert_eq!(
+            ham.edits(b"ACGTACGT", b"CGTACGTA").unwrap().substitutions,
+            8
+        );
+        assert_eq!(lev.edits(b"ACGTACGT", b"CGTACGTA").unwrap().distance(), 2);
+        assert!(ham.edits(b"ACGTACGT", b"ACGTACG").is_none());
+    }
+
+    #[test]
+    fn index_neighbors() {
+        let barcodes: Vec<Vec<u8>> = vec![
+            b"ACGTACGTACGT".to_vec(),
+            b"ACGTACGAACGT".to_vec(),
+            b"TCGTACGAACGT".to_vec(),
+            b"ACGTCGTACGT".to_vec(),
+            b"ACGTACGTACGTA".to_vec(),
+            b"CGTACGTACGTA".to_vec(),
+            b"TTTTACGTTTTT".to_vec(),
+        ];
+
+        let hamming_index = NeighborIndex::new(barcodes.iter(), DistanceMetric::Hamming, 2);
+        let mut hamming_nbrs = hamming_index.neighbors(b"ACGTACGTACGT");
+        hamming_nbrs.sort();
+        assert_eq!(
+            hamming_nbrs,
+            vec![b"ACGTACGAACGT".to_vec(), b"TCGTACGAACGT".to_vec()]
+        );
+
+        let lev_index = NeighborIndex::new(barcodes.iter(), DistanceMetric::Levenshtein, 2);
+        let mut lev_nbrs = lev_index.neighbors(b"ACGTACGTACGT");
+        lev_nbrs.sort();
+        assert_eq!(
+            lev_nbrs,
+            vec![
+                b"ACGTACGAACGT".to_vec(),
+                b"ACGTACGTACGTA".to_vec(),
+                b"ACGTCGTACGT".to_vec(),
+                b"CGTACGTACGTA".to_vec(),
+                b"TCGTACGAACGT".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn index_matches_exhaustive() {
+        let barcodes: Vec<Vec<u8>> = (0..400u32)
+            .map(|i| {
+                (0..10)
+                    .map(|j| b"ACGT"[((i.wrapping_mul(2654435761) >> (2 * j)) & 3) as usize])
+                    .collect::<Vec<u8>>()
+            })
+            .chain(vec![b"ACGTACGTAC".to_vec(), b"ACGTCGTAC".to_vec()])
+            .collect();
+
+        for &metric in [DistanceMetric::Hamming, DistanceMetric::Levenshtein].iter() {
+            for max_dist in 1..4 {
+                let index = NeighborIndex::new(barcodes.iter(), metric, max_dist);
+                for query in barcodes.iter().take(50) {
+                    let mut exp: Vec<Vec<u8>> = barcodes
+                        .iter()
+                        .filter(|bc| *bc != query)
+                        .filter(|bc| metric.bounded_distance(query, bc, max_dist).is_some())
+                        .cloned()
+                        .collect();
+                    exp.sort();
+                    exp.dedup();
+                    let mut act = index.neighbors(query);
+                    act.sort();
+                    act.dedup();
+                    assert_eq!(act, exp);
+                }
+            }
+        }
+    }
+}
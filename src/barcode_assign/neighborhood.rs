@@ -1,28 +1,218 @@
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{Arg, ArgMatches};
+use rayon::prelude::*;
+
+use barcode_key::BarcodeKey;
+use neighbor_index::*;
+
+/// Method for grouping barcodes into neighborhoods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborhoodMethod {
+    /// Connected components of the near-neighbor graph, chaining
+    /// together any barcodes linked by near-neighbor steps.
+    Connected,
+    /// Directional grouping (as in UMI-tools), where a barcode only
+    /// absorbs neighbors with sufficiently lower counts.
+    Directional,
+    /// Each barcode, in order of decreasing count, absorbs its
+    /// immediate near-neighbors with no chaining.
+    Cluster,
+}
+
+impl FromStr for NeighborhoodMethod {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connected" => Ok(NeighborhoodMethod::Connected),
+            "directional" => Ok(NeighborhoodMethod::Directional),
+            "cluster" => Ok(NeighborhoodMethod::Cluster),
+            _ => Err(format_err!("Unknown neighborhood method {:?}", s)),
+        }
+    }
+}
+
+/// Specification of how barcodes are grouped into neighborhoods.
+#[derive(Debug, Clone)]
+pub struct NeighborhoodSpec {
+    pub method: NeighborhoodMethod,
+    /// For directional grouping, an edge from barcode A to its
+    /// neighbor B is followed only when count(A) >= ratio * count(B) - 1.
+    pub directional_ratio: f64,
+    /// Barcodes within this distance are near-neighbors.
+    pub max_distance: usize,
+    pub metric: DistanceMetric,
+}
+
+impl NeighborhoodSpec {
+    pub const METHODS: &'static [&'static str] = &["connected", "directional", "cluster"];
+    pub const METRICS: &'static [&'static str] = &["levenshtein", "hamming"];
+    pub const DEFAULT_DIRECTIONAL_RATIO: f64 = 2.0;
+    pub const MAX_MAX_DISTANCE: usize = 3;
+
+    /// Parses a neighborhood specification from command-line arguments.
+    pub fn new(
+        method: &str,
+        directional_ratio: &str,
+        max_distance: &str,
+        metric: &str,
+    ) -> Result<Self, failure::Error> {
+        let max_distance = max_distance
+            .parse()
+            .map_err(|e| format_err!("Bad maximum distance {:?}: {}", max_distance, e))?;
+        if max_distance < 1 || max_distance > Self::MAX_MAX_DISTANCE {
+            bail!(
+                "Maximum distance {} must be between 1 and {}",
+                max_distance,
+                Self::MAX_MAX_DISTANCE
+            );
+        }
+
+        Ok(NeighborhoodSpec {
+            method: method.parse()?,
+            directional_ratio: directional_ratio
+                .parse()
+                .map_err(|e| format_err!("Bad directional ratio {:?}: {}", directional_ratio, e))?,
+            max_distance: max_distance,
+            metric: metric.parse()?,
+        })
+    }
+
+    /// Command-line arguments for the neighborhood specification, as
+    /// parsed by `from_matches()`.
+    pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("nbhd-method")
+                .long("neighborhood-method")
+                .value_name("METHOD")
+                .help("Method for grouping barcodes into neighborhoods")
+                .takes_value(true)
+                .possible_values(Self::METHODS)
+                .default_value("connected"),
+            Arg::with_name("directional-ratio")
+                .long("directional-ratio")
+                .value_name("RATIO")
+                .help("Minimum count ratio to join barcodes in directional neighborhoods")
+                .takes_value(true)
+                .default_value("2"),
+            Arg::with_name("max-distance")
+                .long("max-distance")
+                .value_name("DIST")
+                .help("Maximum edit distance between neighboring barcodes")
+                .takes_value(true)
+                .default_value("1"),
+            Arg::with_name("distance-metric")
+                .long("distance-metric")
+                .value_name("METRIC")
+                .help("Edit distance between barcodes, with or without indels")
+                .takes_value(true)
+                .possible_values(Self::METRICS)
+                .default_value("levenshtein"),
+        ]
+    }
+
+    /// Parses a neighborhood specification from the command-line
+    /// arguments in `args()`.
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self, failure::Error> {
+        Self::new(
+            matches.value_of("nbhd-method").unwrap(),
+            matches.value_of("directional-ratio").unwrap(),
+            matches.value_of("max-distance").unwrap(),
+            matches.value_of("distance-metric").unwrap(),
+        )
+    }
+
+    fn directional_edge(&self, from_count: usize, to_count: usize) -> bool {
+        from_count as f64 >= self.directional_ratio * (to_count as f64) - 1.0
+    }
+}
+
+impl Default for NeighborhoodSpec {
+    fn default() -> Self {
+        NeighborhoodSpec {
+            method: NeighborhoodMethod::Connected,
+            directional_ratio: Self::DEFAULT_DIRECTIONAL_RATIO,
+            max_distance: 1,
+            metric: DistanceMetric::Levenshtein,
+        }
+    }
+}
+
+/// Source of near-neighbors for barcodes during neighborhood
+/// gathering.
+///
+/// Single-edit neighbors are enumerated directly, while larger
+/// distances use a `NeighborIndex` over all barcodes.
+struct NeighborFinder {
+    index: Option<NeighborIndex>,
+    metric: DistanceMetric,
+}
+
+impl NeighborFinder {
+    fn new<'a, I>(barcodes: I, spec: &NeighborhoodSpec) -> Self
+    where
+        I: Iterator<Item = &'a BarcodeKey>,
+    {
+        let index = if spec.max_distance > 1 {
+            Some(NeighborIndex::new(
+                barcodes.map(BarcodeKey::to_vec),
+                spec.metric,
+                spec.max_distance,
+            ))
+        } else {
+            None
+        };
+
+        NeighborFinder {
+            index: index,
+            metric: spec.metric,
+        }
+    }
+
+    fn neighbors(&self, barcode: &BarcodeKey) -> Vec<BarcodeKey> {
+        let barcode = barcode.to_vec();
+        let neighbors: Vec<Vec<u8>> = match (&self.index, self.metric) {
+            (Some(index), _) => index.neighbors(&barcode),
+            (None, DistanceMetric::Hamming) => Substitutions::new(&barcode).collect(),
+            (None, DistanceMetric::Levenshtein) => near_neighbors(&barcode).collect(),
+        };
+        neighbors.iter().map(|nbr| BarcodeKey::new(nbr)).collect()
+    }
+}
 
 #[derive(Debug)]
 pub struct Neighborhood<T> {
-    barcodes: Vec<(Vec<u8>, T)>,
+    barcodes: Vec<(BarcodeKey, T)>,
+    /// Barcode from which each member was reached while gathering
+    /// the neighborhood; the starting barcode has no parent.
+    parents: HashMap<BarcodeKey, BarcodeKey>,
 }
 
 impl<T> Neighborhood<T> {
     fn new() -> Self {
         Neighborhood {
             barcodes: Vec::new(),
+            parents: HashMap::new(),
         }
     }
 
-    fn insert(&mut self, barcode: Vec<u8>, value: T) -> () {
+    fn insert(&mut self, barcode: BarcodeKey, value: T) -> () {
         self.barcodes.push((barcode, value));
     }
 
-    pub fn barcodes(&self) -> impl Iterator<Item = &(Vec<u8>, T)> {
+    fn set_parent(&mut self, barcode: &BarcodeKey, parent: &BarcodeKey) {
+        self.parents.insert(barcode.clone(), parent.clone());
+    }
+
+    pub fn barcodes(&self) -> impl Iterator<Item = &(BarcodeKey, T)> {
         self.barcodes.iter()
     }
 
-    pub fn into_barcodes(self) -> impl Iterator<Item = (Vec<u8>, T)> {
+    pub fn into_barcodes(self) -> impl Iterator<Item = (BarcodeKey, T)> {
         self.barcodes.into_iter()
     }
 
@@ -41,7 +231,15 @@ impl<T> Neighborhood<T> {
     //    b. add node to neighborhood
     // 3. Repeat handling nodes from work stack until empty
 
-    pub fn gather_neighborhoods(mut bc_map: HashMap<Vec<u8>, T>) -> Vec<Neighborhood<T>> {
+    pub fn gather_neighborhoods(bc_map: HashMap<BarcodeKey, T>) -> Vec<Neighborhood<T>> {
+        Self::gather_connected(bc_map, &NeighborhoodSpec::default())
+    }
+
+    pub fn gather_connected(
+        mut bc_map: HashMap<BarcodeKey, T>,
+        spec: &NeighborhoodSpec,
+    ) -> Vec<Neighborhood<T>> {
+        let finder = NeighborFinder::new(bc_map.keys(), spec);
         let mut neighborhoods = Vec::new();
 
         loop {
@@ -55,19 +253,71 @@ impl<T> Neighborhood<T> {
                 }
             };
 
-            let start = start_ref.0.to_vec();
+            let start = start_ref.0.clone();
             let value = bc_map.remove(&start).unwrap();
             work_stack.push((start, value));
 
             while work_stack.len() > 0 {
                 let (curr, curr_value) = work_stack.pop().unwrap();
 
-                let neighbors = Substitutions::new(&curr)
-                    .chain(Deletions::new(&curr))
-                    .chain(Insertions::new(&curr));
-                for neighbor in neighbors {
+                for neighbor in finder.neighbors(&curr) {
                     if bc_map.contains_key(&neighbor) {
                         let neighbor_value = bc_map.remove(&neighbor).unwrap();
+                        neighborhood.set_parent(&neighbor, &curr);
+                        work_stack.push((neighbor, neighbor_value));
+                    }
+                }
+
+                neighborhood.insert(curr, curr_value);
+            }
+
+            neighborhoods.push(neighborhood);
+        }
+
+        neighborhoods
+    }
+}
+
+impl<T: CountEntry> Neighborhood<T> {
+    /// Groups barcodes into neighborhoods according to `spec`.
+    pub fn gather(bc_map: HashMap<BarcodeKey, T>, spec: &NeighborhoodSpec) -> Vec<Neighborhood<T>> {
+        match spec.method {
+            NeighborhoodMethod::Connected => Self::gather_connected(bc_map, spec),
+            NeighborhoodMethod::Directional => Self::gather_directional(bc_map, spec),
+            NeighborhoodMethod::Cluster => Self::gather_clusters(bc_map, spec),
+        }
+    }
+
+    // Directional neighborhoods start from the most abundant
+    // remaining barcode and follow near-neighbor edges only from
+    // higher-count to sufficiently lower-count barcodes.
+
+    pub fn gather_directional(
+        mut bc_map: HashMap<BarcodeKey, T>,
+        spec: &NeighborhoodSpec,
+    ) -> Vec<Neighborhood<T>> {
+        let finder = NeighborFinder::new(bc_map.keys(), spec);
+        let mut neighborhoods = Vec::new();
+
+        for start in Self::by_decreasing_count(&bc_map) {
+            let value = match bc_map.remove(&start) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let mut work_stack = vec![(start, value)];
+            let mut neighborhood = Neighborhood::new();
+
+            while let Some((curr, curr_value)) = work_stack.pop() {
+                let curr_count = curr_value.entry_count();
+
+                for neighbor in finder.neighbors(&curr) {
+                    let is_edge = bc_map.get(&neighbor).map_or(false, |neighbor_value| {
+                        spec.directional_edge(curr_count, neighbor_value.entry_count())
+                    });
+                    if is_edge {
+                        let neighbor_value = bc_map.remove(&neighbor).unwrap();
+                        neighborhood.set_parent(&neighbor, &curr);
                         work_stack.push((neighbor, neighbor_value));
                     }
                 }
@@ -80,11 +330,141 @@ impl<T> Neighborhood<T> {
 
         neighborhoods
     }
+
+    // Clusters are the most abundant remaining barcode along with
+    // all of its remaining near-neighbors, without chaining.
+
+    pub fn gather_clusters(
+        mut bc_map: HashMap<BarcodeKey, T>,
+        spec: &NeighborhoodSpec,
+    ) -> Vec<Neighborhood<T>> {
+        let finder = NeighborFinder::new(bc_map.keys(), spec);
+        let mut neighborhoods = Vec::new();
+
+        for start in Self::by_decreasing_count(&bc_map) {
+            let value = match bc_map.remove(&start) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let mut neighborhood = Neighborhood::new();
+
+            for neighbor in finder.neighbors(&start) {
+                if let Some(neighbor_value) = bc_map.remove(&neighbor) {
+                    neighborhood.set_parent(&neighbor, &start);
+                    neighborhood.insert(neighbor, neighbor_value);
+                }
+            }
+
+            neighborhood.insert(start, value);
+            neighborhoods.push(neighborhood);
+        }
+
+        neighborhoods
+    }
+
+    fn by_decreasing_count(bc_map: &HashMap<BarcodeKey, T>) -> Vec<BarcodeKey> {
+        let mut counts: Vec<(usize, &BarcodeKey)> = bc_map
+            .iter()
+            .map(|(bc, value)| (value.entry_count(), bc))
+            .collect();
+        counts.sort_unstable_by(|(ctl, bcl), (ctr, bcr)| ctr.cmp(ctl).then_with(|| bcl.cmp(bcr)));
+        counts.into_iter().map(|(_, bc)| bc.clone()).collect()
+    }
+}
+
+impl<T: CountEntry + Send> Neighborhood<T> {
+    /// Groups barcodes into neighborhoods according to `spec`, using
+    /// the current rayon thread pool.
+    ///
+    /// Near-neighbors of all barcodes are found in parallel and used
+    /// to split the barcodes into connected components. No
+    /// neighborhood spans two components, so the components are then
+    /// grouped in parallel. Components with more than one barcode are
+    /// always re-traversed so that every member has a parent.
+    pub fn gather_parallel(
+        bc_map: HashMap<BarcodeKey, T>,
+        spec: &NeighborhoodSpec,
+    ) -> Vec<Neighborhood<T>> {
+        Self::connected_components(bc_map, spec)
+            .into_par_iter()
+            .flat_map(|component| {
+                if component.len() == 1 {
+                    vec![Neighborhood {
+                        barcodes: component,
+                        parents: HashMap::new(),
+                    }]
+                } else {
+                    Self::gather(component.into_iter().collect(), spec)
+                }
+            })
+            .collect()
+    }
+
+    fn connected_components(
+        bc_map: HashMap<BarcodeKey, T>,
+        spec: &NeighborhoodSpec,
+    ) -> Vec<Vec<(BarcodeKey, T)>> {
+        let barcodes: Vec<(BarcodeKey, T)> = bc_map.into_iter().collect();
+
+        let roots = {
+            let keys: Vec<&BarcodeKey> = barcodes.iter().map(|(bc, _)| bc).collect();
+            let key_index: HashMap<&BarcodeKey, usize> =
+                keys.iter().enumerate().map(|(i, &bc)| (bc, i)).collect();
+            let finder = NeighborFinder::new(keys.iter().cloned(), spec);
+
+            let adjacent: Vec<Vec<usize>> = keys
+                .par_iter()
+                .map(|bc| {
+                    finder
+                        .neighbors(bc)
+                        .iter()
+                        .filter_map(|nbr| key_index.get(nbr).cloned())
+                        .collect()
+                })
+                .collect();
+
+            let mut parents: Vec<usize> = (0..keys.len()).collect();
+            for (i, nbrs) in adjacent.iter().enumerate() {
+                for &j in nbrs.iter() {
+                    let root_i = find_root(&mut parents, i);
+                    let root_j = find_root(&mut parents, j);
+                    parents[root_i] = root_j;
+                }
+            }
+            (0..keys.len())
+                .map(|i| find_root(&mut parents, i))
+                .collect::<Vec<usize>>()
+        };
+
+        let mut component_index = HashMap::new();
+        let mut components = Vec::new();
+        for (entry, root) in barcodes.into_iter().zip(roots.into_iter()) {
+            let idx = *component_index.entry(root).or_insert_with(|| {
+                components.push(Vec::new());
+                components.len() - 1
+            });
+            components[idx].push(entry);
+        }
+
+        components
+    }
+}
+
+fn find_root(parents: &mut Vec<usize>, mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
 }
 
 impl<T: OrdEntry> Neighborhood<T> {
     pub fn into_sorted(self) -> SortedNeighborhood<T> {
-        SortedNeighborhood::new(self.barcodes)
+        SortedNeighborhood {
+            parents: self.parents,
+            ..SortedNeighborhood::new(self.barcodes)
+        }
     }
 }
 
@@ -92,6 +472,24 @@ pub trait OrdEntry {
     fn entry_cmp(&self, other: &Self) -> std::cmp::Ordering;
 }
 
+/// Entries with a count of supporting reads, used to decide the
+/// direction of edges when grouping neighborhoods.
+pub trait CountEntry {
+    fn entry_count(&self) -> usize;
+}
+
+impl CountEntry for usize {
+    fn entry_count(&self) -> usize {
+        *self
+    }
+}
+
+impl<T> CountEntry for Vec<T> {
+    fn entry_count(&self) -> usize {
+        self.len()
+    }
+}
+
 impl OrdEntry for usize {
     fn entry_cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.cmp(other)
@@ -106,15 +504,16 @@ impl<T> OrdEntry for Vec<T> {
 
 #[derive(Debug)]
 pub struct SortedNeighborhood<T> {
-    barcodes: Vec<(Vec<u8>, T)>,
+    barcodes: Vec<(BarcodeKey, T)>,
+    parents: HashMap<BarcodeKey, BarcodeKey>,
 }
 
 impl<T> SortedNeighborhood<T> {
-    pub fn barcodes(&self) -> impl Iterator<Item = &(Vec<u8>, T)> {
+    pub fn barcodes(&self) -> impl Iterator<Item = &(BarcodeKey, T)> {
         self.barcodes.iter()
     }
 
-    pub fn into_barcodes(self) -> impl Iterator<Item = (Vec<u8>, T)> {
+    pub fn into_barcodes(self) -> impl Iterator<Item = (BarcodeKey, T)> {
         self.barcodes.into_iter()
     }
 
@@ -122,11 +521,54 @@ impl<T> SortedNeighborhood<T> {
         self.barcodes.len()
     }
 
-    pub fn key_barcode(&self) -> (&[u8], &T) {
+    pub fn key_barcode(&self) -> (&BarcodeKey, &T) {
         let (keybc, keyct) = self.barcodes.first().unwrap();
         (keybc, keyct)
     }
 
+    /// Returns the barcode from which `barcode` was reached while
+    /// gathering the neighborhood, if any.
+    pub fn parent(&self, barcode: &BarcodeKey) -> Option<&BarcodeKey> {
+        self.parents.get(barcode)
+    }
+
+    /// Maps every member barcode of each neighborhood, including the
+    /// key barcode itself, onto the key barcode of its neighborhood.
+    pub fn key_map<'a, I>(nbhd_iter: I) -> HashMap<BarcodeKey, BarcodeKey>
+    where
+        I: Iterator<Item = &'a SortedNeighborhood<T>>,
+        T: 'a,
+    {
+        let mut keys = HashMap::new();
+        for nbhd in nbhd_iter {
+            let (key, _) = nbhd.key_barcode();
+            for (barcode, _) in nbhd.barcodes() {
+                keys.insert(barcode.clone(), key.clone());
+            }
+        }
+        keys
+    }
+
+    /// Writes a table mapping each member barcode onto the key
+    /// barcode of its neighborhood, which can be read back with
+    /// `read_key_map()`.
+    pub fn write_key_map<'a, I, W>(out: W, nbhd_iter: I) -> Result<(), std::io::Error>
+    where
+        I: Iterator<Item = &'a SortedNeighborhood<T>>,
+        T: 'a,
+        W: Write,
+    {
+        let mut out = std::io::BufWriter::new(out);
+        writeln!(out, "{}", KEY_MAP_HEADER)?;
+        for nbhd in nbhd_iter {
+            let (key, _) = nbhd.key_barcode();
+            for (barcode, _) in nbhd.barcodes() {
+                writeln!(out, "{}\t{}", barcode, key)?;
+            }
+        }
+        out.flush()
+    }
+
     pub fn with_mapped_values<F, U>(&self, func: F) -> SortedNeighborhood<U>
     where
         F: Fn(&T) -> U,
@@ -134,19 +576,28 @@ impl<T> SortedNeighborhood<T> {
         let mapped = self
             .barcodes
             .iter()
-            .map(|(bc, t)| (bc.to_vec(), func(t)))
-            .collect::<Vec<(Vec<u8>, U)>>();
-        SortedNeighborhood { barcodes: mapped }
+            .map(|(bc, t)| (bc.clone(), func(t)))
+            .collect::<Vec<(BarcodeKey, U)>>();
+        SortedNeighborhood {
+            barcodes: mapped,
+            parents: self.parents.clone(),
+        }
     }
 }
 
 impl<T: OrdEntry> SortedNeighborhood<T> {
-    pub fn new(mut barcodes: Vec<(Vec<u8>, T)>) -> Self {
+    pub fn new(mut barcodes: Vec<(BarcodeKey, T)>) -> Self {
         barcodes.sort_unstable_by(Self::cmp_entries);
-        SortedNeighborhood { barcodes: barcodes }
+        SortedNeighborhood {
+            barcodes: barcodes,
+            parents: HashMap::new(),
+        }
     }
 
-    fn cmp_entries((bcl, ctl): &(Vec<u8>, T), (bcr, ctr): &(Vec<u8>, T)) -> std::cmp::Ordering {
+    fn cmp_entries(
+        (bcl, ctl): &(BarcodeKey, T),
+        (bcr, ctr): &(BarcodeKey, T),
+    ) -> std::cmp::Ordering {
         match ctl.entry_cmp(&ctr) {
             std::cmp::Ordering::Less => std::cmp::Ordering::Greater,
             std::cmp::Ordering::Greater => std::cmp::Ordering::Less,
@@ -156,11 +607,20 @@ impl<T: OrdEntry> SortedNeighborhood<T> {
 }
 
 impl SortedNeighborhood<usize> {
+    /// Largest neighborhood whose diameter is computed by `shape()`.
+    pub const MAX_DIAMETER_BARCODES: usize = 1000;
+
     pub fn total(&self) -> usize {
         self.barcodes().map(|(_, ct)| *ct).sum()
     }
 
-    pub fn write_tables<'a, I>(filebase: &str, nbhd_iter: I) -> Result<(), std::io::Error>
+    /// Writes tables of barcodes and of neighborhoods, with
+    /// distances measured according to `spec`.
+    pub fn write_tables<'a, I>(
+        filebase: &str,
+        nbhd_iter: I,
+        spec: &NeighborhoodSpec,
+    ) -> Result<(), std::io::Error>
     where
         I: Iterator<Item = &'a SortedNeighborhood<usize>>,
     {
@@ -177,30 +637,44 @@ impl SortedNeighborhood<usize> {
         writeln!(nbhds_out, "{}", SortedNeighborhood::nbhd_counts_header())?;
 
         for nbhd in nbhd_iter {
-            nbhd.write_barcode_counts(&mut barcodes_out)?;
-            nbhd.write_nbhd_counts(&mut nbhds_out)?;
+            nbhd.write_barcode_counts(&mut barcodes_out, spec)?;
+            nbhd.write_nbhd_counts(&mut nbhds_out, spec)?;
         }
 
         Ok(())
     }
 
     pub fn barcode_counts_header() -> &'static str {
-        "barcode\tneighborhood\tcount\ttotal\tfraction"
+        "barcode\tneighborhood\tcount\ttotal\tfraction\tdistance\tedits\tparent"
     }
 
-    pub fn write_barcode_counts<W: Write>(&self, out: &mut W) -> Result<(), std::io::Error> {
+    /// Writes one line per member barcode, including its edit
+    /// distance and the kinds of edits from the key barcode, and its
+    /// parent in the traversal that gathered the neighborhood (`-`
+    /// for the starting barcode).
+    pub fn write_barcode_counts<W: Write>(
+        &self,
+        out: &mut W,
+        spec: &NeighborhoodSpec,
+    ) -> Result<(), std::io::Error> {
         let (keybc, _keyct) = self.key_barcode();
+        let key = keybc.to_vec();
         let total = self.total();
 
         for (bc, ct) in self.barcodes() {
+            let edits = spec.metric.edits(&key, &bc.to_vec());
             write!(
                 out,
-                "{}\t{}\t{}\t{}\t{:0.3}\n",
-                String::from_utf8_lossy(bc),
-                String::from_utf8_lossy(keybc),
+                "{}\t{}\t{}\t{}\t{:0.3}\t{}\t{}\t{}\n",
+                bc,
+                keybc,
                 ct,
                 total,
-                (*ct as f64) / (total as f64)
+                (*ct as f64) / (total as f64),
+                edits.map_or("NA".to_string(), |e| e.distance().to_string()),
+                edits.map_or("NA".to_string(), |e| e.to_string()),
+                self.parent(bc)
+                    .map_or("-".to_string(), BarcodeKey::to_string)
             )?;
         }
 
@@ -208,24 +682,104 @@ impl SortedNeighborhood<usize> {
     }
 
     pub fn nbhd_counts_header() -> &'static str {
-        "neighborhood\tnum_barcodes\ttotal\tnkey\tfract_nbhd"
+        "neighborhood\tnum_barcodes\ttotal\tnkey\tfract_nbhd\tdiameter\tlocal_maxima"
     }
 
-    pub fn write_nbhd_counts<W: Write>(&self, out: &mut W) -> Result<(), std::io::Error> {
+    /// Writes one line summarizing the neighborhood, including its
+    /// diameter and number of local maxima as computed by `shape()`.
+    pub fn write_nbhd_counts<W: Write>(
+        &self,
+        out: &mut W,
+        spec: &NeighborhoodSpec,
+    ) -> Result<(), std::io::Error> {
         let (keybc, keyct) = self.key_barcode();
         let total = self.total();
+        let (diameter, local_maxima) = self.shape(spec);
 
         write!(
             out,
-            "{}\t{}\t{}\t{}\t{:0.3}\n",
-            String::from_utf8_lossy(keybc),
+            "{}\t{}\t{}\t{}\t{:0.3}\t{}\t{}\n",
+            keybc,
             self.len(),
             total,
             *keyct,
-            (*keyct as f64) / (total as f64)
+            (*keyct as f64) / (total as f64),
+            diameter.map_or("NA".to_string(), |d| d.to_string()),
+            local_maxima
         )
     }
 
+    /// Returns the diameter of the neighborhood, the largest number
+    /// of near-neighbor steps between any two members, and its number
+    /// of local maxima, members with no higher-count near-neighbor.
+    /// Near-neighbors are found as in gathering with `spec`.
+    ///
+    /// The diameter needs a search from every member, so it is `None`
+    /// for neighborhoods larger than `MAX_DIAMETER_BARCODES`.
+    pub fn shape(&self, spec: &NeighborhoodSpec) -> (Option<usize>, usize) {
+        let members: HashMap<&BarcodeKey, usize> = self
+            .barcodes()
+            .enumerate()
+            .map(|(i, (bc, _ct))| (bc, i))
+            .collect();
+        let finder = NeighborFinder::new(self.barcodes().map(|(bc, _ct)| bc), spec);
+        let edges: Vec<Vec<usize>> = self
+            .barcodes()
+            .enumerate()
+            .map(|(i, (bc, _ct))| {
+                finder
+                    .neighbors(bc)
+                    .iter()
+                    .filter_map(|nbr| members.get(nbr).cloned())
+                    .filter(|&j| j != i)
+                    .collect()
+            })
+            .collect();
+
+        let counts: Vec<usize> = self.barcodes().map(|(_bc, ct)| *ct).collect();
+        let local_maxima = edges
+            .iter()
+            .enumerate()
+            .filter(|(i, nbrs)| nbrs.iter().all(|&j| counts[j] <= counts[*i]))
+            .count();
+
+        let diameter = if self.len() <= Self::MAX_DIAMETER_BARCODES {
+            Some(
+                (0..self.len())
+                    .map(|start| Self::eccentricity(&edges, start))
+                    .max()
+                    .unwrap_or(0),
+            )
+        } else {
+            None
+        };
+
+        (diameter, local_maxima)
+    }
+
+    // Largest number of steps from `start` to any member it reaches,
+    // by breadth-first search.
+    fn eccentricity(edges: &[Vec<usize>], start: usize) -> usize {
+        let mut steps = vec![None; edges.len()];
+        steps[start] = Some(0);
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+
+        let mut eccentricity = 0;
+        while let Some(curr) = queue.pop_front() {
+            let curr_steps = steps[curr].unwrap_or(0);
+            eccentricity = std::cmp::max(eccentricity, curr_steps);
+            for &nbr in edges[curr].iter() {
+                if steps[nbr].is_none() {
+                    steps[nbr] = Some(curr_steps + 1);
+                    queue.push_back(nbr);
+                }
+            }
+        }
+
+        eccentricity
+    }
+
     pub fn output_filename(output_base: &str, name: &str) -> PathBuf {
         let base_ref: &Path = output_base.as_ref();
         let mut namebase = base_ref
@@ -240,17 +794,65 @@ impl<T> SortedNeighborhood<Vec<T>> {
     pub fn to_counts(&self) -> SortedNeighborhood<usize> {
         let mut barcode_counts = Vec::new();
         for (bc, ents) in self.barcodes.iter() {
-            barcode_counts.push((bc.to_vec(), ents.len()));
+            barcode_counts.push((bc.clone(), ents.len()));
         }
         SortedNeighborhood {
             barcodes: barcode_counts,
+            parents: self.parents.clone(),
         }
     }
 }
 
+const KEY_MAP_HEADER: &str = "barcode\tkey";
+
+/// Reads a table mapping barcodes onto neighborhood keys, as written
+/// by `SortedNeighborhood::write_key_map()`.
+///
+/// The header line, blank lines, and `#` comments are skipped. A
+/// barcode may appear more than once only if it maps to the same key
+/// each time.
+pub fn read_key_map<R: BufRead>(
+    input: R,
+) -> Result<HashMap<BarcodeKey, BarcodeKey>, failure::Error> {
+    let mut keys = HashMap::new();
+
+    for (line_no, line_res) in input.lines().enumerate() {
+        let line = line_res?;
+        if line.is_empty() || line.starts_with('#') || line == KEY_MAP_HEADER {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 2 {
+            bail!("Expected barcode and key on line {}: {:?}", line_no, line);
+        }
+
+        let key = BarcodeKey::new(fields[1].as_bytes());
+        if let Some(previous) = keys.insert(BarcodeKey::new(fields[0].as_bytes()), key.clone()) {
+            if previous != key {
+                bail!(
+                    "Conflicting keys {} and {} line {} barcode {}",
+                    previous,
+                    key,
+                    line_no,
+                    fields[0]
+                );
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
 // Switch to an interface where mutations (acting on a slice buffer)
 // are returned to avoid allocation.
 
+fn near_neighbors<'a>(original: &'a [u8]) -> impl Iterator<Item = Vec<u8>> + 'a {
+    Substitutions::new(original)
+        .chain(Deletions::new(original))
+        .chain(Insertions::new(original))
+}
+
 const NTS_LEN: usize = 4;
 static NTS: [u8; NTS_LEN] = [b'A', b'C', b'G', b'T'];
 
@@ -373,17 +975,17 @@ mod tests {
 
     fn vec_count_map<V: AsRef<[u8]>, I: IntoIterator<Item = (V, usize)>>(
         bc_counts: I,
-    ) -> HashMap<Vec<u8>, usize> {
+    ) -> HashMap<BarcodeKey, usize> {
         let mut ctmap = HashMap::new();
         for (bc, ct) in bc_counts {
-            if ctmap.insert(bc.as_ref().to_vec(), ct).is_some() {
+            if ctmap.insert(BarcodeKey::new(bc.as_ref()), ct).is_some() {
                 panic!("Duplicate barcode {}", String::from_utf8_lossy(bc.as_ref()));
             }
         }
         ctmap
     }
 
-    fn nbhd_map<T: Clone, N: Borrow<Neighborhood<T>>>(nbhd: N) -> HashMap<Vec<u8>, T> {
+    fn nbhd_map<T: Clone, N: Borrow<Neighborhood<T>>>(nbhd: N) -> HashMap<BarcodeKey, T> {
         nbhd.borrow().barcodes().map(|pair| pair.clone()).collect()
     }
 
@@ -398,8 +1000,8 @@ mod tests {
 
         let nbhds = Neighborhood::gather_neighborhoods(vec_count_map(count_vec.clone()));
         assert_eq!(nbhds.len(), 1);
-        let exp_nbhd: HashMap<Vec<u8>, usize> = vec_count_map(count_vec.clone());
-        let act_nbhd: HashMap<Vec<u8>, usize> = nbhd_map(&nbhds[0]);
+        let exp_nbhd: HashMap<BarcodeKey, usize> = vec_count_map(count_vec.clone());
+        let act_nbhd: HashMap<BarcodeKey, usize> = nbhd_map(&nbhds[0]);
         assert_eq!(exp_nbhd, act_nbhd);
     }
 
@@ -437,7 +1039,7 @@ GTACGCACG	9
 GTACGCATCG	6"#;
         let count_map = SampleCounts::read(count_table.as_bytes())
             .unwrap()
-            .count_map();
+            .key_map();
 
         let nbhds = Neighborhood::gather_neighborhoods(count_map);
         assert_eq!(nbhds.len(), 3);
@@ -463,7 +1065,7 @@ GTACGCATCG	6"#;
         let mut act: Vec<Vec<Vec<u8>>> = nbhds
             .iter()
             .map(|n| {
-                let mut n_act: Vec<Vec<u8>> = n.barcodes().map(|(bc, _ct)| bc.clone()).collect();
+                let mut n_act: Vec<Vec<u8>> = n.barcodes().map(|(bc, _ct)| bc.to_vec()).collect();
                 n_act.sort();
                 n_act
             })
@@ -472,4 +1074,263 @@ GTACGCATCG	6"#;
 
         assert_eq!(act, exp);
     }
+
+    fn sorted_members(nbhds: &Vec<Neighborhood<usize>>) -> Vec<Vec<Vec<u8>>> {
+        let mut act: Vec<Vec<Vec<u8>>> = nbhds
+            .iter()
+            .map(|n| {
+                let mut n_act: Vec<Vec<u8>> = n.barcodes().map(|(bc, _ct)| bc.to_vec()).collect();
+                n_act.sort();
+                n_act
+            })
+            .collect();
+        act.sort();
+        act
+    }
+
+    // Two abundant barcodes, ACGTACGT and ACGAACGA, joined by a chain
+    // of rare error barcodes.
+    fn chained_counts() -> HashMap<BarcodeKey, usize> {
+        vec_count_map(vec![
+            (b"ACGTACGT", 100),
+            (b"ACGAACGT", 3),
+            (b"ACGAACGA", 80),
+            (b"ACGTACGA", 1),
+            (b"TCGTACGT", 10),
+        ])
+    }
+
+    #[test]
+    fn directional_nbhds() {
+        let spec = NeighborhoodSpec {
+            method: NeighborhoodMethod::Directional,
+            directional_ratio: 2.0,
+            ..NeighborhoodSpec::default()
+        };
+
+        let nbhds = Neighborhood::gather(chained_counts(), &spec);
+        assert_eq!(
+            sorted_members(&nbhds),
+            vec![
+                vec![b"ACGAACGA".to_vec()],
+                vec![
+                    b"ACGAACGT".to_vec(),
+                    b"ACGTACGA".to_vec(),
+                    b"ACGTACGT".to_vec(),
+                    b"TCGTACGT".to_vec()
+                ],
+            ]
+        );
+
+        let connected = Neighborhood::gather(chained_counts(), &NeighborhoodSpec::default());
+        assert_eq!(connected.len(), 1);
+    }
+
+    #[test]
+    fn directional_ratio() {
+        let spec = NeighborhoodSpec {
+            method: NeighborhoodMethod::Directional,
+            directional_ratio: 20.0,
+            ..NeighborhoodSpec::default()
+        };
+
+        let nbhds = Neighborhood::gather(chained_counts(), &spec);
+        assert_eq!(
+            sorted_members(&nbhds),
+            vec![
+                vec![b"ACGAACGA".to_vec()],
+                vec![
+                    b"ACGAACGT".to_vec(),
+                    b"ACGTACGA".to_vec(),
+                    b"ACGTACGT".to_vec()
+                ],
+                vec![b"TCGTACGT".to_vec()],
+            ]
+        );
+    }
+
+    #[test]
+    fn cluster_nbhds() {
+        let spec = NeighborhoodSpec {
+            method: NeighborhoodMethod::Cluster,
+            ..NeighborhoodSpec::default()
+        };
+
+        let count_map = vec_count_map(vec![
+            (b"ACGTACGT", 5),
+            (b"ACGTTCGT", 3),
+            (b"ACATACGT", 2),
+            (b"ACATATGT", 1),
+        ]);
+
+        let nbhds = Neighborhood::gather(count_map, &spec);
+        assert_eq!(
+            sorted_members(&nbhds),
+            vec![
+                vec![
+                    b"ACATACGT".to_vec(),
+                    b"ACGTACGT".to_vec(),
+                    b"ACGTTCGT".to_vec()
+                ],
+                vec![b"ACATATGT".to_vec()],
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_spec() {
+        let spec = NeighborhoodSpec::new("directional", "3.5", "2", "hamming").unwrap();
+        assert_eq!(spec.method, NeighborhoodMethod::Directional);
+        assert_eq!(spec.directional_ratio, 3.5);
+        assert_eq!(spec.max_distance, 2);
+        assert_eq!(spec.metric, DistanceMetric::Hamming);
+        assert!(NeighborhoodSpec::new("adjacency", "2", "1", "levenshtein").is_err());
+        assert!(NeighborhoodSpec::new("connected", "two", "1", "levenshtein").is_err());
+        assert!(NeighborhoodSpec::new("connected", "2", "0", "levenshtein").is_err());
+        assert!(NeighborhoodSpec::new("connected", "2", "5", "levenshtein").is_err());
+        assert!(NeighborhoodSpec::new("connected", "2", "1", "jaccard").is_err());
+    }
+
+    #[test]
+    fn distance_two_nbhds() {
+        let count_map = vec_count_map(vec![
+            (b"ACGTACGTACGT", 20),
+            (b"ACGTACGAACTT", 4),
+            (b"CGTACGTACGTA", 3),
+            (b"TTGTACGTACGT", 2),
+            (b"CATGCATGCATG", 9),
+        ]);
+
+        let one = Neighborhood::gather(count_map.clone(), &NeighborhoodSpec::default());
+        assert_eq!(one.len(), 5);
+
+        let lev_spec = NeighborhoodSpec {
+            max_distance: 2,
+            ..NeighborhoodSpec::default()
+        };
+        let lev = Neighborhood::gather(count_map.clone(), &lev_spec);
+        assert_eq!(
+            sorted_members(&lev),
+            vec![
+                vec![
+                    b"ACGTACGAACTT".to_vec(),
+                    b"ACGTACGTACGT".to_vec(),
+                    b"CGTACGTACGTA".to_vec(),
+                    b"TTGTACGTACGT".to_vec()
+                ],
+                vec![b"CATGCATGCATG".to_vec()],
+            ]
+        );
+
+        let ham_spec = NeighborhoodSpec {
+            max_distance: 2,
+            metric: DistanceMetric::Hamming,
+            ..NeighborhoodSpec::default()
+        };
+        let ham = Neighborhood::gather(count_map, &ham_spec);
+        assert_eq!(
+            sorted_members(&ham),
+            vec![
+                vec![
+                    b"ACGTACGAACTT".to_vec(),
+                    b"ACGTACGTACGT".to_vec(),
+                    b"TTGTACGTACGT".to_vec()
+                ],
+                vec![b"CATGCATGCATG".to_vec()],
+                vec![b"CGTACGTACGTA".to_vec()],
+            ]
+        );
+    }
+
+    #[test]
+    fn nbhd_tables() {
+        let spec = NeighborhoodSpec {
+            method: NeighborhoodMethod::Directional,
+            ..NeighborhoodSpec::default()
+        };
+        let count_map = vec_count_map(vec![
+            (&b"ACGTACGT"[..], 100),
+            (b"ACGTACGA", 10),
+            (b"ACGTACAA", 2),
+            (b"CGTACGT", 30),
+        ]);
+
+        let nbhds = Neighborhood::gather(count_map, &spec);
+        assert_eq!(nbhds.len(), 1);
+        let nbhd = nbhds.into_iter().next().unwrap().into_sorted();
+
+        let mut barcodes_out = Vec::new();
+        nbhd.write_barcode_counts(&mut barcodes_out, &spec).unwrap();
+        assert_eq!(
+            String::from_utf8(barcodes_out).unwrap(),
+            "ACGTACGT\tACGTACGT\t100\t142\t0.704\t0\tnone\t-\n\
+             CGTACGT\tACGTACGT\t30\t142\t0.211\t1\tdel\tACGTACGT\n\
+             ACGTACGA\tACGTACGT\t10\t142\t0.070\t1\tsub\tACGTACGT\n\
+             ACGTACAA\tACGTACGT\t2\t142\t0.014\t2\tsub\tACGTACGA\n"
+        );
+
+        let mut nbhds_out = Vec::new();
+        nbhd.write_nbhd_counts(&mut nbhds_out, &spec).unwrap();
+        assert_eq!(
+            String::from_utf8(nbhds_out).unwrap(),
+            "ACGTACGT\t4\t142\t100\t0.704\t3\t1\n"
+        );
+
+        let wide_spec = NeighborhoodSpec {
+            max_distance: 2,
+            ..spec.clone()
+        };
+        assert_eq!(nbhd.shape(&wide_spec), (Some(2), 1));
+    }
+
+    #[test]
+    fn key_map() {
+        let nbhds: Vec<_> = Neighborhood::gather(chained_counts(), &NeighborhoodSpec::default())
+            .into_iter()
+            .map(|n| n.into_sorted())
+            .collect();
+        let keys = SortedNeighborhood::key_map(nbhds.iter());
+        assert_eq!(keys.len(), chained_counts().len());
+
+        let mut key_map_out = Vec::new();
+        SortedNeighborhood::write_key_map(&mut key_map_out, nbhds.iter()).unwrap();
+        let key_map_text = String::from_utf8(key_map_out).unwrap();
+        assert!(key_map_text.starts_with("barcode\tkey\n"));
+        assert_eq!(read_key_map(key_map_text.as_bytes()).unwrap(), keys);
+
+        let conflict = "barcode\tkey\nACGTACGA\tACGTACGT\nACGTACGA\tCATGCATG\n";
+        assert!(read_key_map(conflict.as_bytes()).is_err());
+        assert!(read_key_map("ACGTACGA\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parallel_nbhds() {
+        let mut count_map = chained_counts();
+        count_map.extend(vec_count_map(vec![
+            (&b"CATGCATG"[..], 7),
+            (b"CATGCATGA", 2),
+            (b"CTTGCATG", 4),
+            (b"GGGGCCCC", 1),
+        ]));
+
+        for &method in [
+            NeighborhoodMethod::Connected,
+            NeighborhoodMethod::Directional,
+            NeighborhoodMethod::Cluster,
+        ]
+        .iter()
+        {
+            for max_distance in 1..3 {
+                let spec = NeighborhoodSpec {
+                    method: method,
+                    max_distance: max_distance,
+                    ..NeighborhoodSpec::default()
+                };
+                assert_eq!(
+                    sorted_members(&Neighborhood::gather_parallel(count_map.clone(), &spec)),
+                    sorted_members(&Neighborhood::gather(count_map.clone(), &spec))
+                );
+            }
+        }
+    }
 }
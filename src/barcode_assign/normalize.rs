@@ -0,0 +1,229 @@
+use std::str::FromStr;
+
+const PER_MILLION: f64 = 1e6;
+const UPPER_QUARTILE: f64 = 0.75;
+
+/// Method for normalizing barcode counts across samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormMethod {
+    /// Counts per million reads in the sample.
+    Cpm,
+    /// Scaling by the upper quartile of non-zero barcode counts in
+    /// each sample, relative to the geometric mean across samples.
+    UpperQuartile,
+    /// DESeq-style median of ratios to the geometric mean count of
+    /// each barcode across samples, using barcodes present in every
+    /// sample.
+    MedianRatio,
+}
+
+impl NormMethod {
+    pub const METHODS: &'static [&'static str] = &["cpm", "upper-quartile", "median-ratio"];
+
+    /// Computes a size factor for each sample, such that normalized
+    /// counts are raw counts divided by the size factor.
+    ///
+    /// # Arguments
+    ///
+    /// * `count_vecs` is an iterator over the counts for each barcode
+    /// across all samples
+    /// * `nsamples` is the number of samples
+    pub fn size_factors<'a, I>(
+        &self,
+        count_vecs: I,
+        nsamples: usize,
+    ) -> Result<Vec<f64>, failure::Error>
+    where
+        I: Iterator<Item = &'a [usize]>,
+    {
+        let factors: Vec<f64> = match self {
+            NormMethod::Cpm => {
+                let mut totals: Vec<usize> = vec![0; nsamples];
+                for count_vec in count_vecs {
+                    for (total, count) in totals.iter_mut().zip(count_vec.iter()) {
+                        *total += count;
+                    }
+                }
+                totals
+                    .into_iter()
+                    .map(|total| (total as f64) / PER_MILLION)
+                    .collect()
+            }
+            NormMethod::UpperQuartile => {
+                let mut sample_counts = vec![Vec::new(); nsamples];
+                for count_vec in count_vecs {
+                    for (counts, &count) in sample_counts.iter_mut().zip(count_vec.iter()) {
+                        if count > 0 {
+                            counts.push(count);
+                        }
+                    }
+                }
+                let quartiles: Vec<f64> = sample_counts
+                    .into_iter()
+                    .map(|mut counts| {
+                        counts.sort();
+                        upper_quartile(&counts)
+                    })
+                    .collect();
+                let scale = geometric_mean(quartiles.iter().cloned());
+                quartiles.into_iter().map(|uq| uq / scale).collect()
+            }
+            NormMethod::MedianRatio => {
+                let mut sample_ratios = vec![Vec::new(); nsamples];
+                for count_vec in count_vecs.filter(|cts| cts.iter().all(|&ct| ct > 0)) {
+                    let geo_mean = geometric_mean(count_vec.iter().map(|&ct| ct as f64));
+                    for (ratios, &count) in sample_ratios.iter_mut().zip(count_vec.iter()) {
+                        ratios.push((count as f64) / geo_mean);
+                    }
+                }
+                sample_ratios
+                    .into_iter()
+                    .map(|mut ratios| {
+                        ratios.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                        median(&ratios)
+                    })
+                    .collect()
+            }
+        };
+
+        if let Some(sample) = factors.iter().position(|&factor| !(factor > 0.0)) {
+            bail!(
+                "Cannot normalize sample {} by {:?}: no usable barcode counts",
+                sample,
+                self
+            );
+        }
+
+        Ok(factors)
+    }
+}
+
+impl FromStr for NormMethod {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpm" => Ok(NormMethod::Cpm),
+            "upper-quartile" => Ok(NormMethod::UpperQuartile),
+            "median-ratio" => Ok(NormMethod::MedianRatio),
+            _ => Err(format_err!("Unknown normalization method {:?}", s)),
+        }
+    }
+}
+
+/// Returns the log2 ratio of the mean normalized count in one group of
+/// samples to the mean in another.
+///
+/// # Arguments
+///
+/// * `numer` and `denom` are the normalized counts in each group
+/// * `pseudocount` is added to each mean before taking the ratio
+pub fn log2_fold_change(numer: &[f64], denom: &[f64], pseudocount: f64) -> f64 {
+    ((mean(numer) + pseudocount) / (mean(denom) + pseudocount)).log2()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / (values.len() as f64)
+}
+
+// Nearest-rank upper quartile of sorted counts.
+fn upper_quartile(sorted: &[usize]) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = ((sorted.len() as f64) * UPPER_QUARTILE).ceil() as usize;
+    sorted[rank.max(1) - 1] as f64
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let n = sorted.len();
+    if n == 0 {
+        0.0
+    } else if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    }
+}
+
+fn geometric_mean<I: Iterator<Item = f64>>(values: I) -> f64 {
+    let (log_sum, n) = values.fold((0.0, 0), |(log_sum, n), x| (log_sum + x.ln(), n + 1));
+    (log_sum / (n as f64)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> Vec<Vec<usize>> {
+        vec![
+            vec![10, 20, 0],
+            vec![30, 60, 5],
+            vec![60, 120, 10],
+            vec![0, 0, 85],
+        ]
+    }
+
+    fn factors(method: NormMethod) -> Vec<f64> {
+        let matrix = matrix();
+        method
+            .size_factors(matrix.iter().map(Vec::as_slice), 3)
+            .unwrap()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn cpm() {
+        assert_close(&factors(NormMethod::Cpm), &[1e-4, 2e-4, 1e-4]);
+    }
+
+    #[test]
+    fn upper_quartile() {
+        // Upper quartiles 60, 120, and 85
+        let scale = (60.0_f64 * 120.0 * 85.0).powf(1.0 / 3.0);
+        assert_close(
+            &factors(NormMethod::UpperQuartile),
+            &[60.0 / scale, 120.0 / scale, 85.0 / scale],
+        );
+    }
+
+    #[test]
+    fn median_ratio() {
+        // Only the two barcodes present in all samples are used, and
+        // sample 3 is exactly 1/6 of sample 1 for both
+        let scale = (1.0_f64 * 2.0 / 6.0).powf(1.0 / 3.0);
+        assert_close(
+            &factors(NormMethod::MedianRatio),
+            &[1.0 / scale, 2.0 / scale, (1.0 / 6.0) / scale],
+        );
+    }
+
+    #[test]
+    fn unusable() {
+        let matrix = vec![vec![10, 0], vec![0, 5]];
+        assert!(NormMethod::MedianRatio
+            .size_factors(matrix.iter().map(Vec::as_slice), 2)
+            .is_err());
+        assert!(NormMethod::Cpm
+            .size_factors(matrix.iter().map(Vec::as_slice), 2)
+            .is_ok());
+        assert!(NormMethod::Cpm.size_factors(std::iter::empty(), 2).is_err());
+    }
+
+    #[test]
+    fn fold_change() {
+        assert!((log2_fold_change(&[3.0, 5.0], &[1.0], 0.0) - 2.0).abs() < 1e-9);
+        assert!((log2_fold_change(&[0.0], &[3.0], 1.0) + 2.0).abs() < 1e-9);
+        assert_eq!(
+            "median-ratio".parse::<NormMethod>().unwrap(),
+            NormMethod::MedianRatio
+        );
+        assert!("tmm".parse::<NormMethod>().is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::io::Write;
+
+use bio::io::fastq;
+use clap::{Arg, ArgMatches};
+
+use cli::optional_value;
+
+const QUAL_OFFSET: u8 = 33;
+
+/// Per-read quality filters applied to barcode reads before counting.
+///
+/// Each filter is disabled when `None`, so the default filter passes
+/// every read.
+#[derive(Debug, Clone, Default)]
+pub struct ReadFilter {
+    /// Minimum Phred quality of every base in the barcode
+    pub min_qual: Option<u8>,
+    /// Maximum expected number of errors, the sum of base error
+    /// probabilities, in the barcode
+    pub max_expected_errors: Option<f64>,
+    /// Maximum number of `N` bases in the barcode
+    pub max_n: Option<usize>,
+    /// Minimum barcode length
+    pub min_len: Option<usize>,
+    /// Maximum barcode length
+    pub max_len: Option<usize>,
+}
+
+/// Reason a read failed the quality filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Length,
+    NCount,
+    MinQual,
+    ExpectedErrors,
+}
+
+impl ReadFilter {
+    /// Command-line arguments for the read filters, as parsed by
+    /// `from_matches()`.
+    pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("min-qual")
+                .long("min-qual")
+                .value_name("QUAL")
+                .help("Minimum base quality in barcode")
+                .takes_value(true),
+            Arg::with_name("max-expected-errors")
+                .long("max-expected-errors")
+                .value_name("ERRORS")
+                .help("Maximum expected number of errors in barcode")
+                .takes_value(true),
+            Arg::with_name("max-n")
+                .long("max-n")
+                .value_name("COUNT")
+                .help("Maximum number of N bases in barcode")
+                .takes_value(true),
+            Arg::with_name("min-length")
+                .long("min-length")
+                .value_name("LEN")
+                .help("Minimum barcode length")
+                .takes_value(true),
+            Arg::with_name("max-length")
+                .long("max-length")
+                .value_name("LEN")
+                .help("Maximum barcode length")
+                .takes_value(true),
+        ]
+    }
+
+    /// Parses read filters from the command-line arguments in
+    /// `args()`.
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        ReadFilter {
+            min_qual: optional_value(matches, "min-qual"),
+            max_expected_errors: optional_value(matches, "max-expected-errors"),
+            max_n: optional_value(matches, "max-n"),
+            min_len: optional_value(matches, "min-length"),
+            max_len: optional_value(matches, "max-length"),
+        }
+    }
+
+    /// Checks a barcode read against the filters, returning the first
+    /// filter that it fails.
+    pub fn check(&self, rec: &fastq::Record) -> Result<(), Rejection> {
+        let len = rec.seq().len();
+        if self.min_len.map_or(false, |min_len| len < min_len)
+            || self.max_len.map_or(false, |max_len| len > max_len)
+        {
+            return Err(Rejection::Length);
+        }
+
+        if let Some(max_n) = self.max_n {
+            let n_count = rec
+                .seq()
+                .iter()
+                .filter(|&&b| b == b'N' || b == b'n')
+                .count();
+            if n_count > max_n {
+                return Err(Rejection::NCount);
+            }
+        }
+
+        if let Some(min_qual) = self.min_qual {
+            if rec
+                .qual()
+                .iter()
+                .any(|&q| q.saturating_sub(QUAL_OFFSET) < min_qual)
+            {
+                return Err(Rejection::MinQual);
+            }
+        }
+
+        if let Some(max_ee) = self.max_expected_errors {
+            if Self::expected_errors(rec.qual()) > max_ee {
+                return Err(Rejection::ExpectedErrors);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the expected number of errors in a read from its
+    /// Phred+33 quality string.
+    pub fn expected_errors(qual: &[u8]) -> f64 {
+        qual.iter()
+            .map(|&q| 10.0_f64.powf(-(q.saturating_sub(QUAL_OFFSET) as f64) / 10.0))
+            .sum()
+    }
+}
+
+/// Tally of reads passing and failing each quality filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    pub passed: usize,
+    pub length: usize,
+    pub n_count: usize,
+    pub min_qual: usize,
+    pub expected_errors: usize,
+}
+
+impl FilterStats {
+    /// Checks a read against `filter`, records the outcome, and
+    /// returns `true` if the read passed.
+    pub fn check(&mut self, filter: &ReadFilter, rec: &fastq::Record) -> bool {
+        let result = filter.check(rec);
+        let tally = match result {
+            Ok(()) => &mut self.passed,
+            Err(Rejection::Length) => &mut self.length,
+            Err(Rejection::NCount) => &mut self.n_count,
+            Err(Rejection::MinQual) => &mut self.min_qual,
+            Err(Rejection::ExpectedErrors) => &mut self.expected_errors,
+        };
+        *tally += 1;
+        result.is_ok()
+    }
+
+    pub fn total(&self) -> usize {
+        self.passed + self.length + self.n_count + self.min_qual + self.expected_errors
+    }
+
+    /// Writes a report of reads rejected by each filter, in the format
+    /// filter<tab>reads<tab>fraction-of-reads. Reads are counted
+    /// under the first filter they fail, and fractions are 0 when
+    /// there are no reads.
+    pub fn write<W: Write>(&self, out: W) -> Result<(), failure::Error> {
+        let mut out = std::io::BufWriter::new(out);
+        let total = self.total();
+
+        writeln!(out, "filter\treads\tfraction")?;
+        for (name, reads) in vec![
+            ("passed", self.passed),
+            ("length", self.length),
+            ("n_count", self.n_count),
+            ("min_qual", self.min_qual),
+            ("expected_errors", self.expected_errors),
+        ] {
+            writeln!(
+                out,
+                "{}\t{}\t{:0.3}",
+                name,
+                reads,
+                if total > 0 {
+                    (reads as f64) / (total as f64)
+                } else {
+                    0.0
+                }
+            )?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl std::ops::AddAssign for FilterStats {
+    fn add_assign(&mut self, other: Self) {
+        self.passed += other.passed;
+        self.length += other.length;
+        self.n_count += other.n_count;
+        self.min_qual += other.min_qual;
+        self.expected_errors += other.expected_errors;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: &[u8], qual: &[u8]) -> fastq::Record {
+        fastq::Record::with_attrs("read", None, seq, qual)
+    }
+
+    #[test]
+    fn default_passes() {
+        let filter = ReadFilter::default();
+        assert_eq!(filter.check(&record(b"NNNN", b"####")), Ok(()));
+    }
+
+    #[test]
+    fn filters() {
+        let filter = ReadFilter {
+            min_qual: Some(10),
+            max_expected_errors: Some(0.5),
+            max_n: Some(0),
+            min_len: Some(8),
+            max_len: Some(9),
+        };
+
+        assert_eq!(filter.check(&record(b"ACGTACGT", b"IIIIIIII")), Ok(()));
+        assert_eq!(
+            filter.check(&record(b"ACGTACG", b"IIIIIII")),
+            Err(Rejection::Length)
+        );
+        assert_eq!(
+            filter.check(&record(b"ACGTACGTAC", b"IIIIIIIIII")),
+            Err(Rejection::Length)
+        );
+        assert_eq!(
+            filter.check(&record(b"ACGTNCGT", b"IIII#III")),
+            Err(Rejection::NCount)
+        );
+        assert_eq!(
+            filter.check(&record(b"ACGTACGT", b"IIII*III")),
+            Err(Rejection::MinQual)
+        );
+        // Q10 at every base is 0.8 expected errors
+        assert_eq!(
+            filter.check(&record(b"ACGTACGT", b"++++++++")),
+            Err(Rejection::ExpectedErrors)
+        );
+    }
+
+    #[test]
+    fn expected_errors() {
+        assert!((ReadFilter::expected_errors(b"++++") - 0.4).abs() < 1e-9);
+        assert!((ReadFilter::expected_errors(b"5!") - 1.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn filter_stats() {
+        let filter = ReadFilter {
+            max_n: Some(0),
+            ..ReadFilter::default()
+        };
+        let mut stats = FilterStats::default();
+        assert!(stats.check(&filter, &record(b"ACGT", b"IIII")));
+        assert!(!stats.check(&filter, &record(b"ACNT", b"II#I")));
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "filter\treads\tfraction\npassed\t1\t0.500\nlength\t0\t0.000\nn_count\t1\t0.500\nmin_qual\t0\t0.000\nexpected_errors\t0\t0.000\n"
+        );
+    }
+
+    #[test]
+    fn empty_filter_stats() {
+        let mut out = Vec::new();
+        FilterStats::default().write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "filter\treads\tfraction\npassed\t0\t0.000\nlength\t0\t0.000\nn_count\t0\t0.000\nmin_qual\t0\t0.000\nexpected_errors\t0\t0.000\n"
+        );
+    }
+}
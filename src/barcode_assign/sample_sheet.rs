@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use toml;
+
+use compress::*;
+
+const SAMPLE_COLUMN: &str = "sample";
+const FILE_COLUMN: &str = "file";
+
+/// One sample listed in a sample sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleInfo {
+    /// Sample name, used as the column header in count tables
+    pub name: String,
+    /// Count table for the sample
+    pub file: String,
+    /// Metadata values such as condition, replicate, or timepoint
+    pub metadata: HashMap<String, String>,
+}
+
+impl SampleInfo {
+    /// Returns the metadata value in `column`, if any.
+    pub fn value(&self, column: &str) -> Option<&str> {
+        self.metadata.get(column).map(String::as_str)
+    }
+}
+
+/// Table of samples, mapping count table files to sample names and
+/// metadata.
+#[derive(Debug, Clone)]
+pub struct SampleSheet {
+    columns: Vec<String>,
+    samples: Vec<SampleInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SampleSheetTOML {
+    samples: Vec<toml::value::Table>,
+}
+
+impl SampleSheet {
+    /// Reads a sample sheet from a file, in TOML format when the
+    /// filename ends with `.toml` and tab-delimited format otherwise.
+    pub fn from_file(filename: &str) -> Result<Self, failure::Error> {
+        let res = if filename.ends_with(".toml") {
+            let mut contents = String::new();
+            open_input(filename)?.read_to_string(&mut contents)?;
+            Self::read_toml(&contents)
+        } else {
+            Self::read_tsv(open_input(filename)?)
+        };
+        res.map_err(|e| format_err!("Reading sample sheet {:?}: {}", filename, e))
+    }
+
+    /// Reads a tab-delimited sample sheet. The header line names the
+    /// columns, which must include `sample` and `file`, and every
+    /// other column holds metadata. Blank lines and `#` comments are
+    /// skipped.
+    pub fn read_tsv<R: BufRead>(input: R) -> Result<Self, failure::Error> {
+        let mut lines = input.lines().filter(|line_res| match line_res {
+            Ok(line) => !line.is_empty() && !line.starts_with('#'),
+            Err(_) => true,
+        });
+
+        let header = lines
+            .next()
+            .ok_or_else(|| format_err!("Empty sample sheet"))??;
+        let columns: Vec<&str> = header.split('\t').collect();
+        let column_index = |name: &str| {
+            columns
+                .iter()
+                .position(|&column| column == name)
+                .ok_or_else(|| format_err!("No {:?} column in header {:?}", name, header))
+        };
+        let sample_index = column_index(SAMPLE_COLUMN)?;
+        let file_index = column_index(FILE_COLUMN)?;
+
+        let mut samples = Vec::new();
+        for line_res in lines {
+            let line = line_res?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != columns.len() {
+                bail!(
+                    "Expected {} fields but found {} in {:?}",
+                    columns.len(),
+                    fields.len(),
+                    line
+                );
+            }
+
+            let metadata = columns
+                .iter()
+                .zip(fields.iter())
+                .enumerate()
+                .filter(|&(i, _)| i != sample_index && i != file_index)
+                .map(|(_, (column, value))| (column.to_string(), value.to_string()))
+                .collect();
+            samples.push(SampleInfo {
+                name: fields[sample_index].to_string(),
+                file: fields[file_index].to_string(),
+                metadata: metadata,
+            });
+        }
+
+        let metadata_columns = columns
+            .iter()
+            .filter(|&&column| column != SAMPLE_COLUMN && column != FILE_COLUMN)
+            .map(|column| column.to_string())
+            .collect();
+        Self::new(metadata_columns, samples)
+    }
+
+    /// Reads a TOML sample sheet with one `[[samples]]` table per
+    /// sample. Each table must have `sample` and `file` keys, and
+    /// every other key holds metadata.
+    pub fn read_toml(contents: &str) -> Result<Self, failure::Error> {
+        let sheet_toml: SampleSheetTOML = toml::from_str(contents)?;
+
+        let mut columns: Vec<String> = Vec::new();
+        let mut samples = Vec::new();
+        for table in sheet_toml.samples {
+            let mut name = None;
+            let mut file = None;
+            let mut metadata = HashMap::new();
+            for (key, value) in table.into_iter() {
+                let value = match value {
+                    toml::Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                if key == SAMPLE_COLUMN {
+                    name = Some(value);
+                } else if key == FILE_COLUMN {
+                    file = Some(value);
+                } else {
+                    if !columns.contains(&key) {
+                        columns.push(key.clone());
+                    }
+                    metadata.insert(key, value);
+                }
+            }
+
+            let name = name.ok_or_else(|| format_err!("Sample with no {:?}", SAMPLE_COLUMN))?;
+            let file =
+                file.ok_or_else(|| format_err!("Sample {:?} with no {:?}", name, FILE_COLUMN))?;
+            samples.push(SampleInfo {
+                name: name,
+                file: file,
+                metadata: metadata,
+            });
+        }
+
+        Self::new(columns, samples)
+    }
+
+    fn new(columns: Vec<String>, samples: Vec<SampleInfo>) -> Result<Self, failure::Error> {
+        for (i, sample) in samples.iter().enumerate() {
+            for other in samples[..i].iter() {
+                if other.name == sample.name {
+                    bail!("Duplicate sample name {:?}", sample.name);
+                }
+                if other.file == sample.file {
+                    bail!("Duplicate sample file {:?}", sample.file);
+                }
+            }
+        }
+
+        Ok(SampleSheet {
+            columns: columns,
+            samples: samples,
+        })
+    }
+
+    /// Returns the metadata columns, in the order they first appear.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Returns all samples, in the order they are listed.
+    pub fn samples(&self) -> &[SampleInfo] {
+        &self.samples
+    }
+
+    /// Returns the sample whose count table is `file`, if any.
+    pub fn by_file(&self, file: &str) -> Option<&SampleInfo> {
+        self.samples.iter().find(|sample| sample.file == file)
+    }
+
+    /// Groups sample names by their value in a metadata column,
+    /// returning `(value, samples)` pairs in the order each value
+    /// first appears.
+    ///
+    /// # Arguments
+    ///
+    /// * `samples` are the samples to group
+    /// * `column` is the metadata column holding the group
+    pub fn group_by<'a, I>(
+        samples: I,
+        column: &str,
+    ) -> Result<Vec<(String, Vec<String>)>, failure::Error>
+    where
+        I: Iterator<Item = &'a SampleInfo>,
+    {
+        let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+        for sample in samples {
+            let value = sample.value(column).ok_or_else(|| {
+                format_err!(
+                    "Sample {:?} has no metadata column {:?}",
+                    sample.name,
+                    column
+                )
+            })?;
+            match groups.iter_mut().find(|(group, _)| group == value) {
+                Some((_, names)) => names.push(sample.name.clone()),
+                None => groups.push((value.to_string(), vec![sample.name.clone()])),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Writes a table of sample names, files, and metadata for
+    /// `samples`, with the metadata columns of this sheet.
+    pub fn write_metadata<'a, W, I>(&self, out: W, samples: I) -> Result<(), failure::Error>
+    where
+        W: Write,
+        I: Iterator<Item = &'a SampleInfo>,
+    {
+        let mut out = std::io::BufWriter::new(out);
+
+        write!(out, "{}\t{}", SAMPLE_COLUMN, FILE_COLUMN)?;
+        for column in self.columns.iter() {
+            write!(out, "\t{}", column)?;
+        }
+        write!(out, "\n")?;
+
+        for sample in samples {
+            write!(out, "{}\t{}", sample.name, sample.file)?;
+            for column in self.columns.iter() {
+                write!(out, "\t{}", sample.value(column).unwrap_or("NA"))?;
+            }
+            write!(out, "\n")?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Selection of samples whose metadata value in one column is among a
+/// list of values, parsed from `COLUMN=VALUE,VALUE,...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleFilter {
+    pub column: String,
+    pub values: Vec<String>,
+}
+
+impl SampleFilter {
+    pub fn matches(&self, sample: &SampleInfo) -> bool {
+        sample
+            .value(&self.column)
+            .map_or(false, |value| self.values.iter().any(|v| v == value))
+    }
+}
+
+impl FromStr for SampleFilter {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut column_values = s.splitn(2, '=');
+        let column = column_values.next().unwrap_or("");
+        let values: Vec<String> = column_values
+            .next()
+            .ok_or_else(|| format_err!("Sample filter {:?} is not COLUMN=VALUE,...", s))?
+            .split(',')
+            .map(String::from)
+            .collect();
+        if column.is_empty() || values.iter().any(String::is_empty) {
+            bail!("Sample filter {:?} is not COLUMN=VALUE,...", s);
+        }
+        Ok(SampleFilter {
+            column: column.to_string(),
+            values: values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_tsv() {
+        let sheet = "# run42\n\
+                     sample\tcondition\tfile\treplicate\n\
+                     S1\tctrl\t/scratch/run42/S1_counts.txt\t1\n\
+                     S2\tdrug\t/scratch/run42/S2_counts.txt\t1\n\
+                     \n\
+                     S3\tdrug\t/scratch/run42/S3_counts.txt\t2\n";
+        let sheet = SampleSheet::read_tsv(sheet.as_bytes()).unwrap();
+        assert_eq!(sheet.columns(), &["condition", "replicate"]);
+        assert_eq!(sheet.samples().len(), 3);
+
+        let s3 = sheet.by_file("/scratch/run42/S3_counts.txt").unwrap();
+        assert_eq!(s3.name, "S3");
+        assert_eq!(s3.value("condition"), Some("drug"));
+        assert_eq!(s3.value("replicate"), Some("2"));
+        assert_eq!(s3.value("timepoint"), None);
+
+        assert_eq!(
+            SampleSheet::group_by(sheet.samples().iter(), "condition").unwrap(),
+            vec![
+                ("ctrl".to_string(), vec!["S1".to_string()]),
+                ("drug".to_string(), vec!["S2".to_string(), "S3".to_string()]),
+            ]
+        );
+        assert!(SampleSheet::group_by(sheet.samples().iter(), "timepoint").is_err());
+
+        let mut out = Vec::new();
+        sheet
+            .write_metadata(&mut out, sheet.samples().iter().skip(1))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "sample\tfile\tcondition\treplicate\n\
+             S2\t/scratch/run42/S2_counts.txt\tdrug\t1\n\
+             S3\t/scratch/run42/S3_counts.txt\tdrug\t2\n"
+        );
+    }
+
+    #[test]
+    fn read_tsv_errors() {
+        let no_file = "sample\tcondition\nS1\tctrl\n";
+        assert!(SampleSheet::read_tsv(no_file.as_bytes()).is_err());
+        let short = "sample\tfile\tcondition\nS1\ts1.txt\n";
+        assert!(SampleSheet::read_tsv(short.as_bytes()).is_err());
+        let duplicate = "sample\tfile\nS1\ts1.txt\nS1\ts2.txt\n";
+        assert!(SampleSheet::read_tsv(duplicate.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn read_toml() {
+        let sheet = r#"
+[[samples]]
+sample = "S1"
+file = "s1.txt"
+condition = "ctrl"
+timepoint = 0
+
+[[samples]]
+sample = "S2"
+file = "s2.txt"
+condition = "drug"
+timepoint = 24
+"#;
+        let sheet = SampleSheet::read_toml(sheet).unwrap();
+        assert_eq!(sheet.columns(), &["condition", "timepoint"]);
+        assert_eq!(
+            sheet.samples()[1],
+            SampleInfo {
+                name: "S2".to_string(),
+                file: "s2.txt".to_string(),
+                metadata: vec![
+                    ("condition".to_string(), "drug".to_string()),
+                    ("timepoint".to_string(), "24".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            }
+        );
+
+        assert!(SampleSheet::read_toml("[[samples]]\nfile = \"s1.txt\"\n").is_err());
+    }
+
+    #[test]
+    fn sample_filter() {
+        let filter: SampleFilter = "condition=ctrl,drug".parse().unwrap();
+        let sheet = "sample\tfile\tcondition\nS1\ts1.txt\tctrl\nS2\ts2.txt\tnone\n";
+        let sheet = SampleSheet::read_tsv(sheet.as_bytes()).unwrap();
+        assert!(filter.matches(&sheet.samples()[0]));
+        assert!(!filter.matches(&sheet.samples()[1]));
+        assert!(!"condition=ctrl"
+            .parse::<SampleFilter>()
+            .unwrap()
+            .matches(&sheet.samples()[1]));
+        assert!("condition".parse::<SampleFilter>().is_err());
+        assert!("=ctrl".parse::<SampleFilter>().is_err());
+    }
+}
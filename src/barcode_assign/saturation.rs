@@ -0,0 +1,174 @@
+use std::ops::AddAssign;
+
+/// Fractions of reads at which saturation curves are evaluated.
+pub const SATURATION_FRACTIONS: &[f64] = &[
+    0.01, 0.02, 0.05, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0,
+];
+
+/// Library complexity, summarized as the number of molecules seen
+/// with each number of reads.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Complexity {
+    reads: usize,
+    /// `freqs[k]` is the number of molecules seen in exactly `k` reads
+    freqs: Vec<usize>,
+}
+
+impl Complexity {
+    /// Tabulates complexity from the number of reads for each
+    /// molecule.
+    pub fn new<I: IntoIterator<Item = usize>>(molecule_reads: I) -> Self {
+        let mut complexity = Complexity::default();
+        for reads in molecule_reads.into_iter().filter(|&reads| reads > 0) {
+            if complexity.freqs.len() <= reads {
+                complexity.freqs.resize(reads + 1, 0);
+            }
+            complexity.freqs[reads] += 1;
+            complexity.reads += reads;
+        }
+        complexity
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn molecules(&self) -> usize {
+        self.freqs.iter().sum()
+    }
+
+    /// Number of molecules seen in exactly `k` reads.
+    pub fn freq(&self, k: usize) -> usize {
+        self.freqs.get(k).copied().unwrap_or(0)
+    }
+
+    /// Sequencing saturation, the fraction of reads that are
+    /// duplicates of a molecule already seen, or 0 with no reads.
+    pub fn saturation(&self) -> f64 {
+        duplicate_fraction(self.molecules() as f64, self.reads as f64)
+    }
+
+    /// Bias-corrected Chao1 estimate of the total number of molecules
+    /// in the library, seen and unseen.
+    pub fn chao1(&self) -> f64 {
+        let f1 = self.freq(1) as f64;
+        let f2 = self.freq(2) as f64;
+        (self.molecules() as f64) + f1 * (f1 - 1.0) / (2.0 * (f2 + 1.0))
+    }
+
+    /// Good–Toulmin estimate of the number of new molecules that
+    /// would be seen by sequencing `t` times as many reads again, so
+    /// that `t = 1` doubles the depth. The estimate is unstable for
+    /// `t` above 1, and negative estimates are reported as 0.
+    pub fn good_toulmin(&self, t: f64) -> f64 {
+        let estimate: f64 = self
+            .freqs
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(k, &fk)| -(-t).powi(k as i32) * (fk as f64))
+            .sum();
+        if estimate > 0.0 {
+            estimate
+        } else {
+            0.0
+        }
+    }
+
+    /// Expected number of molecules seen when keeping each read
+    /// independently with probability `fraction`, that is, the
+    /// expected result of subsampling the reads.
+    pub fn subsample(&self, fraction: f64) -> f64 {
+        self.freqs
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(k, &fk)| (fk as f64) * (1.0 - (1.0 - fraction).powi(k as i32)))
+            .sum()
+    }
+
+    /// Expected sequencing saturation after subsampling the reads
+    /// with probability `fraction`, or 0 when no reads are expected.
+    pub fn subsample_saturation(&self, fraction: f64) -> f64 {
+        duplicate_fraction(self.subsample(fraction), fraction * (self.reads as f64))
+    }
+}
+
+fn duplicate_fraction(molecules: f64, reads: f64) -> f64 {
+    if reads > 0.0 {
+        1.0 - molecules / reads
+    } else {
+        0.0
+    }
+}
+
+impl AddAssign<&Complexity> for Complexity {
+    fn add_assign(&mut self, other: &Complexity) {
+        if self.freqs.len() < other.freqs.len() {
+            self.freqs.resize(other.freqs.len(), 0);
+        }
+        for (freq, other_freq) in self.freqs.iter_mut().zip(other.freqs.iter()) {
+            *freq += other_freq;
+        }
+        self.reads += other.reads;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn estimates() {
+        // Four singletons, two doubletons, and one molecule with 5 reads
+        let complexity = Complexity::new(vec![1, 1, 2, 5, 1, 0, 2, 1]);
+        assert_eq!(complexity.reads(), 13);
+        assert_eq!(complexity.molecules(), 7);
+        assert_eq!(complexity.freq(1), 4);
+        assert_eq!(complexity.freq(3), 0);
+
+        assert_close(complexity.saturation(), 1.0 - 7.0 / 13.0);
+        assert_close(complexity.chao1(), 7.0 + 4.0 * 3.0 / 6.0);
+        assert_close(complexity.good_toulmin(1.0), 4.0 - 2.0 + 1.0);
+        assert_close(
+            complexity.good_toulmin(0.5),
+            4.0 * 0.5 - 2.0 * 0.25 + 0.5f64.powi(5),
+        );
+
+        assert_close(complexity.subsample(1.0), 7.0);
+        assert_close(complexity.subsample(0.0), 0.0);
+        assert_close(
+            complexity.subsample(0.5),
+            4.0 * 0.5 + 2.0 * 0.75 + (1.0 - 0.5f64.powi(5)),
+        );
+    }
+
+    #[test]
+    fn degenerate() {
+        let empty = Complexity::default();
+        assert_eq!(empty.saturation(), 0.0);
+        assert_eq!(empty.subsample_saturation(0.5), 0.0);
+        assert_eq!(empty.good_toulmin(1.0), 0.0);
+
+        let doubleton = Complexity::new(vec![2]);
+        assert_eq!(doubleton.good_toulmin(1.0), 0.0);
+        assert_eq!(doubleton.subsample_saturation(0.0), 0.0);
+        assert_close(doubleton.subsample_saturation(1.0), 0.5);
+    }
+
+    #[test]
+    fn add() {
+        let mut total = Complexity::new(vec![1, 3]);
+        total += &Complexity::new(vec![1, 1, 2]);
+        assert_eq!(total, Complexity::new(vec![1, 1, 1, 2, 3]));
+    }
+}
@@ -0,0 +1,223 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bio::alphabets::dna;
+use bio::io::fastq;
+use regex::Regex;
+use rust_htslib::bam;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::Read;
+
+/// Location of the UMI for each barcode read.
+#[derive(Debug, Clone)]
+pub enum UmiSource {
+    /// Text following a prefix in the read description, such as `umi=`
+    Prefix(String),
+    /// Match of a regular expression on the read name, using the first
+    /// capture group when there is one, such as `:([ACGTN]+)$` for
+    /// Illumina names ending in `:UMI`
+    NameRegex(Regex),
+    /// SAM-style tag in the read description, such as `RX:Z:ACGTACGT`
+    /// as written by `samtools fastq -T RX`, or an auxiliary tag on
+    /// BAM input
+    Tag(String),
+    /// Fixed range of positions in the read sequence
+    Positions(Range<usize>),
+}
+
+impl UmiSource {
+    /// Parses a regular expression on read names.
+    pub fn name_regex(regex: &str) -> Result<Self> {
+        Ok(UmiSource::NameRegex(
+            Regex::new(regex).with_context(|| format!("Bad UMI regex {:?}", regex))?,
+        ))
+    }
+
+    /// Returns the UMI for a read, if it can be found.
+    pub fn find(&self, rec: &fastq::Record) -> Option<Vec<u8>> {
+        match self {
+            UmiSource::Prefix(prefix) => {
+                let (_, rest) = rec.desc()?.split_once(prefix.as_str())?;
+                rest.split_whitespace()
+                    .next()
+                    .map(|umi| umi.as_bytes().to_vec())
+            }
+            UmiSource::NameRegex(regex) => {
+                let caps = regex.captures(rec.id())?;
+                caps.get(1)
+                    .or_else(|| caps.get(0))
+                    .map(|umi| umi.as_str().as_bytes().to_vec())
+            }
+            UmiSource::Tag(tag) => find_tag(rec.desc()?, tag).map(|umi| umi.as_bytes().to_vec()),
+            UmiSource::Positions(range) => rec.seq().get(range.clone()).map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Removes an inline UMI from the sequence of a read that also
+    /// carries the barcode, and returns other reads unchanged.
+    pub fn trim(&self, rec: fastq::Record) -> fastq::Record {
+        match self {
+            UmiSource::Positions(range) if range.end <= rec.seq().len() => {
+                let cut = |s: &[u8]| [&s[..range.start], &s[range.end..]].concat();
+                fastq::Record::with_attrs(rec.id(), rec.desc(), &cut(rec.seq()), &cut(rec.qual()))
+            }
+            _ => rec,
+        }
+    }
+
+    /// Returns the tag holding the UMI, if any.
+    pub fn tag(&self) -> Option<&str> {
+        match self {
+            UmiSource::Tag(tag) => Some(tag),
+            _ => None,
+        }
+    }
+
+    /// Parses a range of UMI positions in the form `START:END`,
+    /// counting from 1 and including both ends.
+    pub fn parse_positions(positions: &str) -> Result<Range<usize>> {
+        let (start, end) = positions
+            .split_once(':')
+            .ok_or_else(|| anyhow!("UMI positions {:?} are not START:END", positions))?;
+        let start: usize = start
+            .parse()
+            .with_context(|| format!("Bad UMI start in {:?}", positions))?;
+        let end: usize = end
+            .parse()
+            .with_context(|| format!("Bad UMI end in {:?}", positions))?;
+        if start < 1 || end < start {
+            bail!("UMI positions {:?} must have 1 <= START <= END", positions);
+        }
+        Ok((start - 1)..end)
+    }
+}
+
+/// Finds the value of a SAM-style `TAG:TYPE:VALUE` field in a read
+/// description.
+pub fn find_tag<'a>(desc: &'a str, tag: &str) -> Option<&'a str> {
+    desc.split_whitespace().find_map(|field| {
+        let mut parts = field.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(_type), Some(value)) if name == tag => Some(value),
+            _ => None,
+        }
+    })
+}
+
+/// Iterator over the primary records of a BAM file as FastQ records,
+/// with the sequence restored to its original orientation and an
+/// optional tag copied into the description as `TAG:Z:VALUE`.
+pub struct BamRecords {
+    reader: bam::Reader,
+    record: bam::Record,
+    tag: Option<String>,
+}
+
+impl BamRecords {
+    pub fn from_path(filename: &str, tag: Option<&str>) -> Result<Self> {
+        let reader = if filename == "-" {
+            bam::Reader::from_stdin()
+        } else {
+            bam::Reader::from_path(filename)
+        }
+        .with_context(|| format!("Could not open BAM file {:?}", filename))?;
+
+        Ok(BamRecords {
+            reader: reader,
+            record: bam::Record::new(),
+            tag: tag.map(String::from),
+        })
+    }
+
+    fn fastq_record(&self) -> fastq::Record {
+        let id = String::from_utf8_lossy(self.record.qname()).into_owned();
+        let desc = self
+            .tag
+            .as_ref()
+            .and_then(|tag| match self.record.aux(tag.as_bytes()) {
+                Ok(Aux::String(value)) => Some(format!("{}:Z:{}", tag, value)),
+                _ => None,
+            });
+
+        let mut seq = self.record.seq().as_bytes();
+        let mut qual: Vec<u8> = self
+            .record
+            .qual()
+            .iter()
+            .map(|&q| std::cmp::min(q, b'~' - b'!') + b'!')
+            .collect();
+        if self.record.is_reverse() {
+            seq = dna::revcomp(&seq);
+            qual.reverse();
+        }
+
+        fastq::Record::with_attrs(&id, desc.as_deref(), &seq, &qual)
+    }
+}
+
+impl Iterator for BamRecords {
+    type Item = Result<fastq::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read(&mut self.record)? {
+                Err(e) => return Some(Err(anyhow!(e))),
+                Ok(()) if self.record.is_secondary() || self.record.is_supplementary() => (),
+                Ok(()) => return Some(Ok(self.fastq_record())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, desc: Option<&str>) -> fastq::Record {
+        fastq::Record::with_attrs(id, desc, b"ACGTACGTCC", b"ABCDEFGHIJ")
+    }
+
+    #[test]
+    fn find_umis() {
+        let prefix = UmiSource::Prefix("umi=".to_string());
+        assert_eq!(
+            prefix.find(&record("r1", Some("x=1 umi=ACGT y=2"))),
+            Some(b"ACGT".to_vec())
+        );
+        assert_eq!(prefix.find(&record("r1", None)), None);
+
+        let regex = UmiSource::name_regex(":([ACGTN]+)$").unwrap();
+        assert_eq!(
+            regex.find(&record("M00123:1:000:1:1101:15589:1331:TTGCA", None)),
+            Some(b"TTGCA".to_vec())
+        );
+        assert_eq!(regex.find(&record("M00123:1:000:1:1101", None)), None);
+        assert!(UmiSource::name_regex("(").is_err());
+
+        let tag = UmiSource::Tag("UB".to_string());
+        assert_eq!(
+            tag.find(&record("r1", Some("CB:Z:AAAA UB:Z:GGCC"))),
+            Some(b"GGCC".to_vec())
+        );
+        assert_eq!(tag.find(&record("r1", Some("RX:Z:GGCC"))), None);
+    }
+
+    #[test]
+    fn inline_umis() {
+        let positions = UmiSource::Positions(UmiSource::parse_positions("1:4").unwrap());
+        let rec = record("r1", None);
+        assert_eq!(positions.find(&rec), Some(b"ACGT".to_vec()));
+
+        let trimmed = positions.trim(rec);
+        assert_eq!(trimmed.seq(), b"ACGTCC");
+        assert_eq!(trimmed.qual(), b"EFGHIJ");
+
+        let tail = UmiSource::Positions(UmiSource::parse_positions("9:12").unwrap());
+        assert_eq!(tail.find(&record("r1", None)), None);
+        assert_eq!(tail.trim(record("r1", None)).seq(), b"ACGTACGTCC");
+
+        assert!(UmiSource::parse_positions("0:4").is_err());
+        assert!(UmiSource::parse_positions("5:4").is_err());
+        assert!(UmiSource::parse_positions("5").is_err());
+    }
+}
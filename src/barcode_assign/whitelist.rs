@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+use compress::*;
+use neighbor_index::*;
+
+/// Set of trusted barcodes, used to correct barcodes with sequencing
+/// errors.
+pub struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+    index: NeighborIndex,
+}
+
+/// Outcome of correcting one barcode against a whitelist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Correction<'a> {
+    /// The barcode is on the whitelist.
+    Exact,
+    /// The barcode is near exactly one whitelisted barcode.
+    Corrected(&'a [u8]),
+    /// The barcode is near more than one whitelisted barcode.
+    Ambiguous,
+    /// The barcode is not near any whitelisted barcode.
+    Unmatched,
+}
+
+impl Whitelist {
+    /// Creates a whitelist that corrects barcodes within `max_dist`
+    /// of a unique whitelisted barcode.
+    pub fn new(barcodes: HashSet<Vec<u8>>, metric: DistanceMetric, max_dist: usize) -> Self {
+        let index = NeighborIndex::new(barcodes.iter(), metric, max_dist);
+        Whitelist {
+            barcodes: barcodes,
+            index: index,
+        }
+    }
+
+    /// Reads a whitelist from a file.
+    ///
+    /// The barcode is taken from the first tab-delimited field of
+    /// each line, so barcode assignment tables from `bc-frag`,
+    /// `bc-grna`, and `bc-pbj` can be used directly. Blank lines, `#`
+    /// comments, and a header on the first remaining line starting
+    /// with `barcode` are skipped.
+    pub fn from_file(
+        filename: &str,
+        metric: DistanceMetric,
+        max_dist: usize,
+    ) -> Result<Self, failure::Error> {
+        let barcodes = Self::read_barcodes(open_input(filename)?)
+            .map_err(|e| format_err!("Reading whitelist {:?}: {}", filename, e))?;
+        Ok(Self::new(barcodes, metric, max_dist))
+    }
+
+    pub fn read_barcodes<R: BufRead>(input: R) -> Result<HashSet<Vec<u8>>, failure::Error> {
+        let mut barcodes = HashSet::new();
+        let mut first = true;
+
+        for line_res in input.lines() {
+            let line = line_res?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let barcode = line.split('\t').next().unwrap();
+            let is_header = first && barcode == "barcode";
+            first = false;
+            if is_header {
+                continue;
+            }
+
+            barcodes.insert(barcode.as_bytes().to_vec());
+        }
+
+        Ok(barcodes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    pub fn correct(&self, barcode: &[u8]) -> Correction<'_> {
+        if self.barcodes.contains(barcode) {
+            return Correction::Exact;
+        }
+
+        let neighbors = self.index.neighbors(barcode);
+        match neighbors.len() {
+            0 => Correction::Unmatched,
+            1 => Correction::Corrected(self.barcodes.get(&neighbors[0]).unwrap()),
+            _ => Correction::Ambiguous,
+        }
+    }
+}
+
+/// Tally of barcodes and reads for each whitelist correction outcome.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorrectionStats {
+    pub exact: (usize, usize),
+    pub corrected: (usize, usize),
+    pub ambiguous: (usize, usize),
+    pub unmatched: (usize, usize),
+}
+
+impl CorrectionStats {
+    /// Records a distinct barcode with `count` reads.
+    pub fn add(&mut self, correction: &Correction, count: usize) {
+        let tally = match correction {
+            Correction::Exact => &mut self.exact,
+            Correction::Corrected(_) => &mut self.corrected,
+            Correction::Ambiguous => &mut self.ambiguous,
+            Correction::Unmatched => &mut self.unmatched,
+        };
+        tally.0 += 1;
+        tally.1 += count;
+    }
+
+    /// Writes a summary table of correction outcomes, in the format
+    /// outcome<tab>barcodes<tab>reads<tab>fraction-of-reads, with
+    /// fractions of 0 when there are no reads.
+    pub fn write<W: Write>(&self, out: W) -> Result<(), failure::Error> {
+        let mut out = std::io::BufWriter::new(out);
+        let total = self.exact.1 + self.corrected.1 + self.ambiguous.1 + self.unmatched.1;
+
+        writeln!(out, "outcome\tbarcodes\treads\tfraction")?;
+        for (name, (barcodes, reads)) in vec![
+            ("exact", self.exact),
+            ("corrected", self.corrected),
+            ("ambiguous", self.ambiguous),
+            ("unmatched", self.unmatched),
+        ] {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{:0.3}",
+                name,
+                barcodes,
+                reads,
+                if total > 0 {
+                    (reads as f64) / (total as f64)
+                } else {
+                    0.0
+                }
+            )?;
+        }
+
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist() -> Whitelist {
+        let table =
+            "barcode\ttarget\nACGTACGTAC\tchrI:100+\nTTGCATGCAA\tchrII:5-\nTTGCATGCTA\tchrII:9-\n";
+        let barcodes = Whitelist::read_barcodes(table.as_bytes()).unwrap();
+        Whitelist::new(barcodes, DistanceMetric::Levenshtein, 1)
+    }
+
+    #[test]
+    fn read_whitelist() {
+        let table = "# trusted\nACGTACGTAC\n\nTTGCATGCAA\tx\nACGTACGTAC\n";
+        let barcodes = Whitelist::read_barcodes(table.as_bytes()).unwrap();
+        let mut bcvec: Vec<Vec<u8>> = barcodes.into_iter().collect();
+        bcvec.sort();
+        assert_eq!(bcvec, vec![b"ACGTACGTAC".to_vec(), b"TTGCATGCAA".to_vec()]);
+
+        let table = "# bc-frag assignments\n\nbarcode\ttarget\nACGTACGTAC\tchrI:100+\n";
+        let barcodes = Whitelist::read_barcodes(table.as_bytes()).unwrap();
+        assert_eq!(barcodes.len(), 1);
+        assert!(barcodes.contains(&b"ACGTACGTAC".to_vec()));
+
+        assert_eq!(whitelist().len(), 3);
+    }
+
+    #[test]
+    fn correct_barcodes() {
+        let wl = whitelist();
+        assert_eq!(wl.correct(b"ACGTACGTAC"), Correction::Exact);
+        assert_eq!(
+            wl.correct(b"ACGTTCGTAC"),
+            Correction::Corrected(b"ACGTACGTAC")
+        );
+        assert_eq!(
+            wl.correct(b"ACGTACGTA"),
+            Correction::Corrected(b"ACGTACGTAC")
+        );
+        assert_eq!(wl.correct(b"TTGCATGCCA"), Correction::Ambiguous);
+        assert_eq!(wl.correct(b"GGGGGGGGGG"), Correction::Unmatched);
+    }
+
+    #[test]
+    fn correction_stats() {
+        let wl = whitelist();
+        let mut stats = CorrectionStats::default();
+        for (bc, ct) in vec![
+            (&b"ACGTACGTAC"[..], 6),
+            (b"ACGTTCGTAC", 2),
+            (b"TTGCATGCCA", 1),
+            (b"GGGGGGGGGG", 1),
+        ] {
+            stats.add(&wl.correct(bc), ct);
+        }
+
+        let mut out = Vec::new();
+        stats.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "outcome\tbarcodes\treads\tfraction\nexact\t1\t6\t0.600\ncorrected\t1\t2\t0.200\nambiguous\t1\t1\t0.100\nunmatched\t1\t1\t0.100\n"
+        );
+
+        let mut out = Vec::new();
+        CorrectionStats::default().write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "outcome\tbarcodes\treads\tfraction\nexact\t0\t0\t0.000\ncorrected\t0\t0\t0.000\nambiguous\t0\t0\t0.000\nunmatched\t0\t0\t0.000\n"
+        );
+    }
+}
@@ -6,6 +6,7 @@ extern crate failure;
 use clap::{App, Arg};
 
 use barcode_assign::bc_collapse::CLI;
+use barcode_assign::neighborhood::NeighborhoodSpec;
 
 fn main() {
     let matches = App::new("bc-collapse")
@@ -17,10 +18,23 @@ fn main() {
                 .short("i")
                 .long("input")
                 .value_name("BARCODES.TXT")
-                .help("Text file of barcode sequences")
+                .help("Text file of barcode sequences, or count table with --counts")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("counts")
+                .long("counts")
+                .help("Input is a barcode<TAB>count table rather than one barcode per line"),
+        )
+        .arg(
+            Arg::with_name("column")
+                .long("column")
+                .value_name("NAME")
+                .help("Header of the count column to read from the input table")
+                .takes_value(true)
+                .requires("counts"),
+        )
         .arg(
             Arg::with_name("output_base")
                 .short("o")
@@ -30,11 +44,17 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .args(&NeighborhoodSpec::args())
         .get_matches();
 
+    let nbhd_spec = NeighborhoodSpec::from_matches(&matches).unwrap_or_else(|e| panic!("{}", e));
+
     let cli = CLI {
         input: matches.value_of("input").unwrap().to_string(),
+        counts: matches.is_present("counts"),
+        column: matches.value_of("column").map(String::from),
         output_base: matches.value_of("output_base").unwrap().to_string(),
+        nbhd_spec: nbhd_spec,
     };
 
     match cli.run() {
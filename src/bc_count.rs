@@ -1,7 +1,12 @@
 extern crate barcode_assign;
+#[macro_use]
 extern crate clap;
 
+use barcode_assign::barcode_extract::ExtractSpec;
 use barcode_assign::bc_count::*;
+use barcode_assign::cli::optional_value;
+use barcode_assign::neighborhood::NeighborhoodSpec;
+use barcode_assign::read_filter::ReadFilter;
 use clap::{App, Arg};
 
 fn main() {
@@ -14,7 +19,7 @@ fn main() {
                 .short("f")
                 .long("fastq")
                 .value_name("BARCODE-FQ")
-                .help("FastQ file of barcode sequences")
+                .help("FastQ file of barcode sequences, optionally gzip or zstd compressed")
                 .takes_value(true)
                 .required(true),
         )
@@ -23,7 +28,7 @@ fn main() {
                 .short("o")
                 .long("output")
                 .value_name("OUTPUT-TXT")
-                .help("Tab-delimited text file of barcode counts")
+                .help("Tab-delimited text file of barcode counts (compressed if named .gz or .zst)")
                 .takes_value(true)
                 .required(true),
         )
@@ -35,13 +40,136 @@ fn main() {
                 .help("Analyze barcode mutation neighborhoods")
                 .takes_value(true),
         )
+        .args(&NeighborhoodSpec::args())
+        .arg(
+            Arg::with_name("whitelist")
+                .short("w")
+                .long("whitelist")
+                .value_name("WHITELIST-TXT")
+                .help("Correct barcodes to a unique nearby barcode from a whitelist")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("whitelist-distance")
+                .long("whitelist-distance")
+                .value_name("DIST")
+                .help("Maximum edit distance for whitelist correction")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("correction-summary")
+                .long("correction-summary")
+                .value_name("SUMMARY-TXT")
+                .help("Tab-delimited summary of whitelist correction outcomes")
+                .takes_value(true)
+                .requires("whitelist"),
+        )
+        .args(&ReadFilter::args())
+        .arg(
+            Arg::with_name("filter-report")
+                .long("filter-report")
+                .value_name("REPORT-TXT")
+                .help("Tab-delimited report of reads failing each quality filter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("N")
+                .help("Number of worker threads, or 0 for one per core")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("before")
+                .long("before")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, following this constant sequence")
+                .takes_value(true)
+                .requires("after"),
+        )
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, preceding this constant sequence")
+                .takes_value(true)
+                .requires("before"),
+        )
+        .arg(
+            Arg::with_name("flank-errors")
+                .long("flank-errors")
+                .value_name("ERRORS")
+                .help("Maximum errors in each constant flanking sequence")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("revcomp")
+                .long("revcomp")
+                .help("Also extract barcodes from the reverse complement of reads"),
+        )
+        .arg(
+            Arg::with_name("min-insert")
+                .long("min-insert")
+                .value_name("LEN")
+                .help("Minimum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-insert")
+                .long("max-insert")
+                .value_name("LEN")
+                .help("Maximum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fates")
+                .long("fates")
+                .value_name("FATES-TXT")
+                .help("Tab-delimited table of barcode extraction fates")
+                .takes_value(true)
+                .requires("before"),
+        )
         .get_matches();
 
+    let nbhd_spec = NeighborhoodSpec::from_matches(&matches).unwrap_or_else(|e| panic!("{}", e));
+
+    let whitelist_distance =
+        value_t!(matches.value_of("whitelist-distance"), usize).unwrap_or_else(|e| e.exit());
+
+    let read_filter = ReadFilter::from_matches(&matches);
+
+    let extract = matches.value_of("before").map(|before| ExtractSpec {
+        revcomp: matches.is_present("revcomp"),
+        min_insert: optional_value(&matches, "min-insert"),
+        max_insert: optional_value(&matches, "max-insert"),
+        ..ExtractSpec::new(
+            before,
+            matches.value_of("after").unwrap(),
+            matches.value_of("flank-errors").unwrap(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    });
+
     let config = Config {
         barcode_fastq: matches.value_of("fastq").unwrap().to_string(),
         out_barcodes: matches.value_of("output").unwrap().to_string(),
         freq_filename: None,
         neighborhood: matches.value_of("neighborhood").map(|s| String::from(s)),
+        nbhd_spec: nbhd_spec,
+        whitelist: matches.value_of("whitelist").map(|s| String::from(s)),
+        whitelist_distance: whitelist_distance,
+        correction_summary: matches
+            .value_of("correction-summary")
+            .map(|s| String::from(s)),
+        read_filter: read_filter,
+        filter_report: matches.value_of("filter-report").map(|s| String::from(s)),
+        threads: optional_value(&matches, "threads").unwrap(),
+        extract: extract,
+        fates_report: matches.value_of("fates").map(|s| String::from(s)),
     };
 
     match bc_count(config) {
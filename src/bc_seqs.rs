@@ -3,7 +3,12 @@ extern crate clap;
 
 use clap::{App, Arg};
 
+use barcode_assign::barcode_extract::ExtractSpec;
 use barcode_assign::bc_seqs::*;
+use barcode_assign::cli::optional_value;
+use barcode_assign::consensus::ConsensusSpec;
+use barcode_assign::fastq_pair::IdCheck;
+use barcode_assign::neighborhood::NeighborhoodSpec;
 
 fn main() {
     let matches = App::new("bc-seqs")
@@ -15,7 +20,7 @@ fn main() {
                 .short("b")
                 .long("barcodes")
                 .value_name("BARCODE-FQ")
-                .help("FastQ file of barcode sequences")
+                .help("FastQ file of barcode sequences, optionally gzip or zstd compressed")
                 .takes_value(true)
                 .required(true),
         )
@@ -24,9 +29,24 @@ fn main() {
                 .short("s")
                 .long("sequences")
                 .value_name("SEQUENCE-FQ")
-                .help("FastQ file of insert sequences")
+                .help("FastQ file of insert sequences, optionally gzip or zstd compressed")
                 .takes_value(true)
-                .required(true),
+                .required_unless("interleaved"),
+        )
+        .arg(
+            Arg::with_name("interleaved")
+                .long("interleaved")
+                .help("Barcode FastQ interleaves each barcode read with its insert read")
+                .conflicts_with("sequences"),
+        )
+        .arg(
+            Arg::with_name("id-check")
+                .long("id-check")
+                .value_name("CHECK")
+                .help("Handling of barcode and insert reads whose IDs do not agree")
+                .takes_value(true)
+                .possible_values(IdCheck::NAMES)
+                .default_value("fail"),
         )
         .arg(
             Arg::with_name("outbase")
@@ -37,6 +57,12 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("gzip")
+                .short("z")
+                .long("gzip")
+                .help("Write gzip-compressed barcoded sequences (OUTBASE_barcoded.fq.gz)"),
+        )
         .arg(
             Arg::with_name("no-bclist")
                 .long("no-barcode-list")
@@ -75,6 +101,96 @@ fn main() {
                 .help("Group barcodes into single-mismatch neighborhoods")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("consensus")
+                .long("consensus")
+                .value_name("CONSENSUS_BASE")
+                .help("Consensus sequence for each barcode and table of read support")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("consensus-depth")
+                .long("consensus-depth")
+                .value_name("READS")
+                .help("Minimum reads covering a consensus position")
+                .takes_value(true)
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("consensus-fraction")
+                .long("consensus-fraction")
+                .value_name("FRACTION")
+                .help("Minimum quality-weighted fraction for an unambiguous consensus base")
+                .takes_value(true)
+                .default_value("0.8"),
+        )
+        .arg(
+            Arg::with_name("sort-records")
+                .long("sort-records")
+                .value_name("RECORDS")
+                .help("Group sequences with an external sort, holding at most RECORDS (>= 1000) in memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("temp-dir")
+                .long("temp-dir")
+                .value_name("DIR")
+                .help("Directory for temporary files in an external sort")
+                .takes_value(true)
+                .requires("sort-records"),
+        )
+        .args(&NeighborhoodSpec::args())
+        .arg(
+            Arg::with_name("before")
+                .long("before")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, following this constant sequence")
+                .takes_value(true)
+                .requires("after"),
+        )
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, preceding this constant sequence")
+                .takes_value(true)
+                .requires("before"),
+        )
+        .arg(
+            Arg::with_name("flank-errors")
+                .long("flank-errors")
+                .value_name("ERRORS")
+                .help("Maximum errors in each constant flanking sequence")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("revcomp")
+                .long("revcomp")
+                .help("Also extract barcodes from the reverse complement of reads"),
+        )
+        .arg(
+            Arg::with_name("min-insert")
+                .long("min-insert")
+                .value_name("LEN")
+                .help("Minimum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-insert")
+                .long("max-insert")
+                .value_name("LEN")
+                .help("Maximum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fates")
+                .long("fates")
+                .value_name("FATES-TXT")
+                .help("Tab-delimited table of barcode extraction fates")
+                .takes_value(true)
+                .requires("before"),
+        )
         .get_matches();
 
     let outbase = matches.value_of("outbase").unwrap();
@@ -87,10 +203,30 @@ fn main() {
             s.to_string()
         });
 
+    let nbhd_spec = NeighborhoodSpec::from_matches(&matches).unwrap_or_else(|e| panic!("{}", e));
+
+    let extract = matches.value_of("before").map(|before| ExtractSpec {
+        revcomp: matches.is_present("revcomp"),
+        min_insert: optional_value(&matches, "min-insert"),
+        max_insert: optional_value(&matches, "max-insert"),
+        ..ExtractSpec::new(
+            before,
+            matches.value_of("after").unwrap(),
+            matches.value_of("flank-errors").unwrap(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    });
+
     let config = Config {
         barcode_fastq: matches.value_of("barcodes").unwrap().to_string(),
-        sequ_fastq: matches.value_of("sequences").unwrap().to_string(),
-        out_fastq: outbase.to_string() + "_barcoded.fq",
+        sequ_fastq: matches.value_of("sequences").map(|s| String::from(s)),
+        id_check: optional_value(&matches, "id-check").unwrap(),
+        out_fastq: outbase.to_string()
+            + if matches.is_present("gzip") {
+                "_barcoded.fq.gz"
+            } else {
+                "_barcoded.fq"
+            },
         out_barcodes: if matches.is_present("no-bclist") {
             None
         } else {
@@ -102,10 +238,27 @@ fn main() {
             Some(barcode_freqs)
         },
         neighborhood: matches.value_of("neighborhood").map(|s| String::from(s)),
+        nbhd_spec: nbhd_spec,
+        extract: extract,
+        fates_report: matches.value_of("fates").map(|s| String::from(s)),
+        sort_records: optional_value(&matches, "sort-records"),
+        temp_dir: matches.value_of("temp-dir").map(|s| String::from(s)),
+        consensus: matches.value_of("consensus").map(|s| String::from(s)),
+        consensus_spec: ConsensusSpec {
+            min_depth: optional_value(&matches, "consensus-depth").unwrap(),
+            min_fraction: optional_value(&matches, "consensus-fraction").unwrap(),
+        },
     };
 
     match bc_seqs(config) {
-        Ok(_) => (),
+        Ok(mismatches) => {
+            if mismatches > 0 {
+                eprintln!(
+                    "bc-seqs: {} read pairs with mismatched read IDs",
+                    mismatches
+                );
+            }
+        }
         Err(e) => panic!("{}", e),
     }
 }
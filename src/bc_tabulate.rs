@@ -6,6 +6,8 @@ use std::io::Write;
 use clap::{App, Arg};
 
 use barcode_assign::bc_tabulate::*;
+use barcode_assign::neighborhood::NeighborhoodSpec;
+use barcode_assign::normalize::NormMethod;
 
 fn main() {
     let matches = App::new("bc-tabulate")
@@ -17,17 +19,66 @@ fn main() {
                 .index(1)
                 .multiple(true)
                 .takes_value(true)
-                .required(true),
+                .required_unless("sample-sheet"),
+        )
+        .arg(
+            Arg::with_name("sample-sheet")
+                .long("sample-sheet")
+                .value_name("SHEET")
+                .help("Sample sheet (tab-delimited or .toml) mapping count tables to sample names and metadata")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("select")
+                .long("select")
+                .value_name("COLUMN=VALUE,...")
+                .help("Tabulate only samples with one of these values in a sample sheet column")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("sample-sheet"),
+        )
+        .arg(
+            Arg::with_name("group-by")
+                .long("group-by")
+                .value_name("COLUMN")
+                .help("Sample sheet column defining sample groups for fold changes")
+                .takes_value(true)
+                .requires("sample-sheet"),
+        )
+        .arg(
+            Arg::with_name("sample-metadata")
+                .long("sample-metadata")
+                .value_name("METADATA.TXT")
+                .help("Output filename for metadata of tabulated samples")
+                .takes_value(true)
+                .requires("sample-sheet"),
+        )
+        .arg(
+            Arg::with_name("column")
+                .long("column")
+                .value_name("NAME")
+                .help("Header of the count column to read from input tables")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .value_name("OUTPUT.TXT")
-                .help("Output filename for tab-delimited count table")
+                .help("Count table output, or mtx base name (compressed if named .gz or .zst)")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Format of count table")
+                .takes_value(true)
+                .possible_values(TableFormat::FORMATS)
+                .default_value("dense"),
+        )
         .arg(
             Arg::with_name("mintotal")
                 .short("m")
@@ -58,6 +109,131 @@ fn main() {
                 .help("Output filename for omitted barcodes")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("key-map")
+                .long("key-map")
+                .value_name("KEY_MAP.TXT")
+                .help("Table mapping barcodes onto neighborhood keys, from bc-collapse")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("collapse")
+                .long("collapse")
+                .help("Merge counts onto neighborhood keys shared across all samples"),
+        )
+        .arg(
+            Arg::with_name("collapse-nbhds")
+                .long("collapse-neighborhoods")
+                .value_name("NBHD_BASE")
+                .help("Base name for neighborhood tables from collapsing")
+                .takes_value(true)
+                .requires("collapse"),
+        )
+        .args(&NeighborhoodSpec::args())
+        .arg(
+            Arg::with_name("norm-method")
+                .long("norm-method")
+                .value_name("METHOD")
+                .help("Method for normalizing counts across samples")
+                .takes_value(true)
+                .possible_values(NormMethod::METHODS)
+                .default_value("cpm"),
+        )
+        .arg(
+            Arg::with_name("normalized")
+                .long("normalized")
+                .value_name("NORMALIZED.TXT")
+                .help("Output filename for normalized count table")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("group")
+                .long("group")
+                .value_name("NAME=SAMPLE,...")
+                .help("Named group of samples for fold changes")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("contrast")
+                .long("contrast")
+                .value_name("NUMER/DENOM")
+                .help("Pair of sample groups for log2 fold changes")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("fold-changes"),
+        )
+        .arg(
+            Arg::with_name("pseudocount")
+                .long("pseudocount")
+                .value_name("COUNT")
+                .help("Pseudocount added to mean normalized counts for fold changes")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("fold-changes")
+                .long("fold-changes")
+                .value_name("FOLD-CHANGES.TXT")
+                .help("Output filename for log2 fold changes between sample groups")
+                .takes_value(true)
+                .requires("contrast"),
+        )
+        .arg(
+            Arg::with_name("assignments")
+                .long("assignments")
+                .value_name("ASSIGN.TXT")
+                .help("Barcode assignment table for counts by target")
+                .takes_value(true)
+                .requires("targets"),
+        )
+        .arg(
+            Arg::with_name("target-column")
+                .long("target-column")
+                .value_name("COLUMN")
+                .help("Column of the assignment table holding the target, counting from 1 (4 for bc-pbj)")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("targets")
+                .long("targets")
+                .value_name("OUTPUT_BASE")
+                .help("Base name for target count tables and unassigned barcodes")
+                .takes_value(true)
+                .requires("assignments"),
+        )
+        .arg(
+            Arg::with_name("hop-fraction")
+                .long("hop-fraction")
+                .value_name("FRACTION")
+                .help("Flag counts below FRACTION of the barcode's largest count in another sample as index hopping")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hop-report")
+                .long("hop-report")
+                .value_name("HOP_RATES")
+                .help("Output filename for estimated hopping rates between samples")
+                .takes_value(true)
+                .requires("hop-fraction"),
+        )
+        .arg(
+            Arg::with_name("hop-flagged")
+                .long("hop-flagged")
+                .value_name("FLAGGED")
+                .help("Output filename for barcode counts flagged as index hopping")
+                .takes_value(true)
+                .requires("hop-fraction"),
+        )
+        .arg(
+            Arg::with_name("hop-zero")
+                .long("hop-zero")
+                .help("Set flagged counts to zero in output tables")
+                .requires("hop-fraction"),
+        )
         .get_matches();
 
     fn parse_int(value: &str) -> usize {
@@ -66,17 +242,64 @@ fn main() {
             .unwrap_or_else(|e| panic!("Bad whole number argument {:?}: {}", value, e))
     }
 
+    fn parse_arg<T>(value: &str) -> T
+    where
+        T: std::str::FromStr,
+        T::Err: std::fmt::Display,
+    {
+        value
+            .parse::<T>()
+            .unwrap_or_else(|e| panic!("Bad argument {:?}: {}", value, e))
+    }
+
     let cli = CLI {
         inputs: matches
             .values_of("inputs")
-            .unwrap()
-            .map(String::from)
-            .collect(),
+            .map_or(Vec::new(), |inputs| inputs.map(String::from).collect()),
+        sample_sheet: matches.value_of("sample-sheet").map(String::from),
+        select: matches
+            .values_of("select")
+            .map_or(Vec::new(), |filters| filters.map(parse_arg).collect()),
+        group_by: matches.value_of("group-by").map(String::from),
+        sample_metadata: matches.value_of("sample-metadata").map(String::from),
+        column: matches.value_of("column").map(String::from),
         output: matches.value_of("output").unwrap().to_string(),
+        format: parse_arg(matches.value_of("format").unwrap()),
         mintotal: matches.value_of("mintotal").map(parse_int),
         minsamples: matches.value_of("minsamples").map(parse_int),
         mininsample: matches.value_of("mininsample").map(parse_int),
         omitfile: matches.value_of("omitfile").map(String::from),
+        key_map: matches.value_of("key-map").map(String::from),
+        collapse: if matches.is_present("collapse") {
+            Some(NeighborhoodSpec::from_matches(&matches).unwrap_or_else(|e| panic!("{}", e)))
+        } else {
+            None
+        },
+        collapse_nbhds: matches.value_of("collapse-nbhds").map(String::from),
+        norm_method: matches
+            .value_of("norm-method")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        normalized: matches.value_of("normalized").map(String::from),
+        groups: matches
+            .values_of("group")
+            .map_or(Vec::new(), |groups| groups.map(parse_arg).collect()),
+        contrasts: matches
+            .values_of("contrast")
+            .map_or(Vec::new(), |contrasts| contrasts.map(parse_arg).collect()),
+        pseudocount: parse_arg(matches.value_of("pseudocount").unwrap()),
+        fold_changes: matches.value_of("fold-changes").map(String::from),
+        assignments: matches.value_of("assignments").map(String::from),
+        target_field: match parse_int(matches.value_of("target-column").unwrap()) {
+            0 => panic!("Target column counts from 1"),
+            column => column - 1,
+        },
+        targets: matches.value_of("targets").map(String::from),
+        hop_fraction: matches.value_of("hop-fraction").map(parse_arg),
+        hop_report: matches.value_of("hop-report").map(String::from),
+        hop_flagged: matches.value_of("hop-flagged").map(String::from),
+        hop_zero: matches.is_present("hop-zero"),
     };
 
     match cli.run() {
@@ -1,7 +1,13 @@
 extern crate barcode_assign;
 extern crate clap;
 
+use barcode_assign::barcode_extract::ExtractSpec;
 use barcode_assign::bc_umi::*;
+use barcode_assign::cli::optional_value;
+use barcode_assign::fastq_pair::IdCheck;
+use barcode_assign::neighborhood::NeighborhoodSpec;
+use barcode_assign::read_filter::ReadFilter;
+use barcode_assign::umi_source::UmiSource;
 use clap::{App, Arg};
 
 fn main() {
@@ -14,10 +20,16 @@ fn main() {
                 .short("f")
                 .long("fastq")
                 .value_name("BARCODE-FQ")
-                .help("FastQ file of barcode sequences")
+                .help("FastQ file of barcode sequences, optionally gzip or zstd compressed, or BAM file")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("bam")
+                .long("bam")
+                .help("Read barcode input as BAM even without a .bam name, e.g. from standard input")
+                .conflicts_with("umi-fastq"),
+        )
         .arg(
             Arg::with_name("umi")
                 .short("u")
@@ -27,12 +39,51 @@ fn main() {
                 .takes_value(true)
                 .default_value("umi="),
         )
+        .arg(
+            Arg::with_name("umi-regex")
+                .long("umi-regex")
+                .value_name("REGEX")
+                .help("Regular expression matching the UMI in the read name, e.g. ':([ACGTN]+)$'")
+                .takes_value(true)
+                .conflicts_with_all(&["umi-tag", "umi-positions"]),
+        )
+        .arg(
+            Arg::with_name("umi-tag")
+                .long("umi-tag")
+                .value_name("TAG")
+                .help("Tag holding the UMI in BAM input, or in the header as TAG:Z:UMI")
+                .takes_value(true)
+                .conflicts_with("umi-positions"),
+        )
+        .arg(
+            Arg::with_name("umi-positions")
+                .long("umi-positions")
+                .value_name("START:END")
+                .help("Positions of the UMI in the read, counting from 1, removed from the barcode read")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("umi-fastq")
+                .long("umi-fastq")
+                .value_name("UMI-FQ")
+                .help("FastQ file of UMI reads, matching the barcode reads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("id-check")
+                .long("id-check")
+                .value_name("CHECK")
+                .help("Handling of barcode and UMI reads whose IDs do not agree")
+                .takes_value(true)
+                .possible_values(IdCheck::NAMES)
+                .default_value("fail"),
+        )
         .arg(
             Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .value_name("OUTPUT-TXT")
-                .help("Tab-delimited text file of barcode counts")
+                .help("Tab-delimited text file of barcode counts (compressed if named .gz or .zst)")
                 .takes_value(true)
                 .required(true),
         )
@@ -52,18 +103,205 @@ fn main() {
                 .help("Deduplication statistics")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("saturation")
+                .long("saturation")
+                .value_name("SATURATION_BASE")
+                .help("Saturation curve and library complexity estimates")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("chimeras")
+                .long("chimeras")
+                .value_name("CHIMERA_BASE")
+                .help("Reassign UMIs shared across barcodes and report chimeras")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("chimera-ratio")
+                .long("chimera-ratio")
+                .value_name("RATIO")
+                .help("Minimum read ratio of dominant barcode to reassign a shared UMI")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("dedup-fastq")
+                .long("dedup-fastq")
+                .value_name("DEDUP_FASTQ")
+                .help("Deduplicated FastQ output with one read per molecule")
+                .takes_value(true),
+        )
+        .args(&NeighborhoodSpec::args())
+        .arg(
+            Arg::with_name("umi-method")
+                .long("umi-method")
+                .value_name("METHOD")
+                .help("Correct UMI errors by clustering UMIs within each barcode")
+                .takes_value(true)
+                .possible_values(NeighborhoodSpec::METHODS),
+        )
+        .arg(
+            Arg::with_name("umi-ratio")
+                .long("umi-ratio")
+                .value_name("RATIO")
+                .help("Minimum count ratio to join UMIs in directional clusters")
+                .takes_value(true)
+                .default_value("2"),
+        )
+        .arg(
+            Arg::with_name("umi-distance")
+                .long("umi-distance")
+                .value_name("DIST")
+                .help("Maximum edit distance between clustered UMIs")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("umi-metric")
+                .long("umi-metric")
+                .value_name("METRIC")
+                .help("Edit distance between UMIs, with or without indels")
+                .takes_value(true)
+                .possible_values(NeighborhoodSpec::METRICS)
+                .default_value("hamming"),
+        )
+        .args(&ReadFilter::args())
+        .arg(
+            Arg::with_name("filter-report")
+                .long("filter-report")
+                .value_name("REPORT-TXT")
+                .help("Tab-delimited report of reads failing each quality filter")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("N")
+                .help("Number of worker threads, or 0 for one per core")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("before")
+                .long("before")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, following this constant sequence")
+                .takes_value(true)
+                .requires("after"),
+        )
+        .arg(
+            Arg::with_name("after")
+                .long("after")
+                .value_name("SEQ")
+                .help("Extract barcodes from untrimmed reads, preceding this constant sequence")
+                .takes_value(true)
+                .requires("before"),
+        )
+        .arg(
+            Arg::with_name("flank-errors")
+                .long("flank-errors")
+                .value_name("ERRORS")
+                .help("Maximum errors in each constant flanking sequence")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("revcomp")
+                .long("revcomp")
+                .help("Also extract barcodes from the reverse complement of reads"),
+        )
+        .arg(
+            Arg::with_name("min-insert")
+                .long("min-insert")
+                .value_name("LEN")
+                .help("Minimum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-insert")
+                .long("max-insert")
+                .value_name("LEN")
+                .help("Maximum length of extracted barcode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fates")
+                .long("fates")
+                .value_name("FATES-TXT")
+                .help("Tab-delimited table of barcode extraction fates")
+                .takes_value(true)
+                .requires("before"),
+        )
         .get_matches();
 
+    let nbhd_spec = NeighborhoodSpec::from_matches(&matches).unwrap_or_else(|e| panic!("{}", e));
+
+    let umi_spec = matches.value_of("umi-method").map(|umi_method| {
+        NeighborhoodSpec::new(
+            umi_method,
+            matches.value_of("umi-ratio").unwrap(),
+            matches.value_of("umi-distance").unwrap(),
+            matches.value_of("umi-metric").unwrap(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    });
+
+    let umi_source = if let Some(positions) = matches.value_of("umi-positions") {
+        UmiSource::Positions(
+            UmiSource::parse_positions(positions).unwrap_or_else(|e| panic!("{:#}", e)),
+        )
+    } else if let Some(regex) = matches.value_of("umi-regex") {
+        UmiSource::name_regex(regex).unwrap_or_else(|e| panic!("{:#}", e))
+    } else if let Some(tag) = matches.value_of("umi-tag") {
+        UmiSource::Tag(tag.to_string())
+    } else {
+        UmiSource::Prefix(matches.value_of("umi").unwrap().to_string())
+    };
+
+    let read_filter = ReadFilter::from_matches(&matches);
+
+    let extract = matches.value_of("before").map(|before| ExtractSpec {
+        revcomp: matches.is_present("revcomp"),
+        min_insert: optional_value(&matches, "min-insert"),
+        max_insert: optional_value(&matches, "max-insert"),
+        ..ExtractSpec::new(
+            before,
+            matches.value_of("after").unwrap(),
+            matches.value_of("flank-errors").unwrap(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    });
+
     let config = Config {
         barcode_fastq: matches.value_of("fastq").unwrap().to_string(),
-        umi_prefix: matches.value_of("umi").unwrap().to_string(),
+        bam: matches.is_present("bam"),
+        umi_source: umi_source,
+        umi_fastq: matches.value_of("umi-fastq").map(|s| String::from(s)),
+        id_check: optional_value(&matches, "id-check").unwrap(),
         out_barcodes: matches.value_of("output").unwrap().to_string(),
         dedup_stats: matches.value_of("dedup-stats").map(|s| String::from(s)),
+        saturation: matches.value_of("saturation").map(|s| String::from(s)),
+        chimeras: matches.value_of("chimeras").map(|s| String::from(s)),
+        chimera_ratio: optional_value(&matches, "chimera-ratio").unwrap(),
+        dedup_fastq: matches.value_of("dedup-fastq").map(|s| String::from(s)),
         neighborhood: matches.value_of("neighborhood").map(|s| String::from(s)),
+        nbhd_spec: nbhd_spec,
+        umi_spec: umi_spec,
+        read_filter: read_filter,
+        filter_report: matches.value_of("filter-report").map(|s| String::from(s)),
+        threads: optional_value(&matches, "threads").unwrap(),
+        extract: extract,
+        fates_report: matches.value_of("fates").map(|s| String::from(s)),
     };
 
     match bc_umi(config) {
-        Ok(_) => (),
+        Ok(mismatches) => {
+            if mismatches > 0 {
+                eprintln!("bc-umi: {} read pairs with mismatched read IDs", mismatches);
+            }
+        }
         Err(e) => {
             eprint!("bc_umi: failed due to error:\n{:#}\n", e);
         }